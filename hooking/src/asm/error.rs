@@ -9,4 +9,7 @@ pub enum AssemblyError {
 
     #[error("Could not decode enough instructions while trying to relocate")]
     RelocationError,
+
+    #[error("XSAVE is not supported by the cpu or enabled by the os")]
+    XsaveUnsupported,
 }
@@ -1,6 +1,7 @@
 use iced_x86::{
-    BlockEncoder, BlockEncoderResult, Code, ConditionCode, Decoder, DecoderOptions, Instruction,
-    InstructionBlock, MemoryOperand, Register, code_asm::*,
+    BlockEncoder, BlockEncoderOptions, BlockEncoderResult, Code, ConditionCode, Decoder,
+    DecoderOptions, FlowControl, Instruction, InstructionBlock, InstructionInfoFactory,
+    MemoryOperand, Register, code_asm::*,
 };
 use std::{ffi::c_void, ptr::NonNull};
 
@@ -8,6 +9,69 @@ use super::super::*;
 
 pub type InnerError = iced_x86::IcedError;
 
+// XRSTOR faults on garbage in the header of the XSAVE area, it has to be
+// zeroed before saving
+const XSAVE_HEADER: i32 = 512;
+const XSAVE_HEADER_SIZE: i32 = 64;
+
+// Bytes `xsave` needs for every state component the os enabled, rounded up to
+// its 64 byte alignment. None without XSAVE support.
+pub fn xsave_area_size() -> Option<usize> {
+    use core::arch::x86_64::{__cpuid, __cpuid_count};
+
+    const OSXSAVE: u32 = 1 << 27;
+    if __cpuid(1).ecx & OSXSAVE == 0 {
+        return None;
+    }
+    Some((__cpuid_count(0xD, 0).ebx as usize).next_multiple_of(64))
+}
+
+// Bytes below rsp a leaf function may keep locals in, relocated instructions
+// can run in the middle of one
+const RED_ZONE: i32 = 128;
+
+// A register the instruction neither reads nor writes, implicit uses included
+fn scratch_register(
+    info: &mut InstructionInfoFactory,
+    instr: &Instruction,
+) -> Option<AsmRegister64> {
+    let used = info
+        .info(instr)
+        .used_registers()
+        .iter()
+        .map(|used| used.register().full_register())
+        .collect::<Vec<_>>();
+    [r10, r11, r9, r8, rax, rcx, rdx]
+        .into_iter()
+        .find(|scratch| !used.contains(&Register::from(*scratch)))
+}
+
+// Registers at a hooked instruction. Changes are written back before it
+// continues, except that rsp can't be moved below where it was.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct CpuContext {
+    pub xmm: [[u64; 2]; 16],
+    pub r15: usize,
+    pub r14: usize,
+    pub r13: usize,
+    pub r12: usize,
+    pub r11: usize,
+    pub r10: usize,
+    pub r9: usize,
+    pub r8: usize,
+    pub rdi: usize,
+    pub rsi: usize,
+    pub rbp: usize,
+    pub rbx: usize,
+    pub rdx: usize,
+    pub rcx: usize,
+    pub rax: usize,
+    pub rflags: usize,
+    pub rsp: usize,
+    pub rip: usize,
+}
+
 pub struct HookAssemblerx86_64;
 
 impl HookAssemblerx86_64 {
@@ -28,11 +92,107 @@ impl HookAssemblerx86_64 {
         &self,
         eip: usize,
         instructions: &[Instruction],
+    ) -> Result<BlockEncoderResult> {
+        self.assemble_instruction_block_with(eip, instructions, BlockEncoderOptions::NONE)
+    }
+
+    fn assemble_instruction_block_with(
+        &self,
+        eip: usize,
+        instructions: &[Instruction],
+        options: u32,
     ) -> Result<BlockEncoderResult> {
         let block = InstructionBlock::new(instructions, eip as u64);
-        let result = BlockEncoder::encode(self.bitness(), block, 0)?;
+        let result = BlockEncoder::encode(self.bitness(), block, options)?;
         Ok(result)
     }
+
+    // Saves the extended state below a 64 byte aligned rsp. rsp has to be
+    // kept in a callee saved register, it is restored from there after
+    // `assemble_xrstor`. Clobbers rax, rdx and the flags.
+    fn assemble_xsave(&self, a: &mut CodeAssembler, size: usize) -> Result<()> {
+        a.and(rsp, -64)?;
+        a.sub(rsp, size as i32)?;
+        for offset in (XSAVE_HEADER..XSAVE_HEADER + XSAVE_HEADER_SIZE).step_by(8) {
+            a.mov(qword_ptr(rsp + offset), 0)?;
+        }
+        // Every component enabled in XCR0
+        a.mov(eax, -1)?;
+        a.mov(edx, -1)?;
+        a.xsave64(ptr(rsp))?;
+        Ok(())
+    }
+
+    fn assemble_xrstor(&self, a: &mut CodeAssembler) -> Result<()> {
+        a.mov(eax, -1)?;
+        a.mov(edx, -1)?;
+        a.xrstor64(ptr(rsp))?;
+        Ok(())
+    }
+
+    // Calls `enter_fn(context, return_address_slot)` from the start of the
+    // trampoline without disturbing the arguments of the hooked call.
+    fn assemble_enter_call(
+        &self,
+        a: &mut CodeAssembler,
+        enter_fn: NonNull<c_void>,
+        context: NonNull<c_void>,
+        extended_state: Option<usize>,
+    ) -> Result<()> {
+        #[cfg(target_os = "windows")]
+        let (first_arg, second_arg, shadow_space) = (rcx, rdx, 32);
+        #[cfg(not(target_os = "windows"))]
+        let (first_arg, second_arg, shadow_space) = (rdi, rsi, 0);
+
+        // Covers the argument registers of both calling conventions, the
+        // return address is 16 byte aligned again after the 7 pushes.
+        let saved_registers = [rdi, rsi, rdx, rcx, r8, r9, rax];
+        let saved_vectors = [xmm0, xmm1, xmm2, xmm3, xmm4, xmm5, xmm6, xmm7];
+        let vector_space = saved_vectors.len() as i32 * 16;
+
+        for register in saved_registers {
+            a.push(register)?;
+        }
+        a.sub(rsp, vector_space + shadow_space)?;
+        for (index, register) in saved_vectors.into_iter().enumerate() {
+            a.movdqu(
+                xmmword_ptr(rsp + shadow_space + index as i32 * 16),
+                register,
+            )?;
+        }
+
+        let return_slot = vector_space + shadow_space + saved_registers.len() as i32 * 8;
+        if let Some(size) = extended_state {
+            a.push(rbp)?;
+            a.mov(rbp, rsp)?;
+            self.assemble_xsave(a, size)?;
+            a.sub(rsp, shadow_space)?;
+            a.lea(second_arg, qword_ptr(rbp + 8 + return_slot))?;
+        } else {
+            a.lea(second_arg, qword_ptr(rsp + return_slot))?;
+        }
+        a.mov(first_arg, context.as_ptr() as u64)?;
+        a.mov(rax, enter_fn.as_ptr() as u64)?;
+        a.call(rax)?;
+        if extended_state.is_some() {
+            a.add(rsp, shadow_space)?;
+            self.assemble_xrstor(a)?;
+            a.mov(rsp, rbp)?;
+            a.pop(rbp)?;
+        }
+
+        for (index, register) in saved_vectors.into_iter().enumerate() {
+            a.movdqu(
+                register,
+                xmmword_ptr(rsp + shadow_space + index as i32 * 16),
+            )?;
+        }
+        a.add(rsp, vector_space + shadow_space)?;
+        for register in saved_registers.into_iter().rev() {
+            a.pop(register)?;
+        }
+        Ok(())
+    }
 }
 
 impl HookAssembler for HookAssemblerx86_64 {
@@ -41,25 +201,154 @@ impl HookAssembler for HookAssemblerx86_64 {
         eip: usize,
         destination_fn: NonNull<c_void>,
         restore_fn_address: Option<NonNull<c_void>>,
+        prologue: &TrampolinePrologue,
     ) -> Result<Vec<u8>> {
-        let set_restore_fn_instructon = if let Some(restore_fn_address) = restore_fn_address {
-            Instruction::with2(
+        let mut a = CodeAssembler::new(self.bitness())?;
+
+        if let Some(call_counter) = prologue.call_counter {
+            a.mov(r11, call_counter.as_ptr() as u64)?;
+            a.lock().inc(qword_ptr(r11))?;
+        }
+
+        if let Some((enter_fn, context)) = prologue.enter_fn {
+            if let Some(in_flight) = prologue.in_flight {
+                a.mov(r11, in_flight.as_ptr() as u64)?;
+                a.lock().inc(qword_ptr(r11))?;
+            }
+            self.assemble_enter_call(&mut a, enter_fn, context, prologue.extended_state)?;
+        }
+
+        let enabled_flag = prologue
+            .enabled_flag
+            .filter(|_| restore_fn_address.is_some());
+        if let Some(enabled_flag) = enabled_flag {
+            // r11 is scratch in both calling conventions
+            a.mov(r11, enabled_flag.as_ptr() as u64)?;
+            a.cmp(byte_ptr(r11), 0)?;
+        }
+
+        if let Some(restore_fn_address) = restore_fn_address {
+            a.add_instruction(Instruction::with2(
                 Code::Mov_r64_rm64,
                 Register::R10,
                 MemoryOperand::with_base_displ(Register::RIP, restore_fn_address.as_ptr() as i64),
-            )?
-        } else {
-            Instruction::new()
-            //Instruction::with(Code::Nopd)
-        };
-
-        let instructions = &[
-            set_restore_fn_instructon,
-            Instruction::with_branch(Code::Jmp_rel32_64, destination_fn.as_ptr() as u64)?,
-            Instruction::with(Code::Nopd),
+            )?)?;
+        }
+
+        let mut disabled = a.create_label();
+        if enabled_flag.is_some() {
+            a.je(disabled)?;
+        }
+
+        a.add_instruction(Instruction::with_branch(
+            Code::Jmp_rel32_64,
+            destination_fn.as_ptr() as u64,
+        )?)?;
+
+        if enabled_flag.is_some() {
+            a.set_label(&mut disabled)?;
+            a.jmp(r10)?;
+        }
+        a.nop()?;
+
+        let assembled = self.assemble_instruction_block(eip, a.instructions())?;
+        Ok(assembled.code_buffer)
+    }
+
+    fn assemble_context_trampoline(
+        &self,
+        eip: usize,
+        target: usize,
+        handler: NonNull<c_void>,
+        context: NonNull<c_void>,
+        extended_state: Option<usize>,
+    ) -> Result<Vec<u8>> {
+        #[cfg(target_os = "windows")]
+        let (first_arg, second_arg, shadow_space) = (rcx, rdx, 32);
+        #[cfg(not(target_os = "windows"))]
+        let (first_arg, second_arg, shadow_space) = (rdi, rsi, 0);
+
+        // Pushed after rip, rsp, rflags, rax, rcx, rdx and rbx
+        let pushed_registers = [rbp, rsi, rdi, r8, r9, r10, r11, r12, r13, r14, r15];
+        let vectors = [
+            xmm0, xmm1, xmm2, xmm3, xmm4, xmm5, xmm6, xmm7, xmm8, xmm9, xmm10, xmm11, xmm12, xmm13,
+            xmm14, xmm15,
         ];
+        let vector_space = vectors.len() as i32 * 16;
+        // Below the red zone, rflags, rax and rbx are saved before aligning
+        let below_rsp = RED_ZONE + 3 * 8;
 
-        let assembled = self.assemble_instruction_block(eip, instructions)?;
+        let mut a = CodeAssembler::new(self.bitness())?;
+
+        a.lea(rsp, qword_ptr(rsp - RED_ZONE))?;
+        a.pushfq()?;
+        a.push(rax)?;
+        a.push(rbx)?;
+        a.mov(rbx, rsp)?;
+        a.and(rsp, -16)?;
+
+        // 18 pushes keep the stack aligned
+        a.mov(rax, target as u64)?;
+        a.push(rax)?;
+        a.lea(rax, qword_ptr(rbx + below_rsp))?;
+        a.push(rax)?;
+        a.push(qword_ptr(rbx + 16))?;
+        a.push(qword_ptr(rbx + 8))?;
+        a.push(rcx)?;
+        a.push(rdx)?;
+        a.push(qword_ptr(rbx))?;
+        for register in pushed_registers {
+            a.push(register)?;
+        }
+        a.sub(rsp, vector_space)?;
+        for (index, register) in vectors.into_iter().enumerate() {
+            a.movdqu(xmmword_ptr(rsp + index as i32 * 16), register)?;
+        }
+
+        // rbp is already part of the context
+        if let Some(size) = extended_state {
+            a.mov(rbp, rsp)?;
+            self.assemble_xsave(&mut a, size)?;
+            a.mov(second_arg, rbp)?;
+        } else {
+            a.mov(second_arg, rsp)?;
+        }
+        a.mov(first_arg, context.as_ptr() as u64)?;
+        a.sub(rsp, shadow_space)?;
+        a.mov(rax, handler.as_ptr() as u64)?;
+        a.call(rax)?;
+        a.add(rsp, shadow_space)?;
+        // Restored first, the xmm registers of the context can have changed
+        if extended_state.is_some() {
+            self.assemble_xrstor(&mut a)?;
+            a.mov(rsp, rbp)?;
+        }
+
+        for (index, register) in vectors.into_iter().enumerate() {
+            a.movdqu(register, xmmword_ptr(rsp + index as i32 * 16))?;
+        }
+        a.add(rsp, vector_space)?;
+        for register in pushed_registers.into_iter().rev() {
+            a.pop(register)?;
+        }
+        a.pop(rbx)?;
+        a.pop(rdx)?;
+
+        // rax, rflags and rip go below the red zone of the new rsp, and are
+        // restored from there: [rsp] rcx, rax, rflags, rsp, rip
+        a.mov(rax, qword_ptr(rsp + 24))?;
+        a.sub(rax, below_rsp)?;
+        for (from, to) in [(8, 0), (16, 8), (32, 16)] {
+            a.mov(rcx, qword_ptr(rsp + from))?;
+            a.mov(qword_ptr(rax + to), rcx)?;
+        }
+        a.pop(rcx)?;
+        a.mov(rsp, rax)?;
+        a.pop(rax)?;
+        a.popfq()?;
+        a.ret_1(RED_ZONE)?;
+
+        let assembled = self.assemble_instruction_block(eip, a.instructions())?;
         Ok(assembled.code_buffer)
     }
 
@@ -73,17 +362,52 @@ impl HookAssembler for HookAssemblerx86_64 {
         Ok(assembled.code_buffer)
     }
 
+    fn assemble_return_stub(&self, eip: usize, stub: &ReturnStub) -> Result<Vec<u8>> {
+        // rsp is 16 byte aligned again after the return address
+        #[cfg(target_os = "windows")]
+        let call_space = 32 + 8;
+        #[cfg(not(target_os = "windows"))]
+        let call_space = 8;
+
+        let mut a = CodeAssembler::new(self.bitness())?;
+
+        // The argument registers are free, nothing gets called with them
+        if let Some((errno_fn, errno)) = stub.errno {
+            a.sub(rsp, call_space)?;
+            a.mov(rax, errno_fn.as_ptr() as u64)?;
+            a.call(rax)?;
+            a.mov(dword_ptr(rax), errno)?;
+            a.add(rsp, call_space)?;
+        }
+
+        if stub.count > 1 {
+            a.mov(rcx, 1u64)?;
+            a.mov(rdx, stub.next.as_ptr() as u64)?;
+            a.lock().xadd(qword_ptr(rdx), rcx)?;
+            a.mov(r8, (stub.count - 1) as u64)?;
+            a.cmp(rcx, r8)?;
+            a.cmova(rcx, r8)?;
+            a.mov(rdx, stub.values.as_ptr() as u64)?;
+            a.mov(rax, qword_ptr(rdx + rcx * 8))?;
+        } else {
+            a.mov(rax, unsafe { stub.values.read() })?;
+        }
+        // Floats are returned in xmm0
+        a.movq(xmm0, rax)?;
+        a.ret()?;
+
+        let assembled = self.assemble_instruction_block(eip, a.instructions())?;
+        Ok(assembled.code_buffer)
+    }
+
     fn relocate_instructions(
         &self,
         eip: usize,
         source_address: NonNull<c_void>,
+        target_fn_data: &[u8],
         patch_size: usize,
         add_jump: bool,
-    ) -> Result<Vec<u8>> {
-        let target_fn_data = unsafe {
-            core::slice::from_raw_parts(source_address.as_ptr() as *const u8, patch_size + 20)
-        };
-
+    ) -> Result<RelocatedInstructions> {
         let mut a = CodeAssembler::new(self.bitness())?;
 
         let mut decoder = Decoder::with_ip(
@@ -93,12 +417,17 @@ impl HookAssembler for HookAssemblerx86_64 {
             DecoderOptions::NONE,
         );
 
+        // (source offset, index of the first emitted instruction)
+        let mut source_instructions = Vec::new();
+        let mut info = InstructionInfoFactory::new();
+
         let mut instruction_size_read = 0;
         while instruction_size_read < patch_size {
             if !decoder.can_decode() {
                 return Err(AssemblyError::RelocationError);
             }
             let mut instr = decoder.decode();
+            source_instructions.push((instruction_size_read, a.instructions().len()));
             instruction_size_read += instr.len();
 
             let mem_displacement = instr.memory_displacement64();
@@ -108,14 +437,35 @@ impl HookAssembler for HookAssemblerx86_64 {
                 //  then patch the instruction to use the register.
 
                 if instr.memory_base() == Register::RIP {
-                    a.push(r10)?;
-                    a.mov(r10, mem_displacement)?;
+                    let uses_stack = instr.stack_pointer_increment() != 0
+                        || instr.flow_control() != FlowControl::Next
+                        || info
+                            .info(&instr)
+                            .used_registers()
+                            .iter()
+                            .any(|used| used.register().full_register() == Register::RSP);
 
-                    instr.set_memory_base(Register::R10);
-                    instr.set_memory_displacement64(0);
-                    a.add_instruction(instr)?;
+                    if uses_stack {
+                        // Saving a register would move what these see on the
+                        // stack or never be undone. Keep them rip relative,
+                        // encoding fails if the copy is out of reach.
+                        a.add_instruction(instr)?;
+                    } else {
+                        let scratch = scratch_register(&mut info, &instr)
+                            .ok_or(AssemblyError::RelocationError)?;
+
+                        // Step over the red zone before saving the scratch register
+                        a.lea(rsp, qword_ptr(rsp - RED_ZONE))?;
+                        a.push(scratch)?;
+                        a.mov(scratch, mem_displacement)?;
 
-                    a.pop(r10)?;
+                        instr.set_memory_base(scratch.into());
+                        instr.set_memory_displacement64(0);
+                        a.add_instruction(instr)?;
+
+                        a.pop(scratch)?;
+                        a.lea(rsp, qword_ptr(rsp + RED_ZONE))?;
+                    }
                 } else if instr.is_call_near() {
                     // a.push(r10)?;
                     // a.mov(r10, mem_displacement)?;
@@ -177,12 +527,28 @@ impl HookAssembler for HookAssemblerx86_64 {
         }
 
         if add_jump {
-            a.jmp((source_address.as_ptr() as usize + patch_size) as u64)?;
+            a.jmp((source_address.as_ptr() as usize + instruction_size_read) as u64)?;
             a.nop()?;
         }
 
-        let buffer = self.assemble_instruction_block(eip, a.instructions())?;
+        let buffer = self.assemble_instruction_block_with(
+            eip,
+            a.instructions(),
+            BlockEncoderOptions::RETURN_NEW_INSTRUCTION_OFFSETS,
+        )?;
+
+        let instruction_offsets = source_instructions
+            .into_iter()
+            .filter_map(|(source_offset, index)| {
+                let relocated_offset = *buffer.new_instruction_offsets.get(index)?;
+                (relocated_offset != u32::MAX).then_some((source_offset, relocated_offset as usize))
+            })
+            .collect();
 
-        Ok(buffer.code_buffer)
+        Ok(RelocatedInstructions {
+            code: buffer.code_buffer,
+            source_size: instruction_size_read,
+            instruction_offsets,
+        })
     }
 }
@@ -9,22 +9,91 @@ pub mod inner {
 
 pub mod error;
 pub use error::{AssemblyError, Result};
+pub use inner::CpuContext;
 
 pub type DefaultHookAssembler = inner::HookAssemblerImpl;
 
+// Bytes past the patch that have to be available to decode the last
+// overwritten instruction.
+pub const RELOCATION_READ_AHEAD: usize = 20;
+
+// Longest jump `assemble_patch` produces, `jmp [rip]` followed by the address
+pub const MAX_PATCH_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Default)]
+pub struct RelocatedInstructions {
+    pub code: Vec<u8>,
+    // Number of bytes that were read from the source
+    pub source_size: usize,
+    // (source offset, relocated offset) of where each source instruction
+    // starts in the relocated code
+    pub instruction_offsets: Vec<(usize, usize)>,
+}
+
+// Extra work the trampoline does before jumping to the destination
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TrampolinePrologue {
+    // 64 bit counter incremented on every call
+    pub call_counter: Option<NonNull<c_void>>,
+    // `enter(context, return_address_slot)`, called with every argument
+    // register preserved
+    pub enter_fn: Option<(NonNull<c_void>, NonNull<c_void>)>,
+    // Pointer sized counter incremented before `enter_fn`, which takes it
+    // over. Only used with `enter_fn`.
+    pub in_flight: Option<NonNull<c_void>>,
+    // Byte that disables the hook while zero, the trampoline then jumps
+    // straight to the restore function
+    pub enabled_flag: Option<NonNull<c_void>>,
+    // Size of the buffer to save the full extended state (AVX, AVX-512, ...)
+    // into around `enter_fn`, see `inner::xsave_area_size`
+    pub extended_state: Option<usize>,
+}
+
+// Code that returns from the hooked function right away
+#[derive(Debug, Clone, Copy)]
+pub struct ReturnStub {
+    // Returned one per call, the last one repeats
+    pub values: NonNull<u64>,
+    pub count: usize,
+    // Index of the value the next call returns, only used with more than one
+    // value
+    pub next: NonNull<c_void>,
+    // Function returning the address of the calling thread's errno, and the
+    // value stored there on every call
+    pub errno: Option<(NonNull<c_void>, i32)>,
+}
+
 pub trait HookAssembler {
     fn assemble_trampoline(
         &self,
         eip: usize,
         destination_fn: NonNull<c_void>,
         restore_fn_address: Option<NonNull<c_void>>,
+        prologue: &TrampolinePrologue,
     ) -> Result<Vec<u8>>;
     fn assemble_patch(&self, eip: usize, destination_fn: NonNull<c_void>) -> Result<Vec<u8>>;
     fn relocate_instructions(
         &self,
         eip: usize,
-        source_data: NonNull<c_void>,
+        source_address: NonNull<c_void>,
+        // Code at `source_address`, at least `min_size_bytes + RELOCATION_READ_AHEAD` long
+        source_data: &[u8],
         min_size_bytes: usize,
         add_jump: bool,
+    ) -> Result<RelocatedInstructions>;
+    // Saves every register into a `CpuContext`, calls
+    // `handler(context, cpu_context)` and continues at the rip of the context
+    // with the registers it holds. rip starts out as `target`. The extended
+    // state is preserved too when given a buffer size.
+    fn assemble_context_trampoline(
+        &self,
+        eip: usize,
+        target: usize,
+        handler: NonNull<c_void>,
+        context: NonNull<c_void>,
+        extended_state: Option<usize>,
     ) -> Result<Vec<u8>>;
+    // Returns the next value of the stub in rax and xmm0, without calling
+    // anything but the errno function
+    fn assemble_return_stub(&self, eip: usize, stub: &ReturnStub) -> Result<Vec<u8>>;
 }
@@ -0,0 +1,228 @@
+use core::any::TypeId;
+use core::ptr::NonNull;
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StatsOptions {
+    // Count every call that reaches the trampoline, enabled or not
+    pub count_calls: bool,
+    // Time every call with rdtsc from entering the trampoline until the
+    // hooked function returns. This replaces the return address of the hooked
+    // call with a stub that has no unwind info and no CET landing pad, so
+    // panics and C++ exceptions can't unwind through a timed call and it
+    // can't be used with shadow stacks enabled. Calls a longjmp skips and
+    // calls nested more than 256 deep on a thread are not timed.
+    pub measure_latency: bool,
+}
+
+impl StatsOptions {
+    pub const fn new() -> Self {
+        Self {
+            count_calls: false,
+            measure_latency: false,
+        }
+    }
+
+    pub const fn with_count_calls(mut self, count_calls: bool) -> Self {
+        self.count_calls = count_calls;
+        self
+    }
+
+    pub const fn with_measure_latency(mut self, measure_latency: bool) -> Self {
+        self.measure_latency = measure_latency;
+        self
+    }
+}
+
+// How a destination finds the function it should continue to
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OriginalPointerMode {
+    // The trampoline records every call on a per-thread stack that
+    // `original_function_ptr` reads, so it works from anywhere in the
+    // destination. The return address of the call is left alone, calls that
+    // returned are recognized by their stack frame being gone.
+    #[default]
+    Tracked,
+    // Only passed in r10, read with `register_original_function_ptr` before
+    // anything else in the destination. Avoids the per call overhead, but
+    // `original_function_ptr` can't find these hooks.
+    Register,
+}
+
+// Durations are in time stamp counter ticks
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HookStats {
+    pub calls: u64,
+    pub timed_calls: u64,
+    pub total_ticks: u64,
+    pub min_ticks: u64,
+    pub max_ticks: u64,
+}
+
+impl HookStats {
+    pub fn average_ticks(&self) -> Option<u64> {
+        self.total_ticks.checked_div(self.timed_calls)
+    }
+}
+
+// Value owned by a hook and handed to its destination while it runs
+#[derive(Debug)]
+pub(crate) struct HookContext {
+    value: NonNull<c_void>,
+    type_id: TypeId,
+    drop: unsafe fn(NonNull<c_void>),
+}
+
+unsafe impl Send for HookContext {}
+unsafe impl Sync for HookContext {}
+
+impl HookContext {
+    pub fn new<T: Send + Sync + 'static>(value: T) -> Self {
+        unsafe fn drop_value<T>(value: NonNull<c_void>) {
+            drop(unsafe { Box::from_raw(value.as_ptr() as *mut T) });
+        }
+
+        Self {
+            value: NonNull::from(Box::leak(Box::new(value))).cast(),
+            type_id: TypeId::of::<T>(),
+            drop: drop_value::<T>,
+        }
+    }
+
+    pub fn get<T: 'static>(&self) -> Option<&T> {
+        (self.type_id == TypeId::of::<T>()).then(|| unsafe { self.value.cast::<T>().as_ref() })
+    }
+}
+
+impl Drop for HookContext {
+    fn drop(&mut self) {
+        unsafe { (self.drop)(self.value) };
+    }
+}
+
+// State the trampoline reads on every call. It lives outside of the hook heap
+// so it can be changed without touching any page protection.
+#[repr(C)]
+#[derive(Debug)]
+pub(crate) struct HookControl {
+    enabled: AtomicBool,
+    measure_latency: bool,
+    // The trampoline saves the extended state, the exit stub has to as well
+    save_extended_state: bool,
+    // Address of the pointer the trampoline continues to when disabled
+    original_slot: usize,
+    context: Option<HookContext>,
+    // Calls that may still be running, counted from the start of the
+    // trampoline for hooks that call `hook_enter`
+    in_flight: AtomicUsize,
+    calls: AtomicU64,
+    timed_calls: AtomicU64,
+    total_ticks: AtomicU64,
+    min_ticks: AtomicU64,
+    max_ticks: AtomicU64,
+}
+
+impl HookControl {
+    // Kept alive until the hook is freed, the trampoline only has its address
+    pub fn leak(
+        original_slot: NonNull<c_void>,
+        measure_latency: bool,
+        save_extended_state: bool,
+        context: Option<HookContext>,
+    ) -> &'static Self {
+        Box::leak(Box::new(Self {
+            enabled: AtomicBool::new(true),
+            measure_latency,
+            save_extended_state,
+            original_slot: original_slot.as_ptr() as usize,
+            context,
+            in_flight: AtomicUsize::new(0),
+            calls: AtomicU64::new(0),
+            timed_calls: AtomicU64::new(0),
+            total_ticks: AtomicU64::new(0),
+            min_ticks: AtomicU64::new(u64::MAX),
+            max_ticks: AtomicU64::new(0),
+        }))
+    }
+
+    // Nothing may refer to the control anymore, calls in flight included
+    pub unsafe fn free(control: &'static Self) {
+        drop(unsafe { Box::from_raw(control as *const Self as *mut Self) });
+    }
+
+    pub fn enabled_flag(&self) -> *const AtomicBool {
+        &self.enabled
+    }
+
+    pub fn call_counter(&self) -> *const AtomicU64 {
+        &self.calls
+    }
+
+    pub fn in_flight_counter(&self) -> *const AtomicUsize {
+        &self.in_flight
+    }
+
+    pub fn calls_in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Acquire)
+    }
+
+    pub fn finish_call(&self) {
+        self.in_flight.fetch_sub(1, Ordering::Release);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Acquire)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Release);
+    }
+
+    pub fn measures_latency(&self) -> bool {
+        self.measure_latency
+    }
+
+    pub fn saves_extended_state(&self) -> bool {
+        self.save_extended_state
+    }
+
+    pub fn context(&self) -> Option<&HookContext> {
+        self.context.as_ref()
+    }
+
+    pub fn original(&self) -> NonNull<c_void> {
+        let slot = unsafe { AtomicUsize::from_ptr(self.original_slot as *mut usize) };
+        unsafe { NonNull::new_unchecked(slot.load(Ordering::Acquire) as *mut c_void) }
+    }
+
+    pub fn record_latency(&self, ticks: u64) {
+        self.timed_calls.fetch_add(1, Ordering::Relaxed);
+        self.total_ticks.fetch_add(ticks, Ordering::Relaxed);
+        self.min_ticks.fetch_min(ticks, Ordering::Relaxed);
+        self.max_ticks.fetch_max(ticks, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> HookStats {
+        let timed_calls = self.timed_calls.load(Ordering::Relaxed);
+        HookStats {
+            calls: self.calls.load(Ordering::Relaxed),
+            timed_calls,
+            total_ticks: self.total_ticks.load(Ordering::Relaxed),
+            min_ticks: if timed_calls == 0 {
+                0
+            } else {
+                self.min_ticks.load(Ordering::Relaxed)
+            },
+            max_ticks: self.max_ticks.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn reset_stats(&self) {
+        self.calls.store(0, Ordering::Relaxed);
+        self.timed_calls.store(0, Ordering::Relaxed);
+        self.total_ticks.store(0, Ordering::Relaxed);
+        self.min_ticks.store(u64::MAX, Ordering::Relaxed);
+        self.max_ticks.store(0, Ordering::Relaxed);
+    }
+}
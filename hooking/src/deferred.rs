@@ -0,0 +1,412 @@
+use core::ffi::CStr;
+use core::ptr::NonNull;
+use std::cell::Cell;
+use std::ffi::{CString, c_void};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use crate::error::{HookingError, Result};
+use crate::hooks::Hook;
+use crate::mem::{DefaultMemoryController, MemoryController, ModuleOffset};
+use crate::typed::StaticHook;
+
+// Deferred hooks are applied by the function the dynamic linker calls to let
+// debuggers know the module list changed, `_r_debug.r_brk`. It is called by
+// dlopen, dlmopen and loads inside libc alike, after a module was mapped and
+// before any of its code runs.
+#[cfg(target_os = "linux")]
+mod loader {
+    use core::ffi::{CStr, c_char};
+    use core::ptr::NonNull;
+    use std::ffi::c_void;
+    use std::sync::atomic::{AtomicPtr, Ordering};
+
+    use crate::error::{HookingError, Result};
+    use crate::mem::inner::is_module;
+    use crate::mem::{DefaultMemoryController, MemoryController, MemoryError};
+
+    pub type LoadFn = unsafe extern "C" fn();
+
+    const ENDBR64: [u8; 4] = [0xF3, 0x0F, 0x1E, 0xFA];
+    const RET: u8 = 0xC3;
+    const RT_CONSISTENT: i32 = 0;
+
+    // `struct r_debug_extended` from link.h, `next` only exists from version 2
+    #[repr(C)]
+    struct RDebug {
+        version: i32,
+        map: *const LinkMap,
+        brk: usize,
+        state: i32,
+        ldbase: usize,
+        next: *const RDebug,
+    }
+
+    // Start of `struct link_map`, a pointer to it is also its dlopen handle
+    #[repr(C)]
+    struct LinkMap {
+        addr: usize,
+        name: *const c_char,
+        dynamic: *const c_void,
+        next: *const LinkMap,
+        previous: *const LinkMap,
+    }
+
+    static DEBUG: AtomicPtr<RDebug> = AtomicPtr::new(std::ptr::null_mut());
+
+    // `r_brk` only returns and is padded up to the next function, so the
+    // patch covers nothing but padding. Anything else is left alone.
+    pub unsafe fn target() -> Result<LoadFn> {
+        let mem = DefaultMemoryController::new();
+        let debug = unsafe { mem.get_symbol_address(None, c"_r_debug")? }.cast::<RDebug>();
+        DEBUG.store(debug.as_ptr(), Ordering::Relaxed);
+        let brk = unsafe { debug.as_ref().brk };
+
+        let code = unsafe { std::slice::from_raw_parts(brk as *const u8, ENDBR64.len() + 1) };
+        let body = code.strip_prefix(&ENDBR64[..]).unwrap_or(code);
+        if brk % 16 != 0 || body.first() != Some(&RET) {
+            return Err(HookingError::UnexpectedInstructions {
+                target: brk as *const _,
+                found: code.to_vec(),
+            });
+        }
+        Ok(unsafe { std::mem::transmute::<usize, LoadFn>(brk) })
+    }
+
+    // One per link map namespace, dlmopen adds more
+    unsafe fn namespaces() -> impl Iterator<Item = &'static RDebug> {
+        let first = unsafe { DEBUG.load(Ordering::Relaxed).as_ref() };
+        std::iter::successors(first, |debug| {
+            if debug.version < 2 {
+                return None;
+            }
+            unsafe { debug.next.as_ref() }
+        })
+    }
+
+    // Where the symbol is if the module is loaded. Only valid in the detour,
+    // the loader holds its lock and the module lists are consistent, and
+    // doesn't call dlopen or dlclose so the loader state is left alone.
+    pub unsafe fn lookup(module: &CStr, symbol: &CStr) -> Option<Result<NonNull<c_void>>> {
+        let mut maps = unsafe { namespaces() }.flat_map(|debug| {
+            std::iter::successors(unsafe { debug.map.as_ref() }, |map| unsafe {
+                map.next.as_ref()
+            })
+        });
+        let map = maps.find(|map| {
+            !map.name.is_null() && is_module(unsafe { CStr::from_ptr(map.name) }, Some(module))
+        })?;
+
+        let address = unsafe { libc::dlsym(map as *const LinkMap as *mut c_void, symbol.as_ptr()) };
+        Some(NonNull::new(address).ok_or_else(|| {
+            MemoryError::CantFindModule(symbol.to_string_lossy().into_owned()).into()
+        }))
+    }
+
+    pub unsafe extern "C" fn detour() {
+        unsafe { super::LOADER.original()() };
+        // Also called before modules are added or removed
+        if unsafe { namespaces() }.all(|debug| debug.state == RT_CONSISTENT) {
+            super::module_loaded();
+        }
+    }
+}
+
+// LoadLibraryA/W/ExA all end up in LoadLibraryExW of kernelbase. Hooks are
+// applied after the load, the initializers of the module run unhooked.
+#[cfg(windows)]
+mod loader {
+    use core::ffi::CStr;
+    use core::ptr::NonNull;
+    use std::ffi::c_void;
+
+    use crate::error::Result;
+
+    pub type LoadFn = unsafe extern "system" fn(*const u16, *mut c_void, u32) -> *mut c_void;
+
+    pub unsafe fn target() -> Result<LoadFn> {
+        unsafe { crate::typed::resolve_symbol(Some(c"kernelbase.dll"), c"LoadLibraryExW") }
+    }
+
+    pub unsafe fn lookup(module: &CStr, symbol: &CStr) -> Option<Result<NonNull<c_void>>> {
+        unsafe { super::lookup(module, symbol) }
+    }
+
+    pub unsafe extern "system" fn detour(
+        file: *const u16,
+        file_handle: *mut c_void,
+        flags: u32,
+    ) -> *mut c_void {
+        let module = unsafe { super::LOADER.original()(file, file_handle, flags) };
+        if !module.is_null() {
+            super::module_loaded();
+        }
+        module
+    }
+}
+
+// Installed while any hook is pending
+static LOADER: StaticHook<loader::LoadFn> = StaticHook::new();
+
+// The loader can call `module_loaded` while holding its own lock, so nothing
+// may call into the loader while holding this one
+static PENDING: Mutex<Vec<Arc<Deferred>>> = Mutex::new(Vec::new());
+
+thread_local! {
+    // Looking up a module can call the loader again
+    static APPLYING: Cell<bool> = const { Cell::new(false) };
+}
+
+fn pending() -> MutexGuard<'static, Vec<Arc<Deferred>>> {
+    PENDING.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+enum DeferredState {
+    Pending,
+    // `DeferredHook::by_name` found the module already loaded and applies it
+    Applying,
+    Applied(Hook<'static>),
+    Failed(Option<HookingError>),
+}
+
+struct Deferred {
+    module: CString,
+    symbol: CString,
+    destination: *mut u8,
+    state: Mutex<DeferredState>,
+}
+
+// The raw pointers are only addresses of code
+unsafe impl Send for Deferred {}
+unsafe impl Sync for Deferred {}
+
+impl Deferred {
+    fn state(&self) -> MutexGuard<'_, DeferredState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    unsafe fn apply(&self, address: Result<NonNull<c_void>>) -> DeferredState {
+        APPLYING.set(true);
+        let applied = address
+            .and_then(|address| unsafe { Hook::create(address.as_ptr().cast(), self.destination) })
+            .and_then(|mut hook| {
+                unsafe { hook.apply_hook()? };
+                Ok(hook)
+            });
+        APPLYING.set(false);
+        match applied {
+            Ok(hook) => DeferredState::Applied(hook),
+            Err(error) => DeferredState::Failed(Some(error)),
+        }
+    }
+
+    // Applies the hook if the module is loaded, returns false once it is no
+    // longer pending. Only called by the loader.
+    unsafe fn try_apply(&self) -> bool {
+        let mut state = self.state();
+        if !matches!(*state, DeferredState::Pending) {
+            return false;
+        }
+        let Some(address) = (unsafe { loader::lookup(&self.module, &self.symbol) }) else {
+            return true;
+        };
+        *state = unsafe { self.apply(address) };
+        false
+    }
+}
+
+// Where the symbol is if the module is loaded, from outside the loader
+unsafe fn lookup(module: &CStr, symbol: &CStr) -> Option<Result<NonNull<c_void>>> {
+    let mem = DefaultMemoryController::new();
+    unsafe { mem.get_module_address(Some(module), ModuleOffset::Rva(0)) }.ok()?;
+    Some(unsafe { mem.get_symbol_address(Some(module), symbol) }.map_err(Into::into))
+}
+
+// Staying installed only costs a scan of the pending hooks per load, so a
+// failure to remove the loader hook is ignored
+fn uninstall_loader_if_idle(pending: &[Arc<Deferred>]) {
+    if pending.is_empty() {
+        let _ = unsafe { LOADER.uninstall() };
+    }
+}
+
+// Called by the loader hook whenever modules were loaded or unloaded. A load
+// can bring in dependencies too, so every pending hook is checked.
+fn module_loaded() {
+    if APPLYING.get() {
+        return;
+    }
+    let mut pending = pending();
+    pending.retain(|deferred| unsafe { deferred.try_apply() });
+    uninstall_loader_if_idle(&pending);
+}
+
+// A hook on a symbol of a module that might not be loaded yet. On Linux it is
+// applied as soon as the module is mapped, before any of its code runs. On
+// Windows it is applied before the call loading the module returns, so only
+// the module's own initializers can run unhooked.
+//
+// Dropping a pending hook cancels it, an applied hook stays applied.
+pub struct DeferredHook {
+    inner: Arc<Deferred>,
+}
+
+impl DeferredHook {
+    // Applied right away if the module is already loaded
+    pub unsafe fn by_name(module: &CStr, symbol: &CStr, destination: *mut u8) -> Result<Self> {
+        if destination.is_null() {
+            return Err(HookingError::InvalidDestination(destination as *const _));
+        }
+        // Creating the loader hook looks up symbols, done before taking the
+        // lock. Installing it again below only rewrites the patch.
+        let target = unsafe { loader::target()? };
+        unsafe { LOADER.install(target, loader::detour)? };
+
+        let inner = Arc::new(Deferred {
+            module: module.into(),
+            symbol: symbol.into(),
+            destination,
+            state: Mutex::new(DeferredState::Pending),
+        });
+        {
+            let mut pending = pending();
+            // The last pending hook could have removed it meanwhile
+            unsafe { LOADER.install(target, loader::detour)? };
+            pending.push(inner.clone());
+        }
+
+        // The module could have been loaded before the hook was registered.
+        // It is looked up and hooked without holding a lock, the loader may
+        // be waiting for them.
+        let address = unsafe { lookup(module, symbol) }.filter(|_| {
+            let mut state = inner.state();
+            let pending = matches!(*state, DeferredState::Pending);
+            if pending {
+                *state = DeferredState::Applying;
+            }
+            pending
+        });
+        if let Some(address) = address {
+            let applied = unsafe { inner.apply(address) };
+            *inner.state() = applied;
+            let hook = Self { inner };
+            hook.cancel();
+            return Ok(hook);
+        }
+
+        Ok(Self { inner })
+    }
+
+    pub fn is_pending(&self) -> bool {
+        matches!(
+            *self.inner.state(),
+            DeferredState::Pending | DeferredState::Applying
+        )
+    }
+
+    pub fn is_applied(&self) -> bool {
+        matches!(*self.inner.state(), DeferredState::Applied(_))
+    }
+
+    // Why the hook couldn't be applied once the module was loaded
+    pub fn take_error(&self) -> Option<HookingError> {
+        match &mut *self.inner.state() {
+            DeferredState::Failed(error) => error.take(),
+            _ => None,
+        }
+    }
+
+    pub fn original_ptr(&self) -> Option<NonNull<c_void>> {
+        match &*self.inner.state() {
+            DeferredState::Applied(hook) => Some(hook.original_ptr()),
+            _ => None,
+        }
+    }
+
+    // Cancels the hook if it is pending, otherwise removes and frees it
+    pub unsafe fn remove(self) -> Result<()> {
+        self.cancel();
+        let mut state = self.inner.state();
+        let DeferredState::Applied(hook) = &mut *state else {
+            return Ok(());
+        };
+        unsafe { hook.remove_hook()? };
+
+        let DeferredState::Applied(hook) =
+            std::mem::replace(&mut *state, DeferredState::Failed(None))
+        else {
+            unreachable!();
+        };
+        hook.free()
+    }
+
+    fn cancel(&self) {
+        let mut pending = pending();
+        pending.retain(|deferred| !Arc::ptr_eq(deferred, &self.inner));
+        uninstall_loader_if_idle(&pending);
+    }
+}
+
+impl Drop for DeferredHook {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    unsafe extern "C" fn hooked_getaddrinfo_a(
+        _mode: libc::c_int,
+        _list: *mut c_void,
+        _count: libc::c_int,
+        _signal: *mut c_void,
+    ) -> libc::c_int {
+        -42
+    }
+
+    #[test]
+    fn deferred_hooks_apply_when_loaded() {
+        // A glibc stub that nothing else in the tests loads
+        let module = c"libanl.so.1";
+        let deferred = unsafe {
+            DeferredHook::by_name(
+                module,
+                c"getaddrinfo_a",
+                hooked_getaddrinfo_a as *const () as *mut u8,
+            )
+            .unwrap()
+        };
+        assert!(deferred.is_pending());
+
+        assert!(LOADER.is_installed());
+
+        let handle = unsafe { libc::dlopen(module.as_ptr(), libc::RTLD_NOW) };
+        assert!(!handle.is_null());
+        assert!(deferred.is_applied(), "{:?}", deferred.take_error());
+        // Nothing is pending anymore
+        assert!(!LOADER.is_installed());
+
+        let getaddrinfo_a: unsafe extern "C" fn(
+            libc::c_int,
+            *mut c_void,
+            libc::c_int,
+            *mut c_void,
+        ) -> libc::c_int =
+            unsafe { std::mem::transmute(libc::dlsym(handle, c"getaddrinfo_a".as_ptr())) };
+        assert_eq!(
+            unsafe { getaddrinfo_a(0, std::ptr::null_mut(), 0, std::ptr::null_mut()) },
+            -42
+        );
+
+        let target = getaddrinfo_a as *const () as *mut c_void;
+        let is_listed = || {
+            crate::registry::hooks()
+                .iter()
+                .any(|info| info.target.as_ptr() == target)
+        };
+        assert!(is_listed());
+        unsafe { deferred.remove().unwrap() };
+        assert!(!is_listed());
+    }
+}
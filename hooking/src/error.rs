@@ -12,6 +12,18 @@ pub enum HookingError {
     #[error("Assembly error")]
     AssemblyError(#[from] crate::asm::AssemblyError),
 
+    #[error("Thread error")]
+    ThreadError(#[from] crate::threads::ThreadError),
+
+    #[error("Patch error")]
+    PatchError(#[from] crate::patch::PatchError),
+
+    #[error("Scan error")]
+    ScanError(#[from] crate::scan::ScanError),
+
+    #[error("Trace error")]
+    TraceError(#[from] crate::trace::TraceError),
+
     #[error("Provided destination for hook \"{0}\" was null")]
     NoDestination(String),
 
@@ -20,4 +32,25 @@ pub enum HookingError {
 
     #[error("Provided destination for hook {0:?} is invalid")]
     InvalidDestination(*const c_void),
+
+    #[error("Hook for {0:?} is still applied")]
+    StillApplied(*const c_void),
+
+    #[error("Target {0:?} no longer holds the instructions the hook was created for")]
+    TargetModified(*const c_void),
+
+    #[error("Target {target:?} holds {found:02x?} instead of the expected instructions")]
+    UnexpectedInstructions {
+        target: *const c_void,
+        found: Vec<u8>,
+    },
+
+    #[error("Rolling back after \"{error}\" failed: {rollback}")]
+    RollbackFailed {
+        error: Box<HookingError>,
+        rollback: Box<HookingError>,
+    },
+
+    #[error("{} operations failed, the first with: {}", .0.len(), .0[0])]
+    Multiple(Vec<HookingError>),
 }
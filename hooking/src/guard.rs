@@ -0,0 +1,96 @@
+use crate::error::{HookingError, Result};
+use crate::hooks::Hook;
+use crate::mem::{DefaultMemoryController, MemoryController};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum UnhookFailurePolicy {
+    Ignore,
+    Log,
+    // Falls back to logging if the thread is already panicking
+    #[default]
+    Panic,
+    Abort,
+}
+
+impl UnhookFailurePolicy {
+    pub(crate) fn handle(self, error: HookingError) {
+        match self {
+            Self::Ignore => {}
+            Self::Log => eprintln!("Failed to remove hook: {error}"),
+            Self::Panic if std::thread::panicking() => {
+                eprintln!("Failed to remove hook: {error}")
+            }
+            Self::Panic => panic!("Failed to remove hook: {error}"),
+            Self::Abort => {
+                eprintln!("Failed to remove hook: {error}");
+                std::process::abort();
+            }
+        }
+    }
+}
+
+pub struct HookGuard<'h, 'a, M: MemoryController = DefaultMemoryController> {
+    hook: &'h mut Hook<'a, M>,
+    policy: UnhookFailurePolicy,
+}
+
+impl<'h, 'a, M: MemoryController> HookGuard<'h, 'a, M> {
+    pub(crate) fn new(hook: &'h mut Hook<'a, M>, policy: UnhookFailurePolicy) -> Self {
+        Self { hook, policy }
+    }
+
+    pub fn hook(&self) -> &Hook<'a, M> {
+        self.hook
+    }
+
+    pub fn policy(&self) -> UnhookFailurePolicy {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: UnhookFailurePolicy) {
+        self.policy = policy;
+    }
+
+    pub fn remove(self) -> Result<()> {
+        let mut guard = std::mem::ManuallyDrop::new(self);
+        unsafe { guard.hook.remove_hook() }
+    }
+}
+
+impl<'h, 'a, M: MemoryController> Drop for HookGuard<'h, 'a, M> {
+    fn drop(&mut self) {
+        if let Err(error) = unsafe { self.hook.remove_hook() } {
+            self.policy.handle(error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[inline(never)]
+    extern "C" fn answer() -> i32 {
+        std::hint::black_box(42)
+    }
+
+    extern "C" fn hooked_answer() -> i32 {
+        7
+    }
+
+    #[test]
+    fn guard_removes_hook_on_drop() {
+        let answer_fn: extern "C" fn() -> i32 = std::hint::black_box(answer);
+        let mut hook =
+            unsafe { Hook::create(answer as *mut u8, hooked_answer as *mut u8).unwrap() };
+
+        {
+            let guard = unsafe { hook.apply_scoped().unwrap() };
+            assert!(guard.hook().is_applied());
+            assert_eq!(answer_fn(), 7);
+        }
+
+        assert!(!hook.is_applied());
+        assert_eq!(answer_fn(), 42);
+    }
+}
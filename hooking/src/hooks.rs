@@ -1,17 +1,68 @@
 use core::ffi;
 use core::{ffi::CStr, ptr::NonNull};
 use std::ffi::c_void;
+use std::ops::Range;
 
-use crate::asm::{DefaultHookAssembler, HookAssembler};
+use crate::asm::{
+    DefaultHookAssembler, HookAssembler, MAX_PATCH_SIZE, RELOCATION_READ_AHEAD, ReturnStub,
+    TrampolinePrologue,
+};
+use crate::control::{HookContext, HookControl, HookStats, OriginalPointerMode, StatsOptions};
 use crate::error::{HookingError, Result};
-use crate::mem::{DefaultMemoryController, HookHeap, MemoryController, MemoryProtection};
+use crate::guard::{HookGuard, UnhookFailurePolicy};
+use crate::mem::{DefaultMemoryController, HookHeap, MemoryController, ModuleOffset, SymbolInfo};
+use crate::patch::{PatchOptions, write_patch};
+use crate::registry::{self, ChainLink, HookChain, HookInfo, HookIntegrity, Patcher};
+use crate::runtime;
 
 static HOOK_HEAP: HookHeap<DefaultMemoryController> = HookHeap::new();
 
+// Upper bound for the restore pointer, trampoline and relocated
+// instructions of a single hook.
+const HOOK_TABLE_SIZE: usize = 0x120;
+
+// Saving and restoring every register takes up most of it
+const CONTEXT_HOOK_TABLE_SIZE: usize = 0x300;
+
+// Room for the stub next to the trampoline
+const RETURN_STUB_TABLE_SIZE: usize = HOOK_TABLE_SIZE + 0x80;
+
+// A detour table with the stub that hands the hook to a closure shim
+
+// Added to the table size when the trampoline saves the extended state
+const EXTENDED_STATE_TABLE_SIZE: usize = 0x100;
+
+// What the target jumps to
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Trampoline {
+    // Continues at the destination with the target's arguments
+    Detour(NonNull<c_void>),
+    // Calls `handler(control, cpu_context)` with every register saved, see
+    // `HookAssembler::assemble_context_trampoline`
+    Context(NonNull<c_void>),
+    // Returns from the target right away, the stub is written next to the
+    // trampoline
+    Return(ReturnStub),
+    // Continues at the closure shim, which finds the hook through the call
+    // the trampoline recorded
+    Closure(NonNull<c_void>),
+}
+
+impl Trampoline {
+    fn table_size(self) -> usize {
+        match self {
+            Self::Detour(_) => HOOK_TABLE_SIZE,
+            Self::Context(_) => CONTEXT_HOOK_TABLE_SIZE,
+            Self::Return(_) => RETURN_STUB_TABLE_SIZE,
+            Self::Closure(_) => HOOK_TABLE_SIZE,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Hook<'a, M: MemoryController = DefaultMemoryController> {
     pub data: HookData<'a, M>,
-    is_applied: bool,
+    patch_options: PatchOptions,
 }
 
 impl Hook<'static, DefaultMemoryController> {
@@ -24,6 +75,18 @@ impl Hook<'static, DefaultMemoryController> {
         unsafe { hook_writer.create_hook_by_name(module, symbol, destination) }
     }
 
+    // For functions that aren't exported. `expected` is compared with the
+    // start of the target before anything is written.
+    pub unsafe fn by_offset(
+        module: Option<&CStr>,
+        offset: ModuleOffset,
+        expected: Option<&[u8]>,
+        destination: *mut u8,
+    ) -> Result<Self> {
+        let hook_writer = HookWriter::from_static();
+        unsafe { hook_writer.create_hook_by_offset(module, offset, expected, destination) }
+    }
+
     pub unsafe fn create(target: *mut u8, destination: *mut u8) -> Result<Self> {
         let hook_writer = HookWriter::from_static();
         unsafe {
@@ -38,61 +101,336 @@ impl Hook<'static, DefaultMemoryController> {
 }
 
 impl<'a, M: MemoryController> Hook<'a, M> {
-    pub unsafe fn apply_hook(&mut self) -> Result<()> {
-        if self.is_applied {
-            return Ok(());
+    fn id(&self) -> usize {
+        self.data.table_address.as_ptr() as usize
+    }
+
+    pub fn is_applied(&self) -> bool {
+        registry::is_applied(self.data.symbol_address.as_ptr() as usize, self.id())
+    }
+
+    pub fn info(&self) -> Option<HookInfo> {
+        registry::hook_info(self.id())
+    }
+
+    // Compares the target with what this hook and the hooks chained with it
+    // have written there.
+    pub fn verify(&self) -> HookIntegrity {
+        let target = self.data.symbol_address.as_ptr() as usize;
+        let chains = registry::chains();
+
+        let Some(chain) = chains.get(&target) else {
+            let original = &self.data.original_instructions;
+            let current = unsafe { registry::read_target(target, original.len()) };
+            return if current == *original {
+                HookIntegrity::Removed
+            } else {
+                HookIntegrity::Overwritten(current)
+            };
+        };
+
+        let expected = chain.expected_bytes(self.data.patch_data.len());
+        let original = &chain.original[..expected.len()];
+        let current = unsafe { registry::read_target(target, expected.len()) };
+
+        if chain.position(self.id()).is_some() {
+            registry::integrity(&current, &expected, original)
+        } else if current == expected || current == original {
+            HookIntegrity::Removed
+        } else {
+            HookIntegrity::Overwritten(current)
+        }
+    }
+
+    // Writes the patch again if something changed the target while the hook
+    // was applied. Returns what was found before.
+    pub unsafe fn reapply(&mut self) -> Result<HookIntegrity> {
+        let target = self.data.symbol_address.as_ptr() as usize;
+        {
+            let chains = registry::chains();
+            if let Some(chain) = chains.get(&target)
+                && chain.position(self.id()).is_some()
+            {
+                return unsafe { registry::reapply_chain(target, chain, self.patch_options) };
+            }
         }
+        Ok(self.verify())
+    }
+
+    // Where calls continue from this hook, the relocated original function or
+    // the next hook chained on the same target
+    pub fn original_ptr(&self) -> NonNull<c_void> {
+        self.data.control.original()
+    }
+
+    // A disabled hook stays applied but calls go straight to the original
+    // function, or the next hook chained on the same target.
+    pub fn is_enabled(&self) -> bool {
+        self.data.control.is_enabled()
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.data.control.set_enabled(enabled);
+    }
+
+    pub fn enable(&self) {
+        self.set_enabled(true);
+    }
+
+    pub fn disable(&self) {
+        self.set_enabled(false);
+    }
+
+    // Only collected if the writer was set up to with `HookWriter::with_stats`
+    pub fn stats(&self) -> HookStats {
+        self.data.control.stats()
+    }
+
+    pub fn reset_stats(&self) {
+        self.data.control.reset_stats();
+    }
+
+    pub fn patch_options(&self) -> PatchOptions {
+        self.patch_options
+    }
+
+    pub fn set_patch_options(&mut self, options: PatchOptions) {
+        self.patch_options = options;
+    }
+
+    pub unsafe fn apply_scoped(&mut self) -> Result<HookGuard<'_, 'a, M>> {
+        unsafe { self.apply_scoped_with(UnhookFailurePolicy::default()) }
+    }
+
+    pub unsafe fn apply_scoped_with(
+        &mut self,
+        policy: UnhookFailurePolicy,
+    ) -> Result<HookGuard<'_, 'a, M>> {
+        unsafe { self.apply_hook()? };
+        Ok(HookGuard::new(self, policy))
+    }
+
+    fn chain_link(&self) -> ChainLink {
+        ChainLink {
+            id: self.id(),
+            trampoline: self.data.trampoline_data.as_ptr() as usize,
+            stub: self.data.original_fn_call_stub_data.as_ptr() as usize,
+            slot: self.data.original_fn_slot.as_ptr() as usize,
+            patch: self.data.patch_data.clone(),
+            patcher: Patcher::new(&self.data.heap.mem),
+        }
+    }
+
+    pub unsafe fn apply_hook(&mut self) -> Result<()> {
+        let link = self.chain_link();
         let HookData {
-            mem,
+            heap,
             patch_data,
+            original_instructions,
             symbol_address,
+            instruction_offsets,
+            original_fn_slot,
             ..
         } = &self.data;
 
-        let _protection_guard =
-            mem.protection_guard_for_page(*symbol_address, MemoryProtection::ReadWrite, None)?;
+        let target = symbol_address.as_ptr() as usize;
+        let stub = link.stub;
 
-        unsafe {
-            std::ptr::copy_nonoverlapping(
-                patch_data.as_ptr(),
-                symbol_address.as_ptr() as *mut _,
-                patch_data.len(),
-            );
+        let mut chains = registry::chains();
+        let chain = chains
+            .entry(target)
+            .or_insert_with(|| HookChain::new(original_instructions.clone()));
+        if chain.position(link.id).is_some() {
+            return Ok(());
         }
-        self.is_applied = true;
 
-        Ok(())
-    }
-    pub unsafe fn remove_hook(&mut self) -> Result<()> {
-        if !self.is_applied {
-            return Ok(());
+        // Relocated instructions are only valid for the bytes they were made from
+        let expected = chain.expected_bytes(patch_data.len());
+        let current = unsafe { registry::read_target(target, expected.len()) };
+        if current != expected || chain.original[..patch_data.len()] != original_instructions[..] {
+            if chain.links.is_empty() {
+                chains.remove(&target);
+            }
+            return Err(HookingError::TargetModified(symbol_address.as_ptr()));
         }
+        unsafe { chain.extend_original(target, patch_data.len()) };
 
+        // The new hook calls whatever the target jumped to until now
+        let next = chain.head().map_or(stub, |head| head.trampoline);
+        let previous_len = chain.head().map_or(0, |head| head.patch.len());
+        let bytes = chain.patched_bytes(Some(patch_data), previous_len);
+
+        // Threads inside the overwritten instructions continue from the same
+        // instruction in the relocated stub. The first instruction is left
+        // alone, it becomes the jump into the hook.
+        let result = unsafe {
+            heap.write_pointer(*original_fn_slot, next)
+                .map_err(HookingError::from)
+                .and_then(|_| {
+                    write_patch(
+                        &heap.mem,
+                        *symbol_address,
+                        &bytes,
+                        NonNull::new_unchecked(next as *mut c_void),
+                        self.patch_options,
+                        |instruction_pointer| {
+                            let offset = instruction_pointer.checked_sub(target)?;
+                            if offset == 0 || offset >= bytes.len() {
+                                return None;
+                            }
+                            instruction_offsets
+                                .iter()
+                                .find(|(source_offset, _)| *source_offset == offset)
+                                .map(|(_, relocated_offset)| stub + relocated_offset)
+                        },
+                    )
+                })
+        };
+
+        match result {
+            Ok(()) => {
+                chain.links.push(link);
+                Ok(())
+            }
+            Err(error) => {
+                if chain.links.is_empty() {
+                    chains.remove(&target);
+                }
+                Err(error)
+            }
+        }
+    }
+    pub unsafe fn remove_hook(&mut self) -> Result<()> {
         let HookData {
-            mem,
-            patch_data,
-            original_instructions,
+            heap,
             symbol_address,
+            original_fn_call_stub_data,
+            instruction_offsets,
+            table_address,
             ..
         } = &self.data;
 
-        let _protection_guard =
-            mem.protection_guard_for_page(*symbol_address, MemoryProtection::ReadWrite, None)?;
+        let target = symbol_address.as_ptr() as usize;
+        let stub = original_fn_call_stub_data.as_ptr() as usize;
 
-        unsafe {
-            std::ptr::copy_nonoverlapping(
-                original_instructions.as_ptr(),
-                symbol_address.as_ptr() as *mut _,
-                patch_data.len(),
+        // Not being part of the chain means it is not applied, possibly
+        // because `registry::remove_all` restored the target.
+        let mut chains = registry::chains();
+        let Some(chain) = chains.get_mut(&target) else {
+            return Ok(());
+        };
+        let Some(position) = chain.position(table_address.as_ptr() as usize) else {
+            return Ok(());
+        };
+
+        let next = position
+            .checked_sub(1)
+            .map(|next| chain.links[next].clone());
+
+        if let Some(caller) = chain.links.get(position + 1) {
+            // Hooks applied later call this one, skip over it
+            let destination = next.map_or(caller.stub, |next| next.trampoline);
+            let caller_slot = NonNull::new(caller.slot as *mut c_void)
+                .ok_or(HookingError::InvalidTarget(caller.slot as *const _))?;
+            unsafe { heap.write_pointer(caller_slot, destination)? };
+        } else {
+            let bytes = chain.patched_bytes(
+                next.as_ref().map(|next| &next.patch[..]),
+                chain.links[position].patch.len(),
             );
+            let redirect = next.as_ref().map_or(stub, |next| next.trampoline);
+            let unhooked = next.is_none();
+
+            // Threads at the start of a relocated instruction go back to the
+            // original one, anything else in the stub can finish from there.
+            unsafe {
+                write_patch(
+                    &heap.mem,
+                    *symbol_address,
+                    &bytes,
+                    NonNull::new_unchecked(redirect as *mut c_void),
+                    self.patch_options,
+                    |instruction_pointer| {
+                        let offset = instruction_pointer.checked_sub(stub)?;
+                        if !unhooked {
+                            return None;
+                        }
+                        instruction_offsets
+                            .iter()
+                            .find(|(_, relocated_offset)| *relocated_offset == offset)
+                            .map(|(source_offset, _)| target + source_offset)
+                    },
+                )?;
+            }
+        }
+
+        chain.links.remove(position);
+        if chain.links.is_empty() {
+            chains.remove(&target);
+        }
+
+        Ok(())
+    }
+
+    // Releases the trampoline and relocated instructions of a removed hook
+    // back to the hook heap once no thread can run them anymore. Calls that
+    // entered the hook before it was removed may still be in the detour and
+    // call the original function stub later, the heap only reuses the memory
+    // once `runtime::hook_running` rules that out. Hooks that don't call
+    // `hook_enter`, like those in `OriginalPointerMode::Register` or mid
+    // hooks, can't be checked and their memory is never reused. Neither can
+    // a hook in `OriginalPointerMode::Register` applied over this one that
+    // handed its destination this trampoline.
+    pub fn free(self) -> Result<()> {
+        if self.is_applied() {
+            return Err(HookingError::StillApplied(
+                self.data.symbol_address.as_ptr(),
+            ));
         }
 
-        self.is_applied = false;
+        let HookData {
+            heap,
+            table_address,
+            table_size,
+            control,
+            tracked,
+            ..
+        } = self.data;
+
+        registry::unregister(table_address.as_ptr() as usize);
+        if !tracked {
+            return Ok(());
+        }
 
+        let start = table_address.as_ptr() as usize;
+        let mut freed = FreedHook {
+            control,
+            code: start..start + table_size,
+        };
+        unsafe { heap.retire(table_address, table_size, Box::new(move || freed.idle()))? };
         Ok(())
     }
 }
 
+// A freed hook whose memory hasn't been released yet, the control goes
+// along with it
+struct FreedHook {
+    control: &'static HookControl,
+    code: Range<usize>,
+}
+
+impl FreedHook {
+    fn idle(&mut self) -> bool {
+        !runtime::hook_running(self.control, self.code.clone())
+    }
+}
+
+impl Drop for FreedHook {
+    fn drop(&mut self) {
+        unsafe { HookControl::free(self.control) };
+    }
+}
+
 #[derive(Debug)]
 pub struct HookData<'a, M: MemoryController> {
     pub symbol_address: NonNull<ffi::c_void>,
@@ -100,12 +438,29 @@ pub struct HookData<'a, M: MemoryController> {
     pub original_fn_call_stub_data: &'a [u8],
     pub patch_data: Vec<u8>,
     pub original_instructions: Vec<u8>,
-    mem: &'a M,
+    // (source offset, relocated offset) of each overwritten instruction
+    pub instruction_offsets: Vec<(usize, usize)>,
+    table_address: NonNull<ffi::c_void>,
+    original_fn_slot: NonNull<ffi::c_void>,
+    table_size: usize,
+    pub(crate) control: &'static HookControl,
+    // The trampoline counts calls in flight in the control
+    tracked: bool,
+    heap: &'a HookHeap<M>,
 }
 
+// The hook only refers to the hook heap and process-wide state that is
+// synchronized on its own
+unsafe impl<M: MemoryController + Sync> Send for HookData<'_, M> {}
+unsafe impl<M: MemoryController + Sync> Sync for HookData<'_, M> {}
+
 pub struct HookWriter<'a, M: MemoryController, A: HookAssembler> {
-    hook_heap: &'a HookHeap<M>,
+    pub(crate) hook_heap: &'a HookHeap<M>,
     asm: A,
+    patch_options: PatchOptions,
+    stats_options: StatsOptions,
+    original_pointer_mode: OriginalPointerMode,
+    save_extended_state: bool,
 }
 
 impl HookWriter<'static, DefaultMemoryController, DefaultHookAssembler> {
@@ -119,9 +474,64 @@ impl<'a, M: MemoryController, A: HookAssembler> HookWriter<'a, M, A> {
         Self {
             hook_heap,
             asm: assembler,
+            patch_options: PatchOptions::new(),
+            stats_options: StatsOptions::new(),
+            original_pointer_mode: OriginalPointerMode::Tracked,
+            save_extended_state: false,
+        }
+    }
+
+    pub const fn with_original_pointer_mode(mut self, mode: OriginalPointerMode) -> Self {
+        self.original_pointer_mode = mode;
+        self
+    }
+
+    pub const fn with_stats(mut self, options: StatsOptions) -> Self {
+        self.stats_options = options;
+        self
+    }
+
+    // Saves and restores the full extended state (AVX, AVX-512, ...) with
+    // `xsave` around the calls trampolines make into this crate: mid hook
+    // and probe callbacks, and the enter and exit of hooks that measure
+    // latency, track their original or read a context. A plain detour makes
+    // none of those and is unaffected, it has to preserve the vector
+    // registers it uses itself. Hooks fail to create without XSAVE support.
+    pub const fn with_extended_state(mut self, save: bool) -> Self {
+        self.save_extended_state = save;
+        self
+    }
+
+    // `uses_context` when the destination finds its context through the
+    // current hook
+    fn trampoline_prologue(
+        &self,
+        control: &'static HookControl,
+        extended_state: Option<usize>,
+        uses_context: bool,
+    ) -> TrampolinePrologue {
+        let pointer = |address: *const ()| NonNull::new(address as *mut c_void);
+        TrampolinePrologue {
+            call_counter: pointer(control.call_counter().cast())
+                .filter(|_| self.stats_options.count_calls),
+            enter_fn: pointer(runtime::hook_enter as *const ())
+                .zip(pointer((control as *const HookControl).cast()))
+                .filter(|_| {
+                    self.stats_options.measure_latency
+                        || self.original_pointer_mode == OriginalPointerMode::Tracked
+                        || uses_context
+                }),
+            in_flight: pointer(control.in_flight_counter().cast()),
+            enabled_flag: pointer(control.enabled_flag().cast()),
+            extended_state,
         }
     }
 
+    pub const fn with_patch_options(mut self, options: PatchOptions) -> Self {
+        self.patch_options = options;
+        self
+    }
+
     pub unsafe fn create_hook_by_name(
         &self,
         module: Option<&CStr>,
@@ -134,7 +544,43 @@ impl<'a, M: MemoryController, A: HookAssembler> HookWriter<'a, M, A> {
 
         unsafe {
             let symbol_address = self.hook_heap.mem.get_symbol_address(module, symbol)?;
-            self.create_hook(symbol_address, destination)
+            let mut symbol_info = self.hook_heap.mem.get_symbol_info(symbol_address);
+            symbol_info.symbol = Some(symbol.to_string_lossy().into_owned());
+            if symbol_info.module.is_none() {
+                symbol_info.module = module.map(|module| module.to_string_lossy().into_owned());
+            }
+            self.create_registered_hook(symbol_address, destination, symbol_info, None)
+        }
+    }
+
+    pub unsafe fn create_hook_by_offset(
+        &self,
+        module: Option<&CStr>,
+        offset: ModuleOffset,
+        expected: Option<&[u8]>,
+        destination: *mut u8,
+    ) -> Result<Hook<'a, M>> {
+        let destination = NonNull::new(destination as *mut c_void)
+            .ok_or(HookingError::InvalidDestination(destination as *const _))?;
+
+        unsafe {
+            let target = self.hook_heap.mem.get_module_address(module, offset)?;
+            if let Some(expected) = expected {
+                // Compared with what was there before any other hook
+                let found = registry::original_bytes(target, expected.len());
+                if found != expected {
+                    return Err(HookingError::UnexpectedInstructions {
+                        target: target.as_ptr(),
+                        found,
+                    });
+                }
+            }
+
+            let mut symbol_info = self.hook_heap.mem.get_symbol_info(target);
+            if symbol_info.module.is_none() {
+                symbol_info.module = module.map(|module| module.to_string_lossy().into_owned());
+            }
+            self.create_registered_hook(target, destination, symbol_info, None)
         }
     }
 
@@ -143,10 +589,49 @@ impl<'a, M: MemoryController, A: HookAssembler> HookWriter<'a, M, A> {
         target: NonNull<c_void>,
         destination: NonNull<c_void>,
     ) -> Result<Hook<'a, M>> {
-        let hook_data = unsafe { self.write_hook_table(target, destination)? };
+        unsafe {
+            let symbol_info = self.hook_heap.mem.get_symbol_info(target);
+            self.create_registered_hook(target, destination, symbol_info, None)
+        }
+    }
+
+    // Hooks with a context are always tracked, their probes find the context
+    // through the current hook
+    pub(crate) unsafe fn create_registered_hook(
+        &self,
+        target: NonNull<c_void>,
+        destination: NonNull<c_void>,
+        symbol_info: SymbolInfo,
+        context: Option<HookContext>,
+    ) -> Result<Hook<'a, M>> {
+        unsafe {
+            self.create_registered_trampoline(
+                target,
+                Trampoline::Detour(destination),
+                symbol_info,
+                context,
+            )
+        }
+    }
+
+    pub(crate) unsafe fn create_registered_trampoline(
+        &self,
+        target: NonNull<c_void>,
+        trampoline: Trampoline,
+        symbol_info: SymbolInfo,
+        context: Option<HookContext>,
+    ) -> Result<Hook<'a, M>> {
+        let hook_data = unsafe { self.write_context_hook_table(target, trampoline, context)? };
+        registry::register(
+            hook_data.table_address.as_ptr() as usize,
+            target.as_ptr() as usize,
+            symbol_info,
+            hook_data.trampoline_data.as_ptr() as usize,
+            hook_data.original_fn_call_stub_data.as_ptr() as usize,
+        );
         Ok(Hook {
             data: hook_data,
-            is_applied: false,
+            patch_options: self.patch_options,
         })
     }
 
@@ -155,22 +640,103 @@ impl<'a, M: MemoryController, A: HookAssembler> HookWriter<'a, M, A> {
         target: NonNull<ffi::c_void>,
         destination_fn: NonNull<ffi::c_void>,
     ) -> Result<HookData<'a, M>> {
-        let mut heap_handle = self.hook_heap.get_handle()?;
+        unsafe { self.write_context_hook_table(target, Trampoline::Detour(destination_fn), None) }
+    }
+
+    unsafe fn write_context_hook_table(
+        &self,
+        target: NonNull<ffi::c_void>,
+        trampoline: Trampoline,
+        context: Option<HookContext>,
+    ) -> Result<HookData<'a, M>> {
+        // Read before taking the heap, applying hooks locks them the other way
+        // around. Another hook may already be written over the target.
+        let source =
+            unsafe { registry::original_bytes(target, MAX_PATCH_SIZE + RELOCATION_READ_AHEAD) };
+
+        let extended_state = self
+            .save_extended_state
+            .then(runtime::extended_state_size)
+            .transpose()?;
+        let table_size =
+            trampoline.table_size() + extended_state.map_or(0, |_| EXTENDED_STATE_TABLE_SIZE);
+
+        let mut heap_handle = self.hook_heap.get_handle(table_size, Some(target))?;
+        let table_address = unsafe { heap_handle.block_address()? };
         let mut write_handle = heap_handle.begin_write()?;
 
         let restore_fn_address = unsafe { write_handle.reserve(std::mem::size_of::<usize>())? };
+        let control = HookControl::leak(
+            restore_fn_address,
+            self.stats_options.measure_latency,
+            extended_state.is_some(),
+            context,
+        );
         let mut eip = unsafe { write_handle.write_address()? }.as_ptr() as usize;
 
-        let (trampoline_address, trampoline_size) = {
-            let trampoline =
-                self.asm
-                    .assemble_trampoline(eip, destination_fn, Some(restore_fn_address))?;
+        let (trampoline_address, trampoline_size, tracked) = {
+            let (trampoline, tracked) = match trampoline {
+                Trampoline::Detour(destination_fn) => {
+                    let prologue = self.trampoline_prologue(
+                        control,
+                        extended_state,
+                        control.context().is_some(),
+                    );
+                    let trampoline = self.asm.assemble_trampoline(
+                        eip,
+                        destination_fn,
+                        Some(restore_fn_address),
+                        &prologue,
+                    )?;
+                    (trampoline, prologue.enter_fn.is_some())
+                }
+                Trampoline::Context(handler) => {
+                    let trampoline = self.asm.assemble_context_trampoline(
+                        eip,
+                        target.as_ptr() as usize,
+                        handler,
+                        NonNull::from(control).cast(),
+                        extended_state,
+                    )?;
+                    (trampoline, false)
+                }
+                // The stub only reads its context from machine code and never
+                // calls an original, only timing it needs `hook_enter`
+                Trampoline::Return(stub) => {
+                    let stub = self.asm.assemble_return_stub(eip, &stub)?;
+                    let stub_address = unsafe { write_handle.write_bytes(&stub)? };
+                    eip += stub.len();
+                    let mut prologue = self.trampoline_prologue(control, extended_state, false);
+                    prologue.enter_fn = prologue
+                        .enter_fn
+                        .filter(|_| self.stats_options.measure_latency);
+                    let trampoline = self.asm.assemble_trampoline(
+                        eip,
+                        stub_address,
+                        Some(restore_fn_address),
+                        &prologue,
+                    )?;
+                    (trampoline, prologue.enter_fn.is_some())
+                }
+                // Recorded in every mode, the shim looks up its closure from
+                // the call
+                Trampoline::Closure(shim) => {
+                    let trampoline = self.asm.assemble_trampoline(
+                        eip,
+                        shim,
+                        Some(restore_fn_address),
+                        &self.trampoline_prologue(control, extended_state, true),
+                    )?;
+                    (trampoline, true)
+                }
+            };
 
             eip += trampoline.len();
 
             (
                 unsafe { write_handle.write_bytes(&trampoline)? },
                 trampoline.len(),
+                tracked,
             )
         };
 
@@ -178,15 +744,15 @@ impl<'a, M: MemoryController, A: HookAssembler> HookWriter<'a, M, A> {
             .asm
             .assemble_patch(target.as_ptr() as usize, trampoline_address)?;
 
-        let (original_fn_call_stub_address, restore_stub_size) = {
-            let restore_stub = self
-                .asm
-                .relocate_instructions(eip, target, patch.len(), true)?;
+        let restore_stub =
+            self.asm
+                .relocate_instructions(eip, target, &source, patch.len(), true)?;
 
-            //eip += restore_stub.len();
+        let (original_fn_call_stub_address, restore_stub_size) = {
+            //eip += restore_stub.code.len();
             (
-                unsafe { write_handle.write_bytes(&restore_stub)? },
-                restore_stub.len(),
+                unsafe { write_handle.write_bytes(&restore_stub.code)? },
+                restore_stub.code.len(),
             )
         };
 
@@ -195,14 +761,19 @@ impl<'a, M: MemoryController, A: HookAssembler> HookWriter<'a, M, A> {
             restore_fn_address.cast().write(write_restore_addr);
         }
 
-        let original_fn_instructions =
-            unsafe { std::slice::from_raw_parts(target.as_ptr() as *const u8, patch.len()) };
+        drop(write_handle);
 
         Ok(HookData {
-            mem: &self.hook_heap.mem,
+            heap: self.hook_heap,
+            control,
+            tracked,
+            table_address,
+            original_fn_slot: restore_fn_address,
+            table_size: heap_handle.written(),
             symbol_address: target,
+            original_instructions: source[..patch.len()].into(),
             patch_data: patch,
-            original_instructions: original_fn_instructions.into(),
+            instruction_offsets: restore_stub.instruction_offsets,
             trampoline_data: unsafe {
                 core::slice::from_raw_parts(
                     trampoline_address.as_ptr() as *const _,
@@ -218,3 +789,336 @@ impl<'a, M: MemoryController, A: HookAssembler> HookWriter<'a, M, A> {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[inline(never)]
+    extern "C" fn value() -> i32 {
+        std::hint::black_box(1)
+    }
+
+    extern "C" fn add_one() -> i32 {
+        let original: extern "C" fn() -> i32 =
+            unsafe { std::mem::transmute(crate::original_function_ptr()) };
+        original() + 1
+    }
+
+    extern "C" fn times_ten() -> i32 {
+        let original: extern "C" fn() -> i32 =
+            unsafe { std::mem::transmute(crate::original_function_ptr()) };
+        original() * 10
+    }
+
+    #[inline(never)]
+    extern "C" fn inner() -> i32 {
+        std::hint::black_box(6)
+    }
+
+    #[inline(never)]
+    extern "C" fn outer() -> i32 {
+        std::hint::black_box(7)
+    }
+
+    extern "C" fn hooked_inner() -> i32 {
+        let original: extern "C" fn() -> i32 =
+            unsafe { std::mem::transmute(crate::original_function_ptr()) };
+        original() + 100
+    }
+
+    extern "C" fn hooked_outer() -> i32 {
+        let inner_fn: extern "C" fn() -> i32 = std::hint::black_box(inner);
+        let nested = inner_fn();
+        let original: extern "C" fn() -> i32 =
+            unsafe { std::mem::transmute(crate::original_function_ptr()) };
+        original() * 1000 + nested
+    }
+
+    #[test]
+    fn original_function_ptr_survives_nested_hooks() {
+        let outer_fn: extern "C" fn() -> i32 = std::hint::black_box(outer);
+        let mut inner_hook =
+            unsafe { Hook::create(inner as *mut u8, hooked_inner as *mut u8).unwrap() };
+        let mut outer_hook =
+            unsafe { Hook::create(outer as *mut u8, hooked_outer as *mut u8).unwrap() };
+
+        unsafe {
+            inner_hook.apply_hook().unwrap();
+            outer_hook.apply_hook().unwrap();
+            assert_eq!(outer_fn(), 7106);
+            assert_eq!(outer_fn(), 7106);
+            // Both calls returned
+            assert_eq!(crate::try_original_function_ptr(), None);
+
+            outer_hook.remove_hook().unwrap();
+            inner_hook.remove_hook().unwrap();
+        }
+    }
+
+    #[test]
+    fn chained_hooks_can_be_removed_in_any_order() {
+        let value_fn: extern "C" fn() -> i32 = std::hint::black_box(value);
+        let mut first = unsafe { Hook::create(value as *mut u8, add_one as *mut u8).unwrap() };
+        let mut second = unsafe { Hook::create(value as *mut u8, times_ten as *mut u8).unwrap() };
+
+        unsafe {
+            first.apply_hook().unwrap();
+            second.apply_hook().unwrap();
+            assert_eq!(value_fn(), 20);
+
+            first.remove_hook().unwrap();
+            assert_eq!(value_fn(), 10);
+
+            first.apply_hook().unwrap();
+            assert_eq!(value_fn(), 11);
+
+            first.remove_hook().unwrap();
+            assert_eq!(value_fn(), 10);
+
+            second.remove_hook().unwrap();
+            assert_eq!(value_fn(), 1);
+
+            first.free().unwrap();
+            second.free().unwrap();
+        }
+    }
+
+    #[inline(never)]
+    extern "C" fn toggled() -> i32 {
+        std::hint::black_box(2)
+    }
+
+    #[test]
+    fn disabled_hooks_fall_through_to_the_chain() {
+        let toggled_fn: extern "C" fn() -> i32 = std::hint::black_box(toggled);
+        let mut first = unsafe { Hook::create(toggled as *mut u8, add_one as *mut u8).unwrap() };
+        let mut second = unsafe { Hook::create(toggled as *mut u8, times_ten as *mut u8).unwrap() };
+
+        unsafe {
+            first.apply_hook().unwrap();
+            second.apply_hook().unwrap();
+        }
+        assert_eq!(toggled_fn(), 30);
+
+        second.disable();
+        assert!(!second.is_enabled());
+        assert_eq!(toggled_fn(), 3);
+
+        first.disable();
+        assert_eq!(toggled_fn(), 2);
+
+        second.enable();
+        assert_eq!(toggled_fn(), 20);
+
+        unsafe {
+            second.remove_hook().unwrap();
+            first.remove_hook().unwrap();
+            first.free().unwrap();
+            second.free().unwrap();
+        }
+        assert_eq!(toggled_fn(), 2);
+    }
+
+    #[inline(never)]
+    extern "C" fn mix(a: i64, b: f64, c: i64, d: f64, e: i64, f: i64, g: i64) -> f64 {
+        std::hint::black_box(a + c + e + f + g) as f64 * b + d
+    }
+
+    extern "C" fn hooked_mix(a: i64, b: f64, c: i64, d: f64, e: i64, f: i64, g: i64) -> f64 {
+        let original: extern "C" fn(i64, f64, i64, f64, i64, i64, i64) -> f64 =
+            unsafe { std::mem::transmute(crate::register_original_function_ptr()) };
+        original(a, b, c, d, e, f, g) + 1.0
+    }
+
+    #[test]
+    fn stats_count_and_time_calls() {
+        let mix_fn: extern "C" fn(i64, f64, i64, f64, i64, i64, i64) -> f64 =
+            std::hint::black_box(mix);
+        let writer = HookWriter::from_static()
+            .with_original_pointer_mode(OriginalPointerMode::Register)
+            .with_stats(
+                StatsOptions::new()
+                    .with_count_calls(true)
+                    .with_measure_latency(true),
+            );
+        let mut hook = unsafe {
+            writer
+                .create_hook(
+                    NonNull::new(mix as *mut c_void).unwrap(),
+                    NonNull::new(hooked_mix as *mut c_void).unwrap(),
+                )
+                .unwrap()
+        };
+
+        unsafe { hook.apply_hook().unwrap() };
+        for _ in 0..10 {
+            assert_eq!(mix_fn(1, 2.0, 3, 0.5, 4, 5, 6), 39.5);
+        }
+        hook.disable();
+        assert_eq!(mix_fn(1, 2.0, 3, 0.5, 4, 5, 6), 38.5);
+
+        let stats = hook.stats();
+        assert_eq!(stats.calls, 11);
+        assert_eq!(stats.timed_calls, 11);
+        assert!(stats.min_ticks <= stats.max_ticks);
+        assert!(stats.average_ticks().is_some());
+
+        hook.reset_stats();
+        assert_eq!(hook.stats(), HookStats::default());
+
+        unsafe {
+            hook.remove_hook().unwrap();
+            hook.free().unwrap();
+        }
+    }
+
+    #[inline(never)]
+    extern "C" fn guarded() -> i32 {
+        std::hint::black_box(5)
+    }
+
+    extern "C" fn hooked_guarded() -> i32 {
+        6
+    }
+
+    unsafe fn scribble(hook: &Hook, bytes: &[u8]) {
+        let HookData {
+            heap,
+            symbol_address,
+            ..
+        } = &hook.data;
+        unsafe {
+            write_patch(
+                &heap.mem,
+                *symbol_address,
+                bytes,
+                *symbol_address,
+                PatchOptions::new(),
+                |_| None,
+            )
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn overwritten_hooks_are_detected_and_restored() {
+        let guarded_fn: extern "C" fn() -> i32 = std::hint::black_box(guarded);
+        let mut hook =
+            unsafe { Hook::create(guarded as *mut u8, hooked_guarded as *mut u8).unwrap() };
+        let original = hook.data.original_instructions.clone();
+        let foreign = vec![0x90; original.len()];
+
+        unsafe {
+            assert_eq!(hook.verify(), HookIntegrity::Removed);
+
+            scribble(&hook, &foreign);
+            assert!(matches!(
+                hook.apply_hook(),
+                Err(HookingError::TargetModified(_))
+            ));
+            scribble(&hook, &original);
+
+            hook.apply_hook().unwrap();
+            assert_eq!(hook.verify(), HookIntegrity::Intact);
+
+            scribble(&hook, &foreign);
+            assert_eq!(hook.verify(), HookIntegrity::Overwritten(foreign.clone()));
+            assert_eq!(
+                hook.reapply().unwrap(),
+                HookIntegrity::Overwritten(foreign.clone())
+            );
+            assert_eq!(hook.verify(), HookIntegrity::Intact);
+            assert_eq!(guarded_fn(), 6);
+
+            hook.remove_hook().unwrap();
+            assert_eq!(hook.verify(), HookIntegrity::Removed);
+            assert_eq!(guarded_fn(), 5);
+            hook.free().unwrap();
+        }
+    }
+
+    #[inline(never)]
+    extern "C" fn unexported() -> i32 {
+        std::hint::black_box(8)
+    }
+
+    extern "C" fn hooked_unexported() -> i32 {
+        9
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn hooks_by_offset_check_the_expected_bytes() {
+        let unexported_fn: extern "C" fn() -> i32 = std::hint::black_box(unexported);
+        let address = unexported as *const () as usize;
+        let mut info = std::mem::MaybeUninit::<libc::Dl_info>::zeroed();
+        let base = unsafe {
+            assert_ne!(libc::dladdr(address as *const _, info.as_mut_ptr()), 0);
+            info.assume_init().dli_fbase as usize
+        };
+        let offset = ModuleOffset::Rva(address - base);
+        let prologue = unsafe { core::slice::from_raw_parts(address as *const u8, 4) }.to_vec();
+        let destination = hooked_unexported as *mut u8;
+
+        unsafe {
+            let wrong = [0xCC; 4];
+            assert!(matches!(
+                Hook::by_offset(None, offset, Some(&wrong), destination),
+                Err(HookingError::UnexpectedInstructions { found, .. }) if found == prologue
+            ));
+
+            let mut hook = Hook::by_offset(None, offset, Some(&prologue), destination).unwrap();
+            assert_eq!(hook.data.symbol_address.as_ptr() as usize, address);
+            hook.apply_hook().unwrap();
+            assert_eq!(unexported_fn(), 9);
+
+            hook.remove_hook().unwrap();
+            hook.free().unwrap();
+        }
+    }
+
+    #[inline(never)]
+    extern "C" fn held(value: i64) -> i64 {
+        std::hint::black_box(value * 3)
+    }
+
+    #[test]
+    fn freed_hooks_outlive_their_calls() {
+        use std::sync::{Arc, Barrier};
+        type HeldFn = extern "C" fn(i64) -> i64;
+        // Nothing else reclaims from this heap
+        static HEAP: HookHeap<DefaultMemoryController> = HookHeap::new();
+
+        let held_fn: HeldFn = std::hint::black_box(held);
+        let (entered, release) = (Arc::new(Barrier::new(2)), Arc::new(Barrier::new(2)));
+        let destination = Box::new({
+            let (entered, release) = (entered.clone(), release.clone());
+            move |value| {
+                entered.wait();
+                release.wait();
+                let original = unsafe { crate::typed::current_original::<HeldFn>() };
+                original(value) + 1
+            }
+        });
+        let writer = HookWriter::new(&HEAP, DefaultHookAssembler::new());
+        let mut hook = unsafe {
+            writer
+                .create_closure_hook(held as HeldFn, destination)
+                .unwrap()
+        };
+
+        unsafe { hook.apply_hook().unwrap() };
+        let caller = std::thread::spawn(move || held_fn(5));
+        entered.wait();
+        unsafe { hook.remove_hook().unwrap() };
+        hook.into_inner().free().unwrap();
+        // The call still holds on to the closure and the original
+        assert_eq!(Arc::strong_count(&release), 2);
+
+        release.wait();
+        assert_eq!(caller.join().unwrap(), 16);
+        HEAP.reclaim().unwrap();
+        assert_eq!(Arc::strong_count(&release), 1);
+    }
+}
@@ -0,0 +1,369 @@
+use core::ptr::NonNull;
+use std::ffi::c_void;
+
+use crate::asm::HookAssembler;
+use crate::control::HookContext;
+use crate::error::{HookingError, Result};
+use crate::hooks::{Hook, HookWriter};
+use crate::mem::{DefaultMemoryController, MemoryController};
+use crate::runtime;
+
+#[cfg(not(target_os = "windows"))]
+pub(crate) const INTEGER_REGISTERS: usize = 6;
+#[cfg(target_os = "windows")]
+pub(crate) const INTEGER_REGISTERS: usize = 4;
+
+#[cfg(not(target_os = "windows"))]
+pub(crate) const VECTOR_REGISTERS: usize = 8;
+#[cfg(target_os = "windows")]
+pub(crate) const VECTOR_REGISTERS: usize = 4;
+
+// What the trampoline saved right below the return address of the hooked
+// call, lowest address first
+#[repr(C)]
+struct EntryRegisters {
+    vectors: [[u64; 2]; 8],
+    rax: usize,
+    r9: usize,
+    r8: usize,
+    rcx: usize,
+    rdx: usize,
+    rsi: usize,
+    rdi: usize,
+    return_address: usize,
+}
+
+// Arguments of a hooked call, as they were on entry
+pub struct Arguments {
+    registers: *const EntryRegisters,
+}
+
+impl Arguments {
+    pub(crate) unsafe fn from_return_slot(return_slot: *const usize) -> Self {
+        Self {
+            registers: unsafe { return_slot.add(1).cast::<EntryRegisters>().sub(1) },
+        }
+    }
+
+    fn registers(&self) -> &EntryRegisters {
+        unsafe { &*self.registers }
+    }
+
+    /// Integer or pointer argument `index`, counting only integer arguments on
+    /// linux and every argument on windows like their calling conventions do.
+    /// Arguments past the registers are read from the stack.
+    ///
+    /// # Safety
+    /// The hooked function has to take that many arguments.
+    pub unsafe fn integer(&self, index: usize) -> usize {
+        let registers = self.registers();
+        #[cfg(not(target_os = "windows"))]
+        let in_registers = [
+            registers.rdi,
+            registers.rsi,
+            registers.rdx,
+            registers.rcx,
+            registers.r8,
+            registers.r9,
+        ];
+        #[cfg(target_os = "windows")]
+        let in_registers = [registers.rcx, registers.rdx, registers.r8, registers.r9];
+
+        if let Some(value) = in_registers.get(index) {
+            return *value;
+        }
+        // Windows reserves stack space for the register arguments too
+        #[cfg(not(target_os = "windows"))]
+        let slot = index - INTEGER_REGISTERS;
+        #[cfg(target_os = "windows")]
+        let slot = index;
+
+        unsafe { self.stack_slot(slot) }
+    }
+
+    /// 8 byte slot `index` of the arguments passed on the stack.
+    ///
+    /// # Safety
+    /// The hooked function has to take that many stack arguments.
+    pub unsafe fn stack_slot(&self, index: usize) -> usize {
+        unsafe { self.stack().add(index).read() }
+    }
+
+    // Floating point argument `index`, counted like `integer`. Panics for
+    // arguments that aren't passed in a register.
+    pub fn float(&self, index: usize) -> f64 {
+        assert!(
+            index < VECTOR_REGISTERS,
+            "argument {index} isn't passed in a register"
+        );
+        f64::from_bits(self.registers().vectors[index][0])
+    }
+
+    pub fn return_address(&self) -> usize {
+        self.registers().return_address
+    }
+
+    // First stack argument of the hooked call
+    fn stack(&self) -> *const usize {
+        unsafe { (&raw const (*self.registers).return_address).add(1) }
+    }
+}
+
+// Return registers of a hooked call, saved by the exit stub
+#[repr(C)]
+pub struct ReturnValue {
+    xmm0: [u64; 2],
+    xmm1: [u64; 2],
+    _padding: usize,
+    rdx: usize,
+    rax: usize,
+}
+
+impl ReturnValue {
+    pub fn integer(&self) -> usize {
+        self.rax
+    }
+
+    pub fn float(&self) -> f64 {
+        f64::from_bits(self.xmm0[0])
+    }
+}
+
+pub type EnterFn = dyn Fn(&Arguments) + Send + Sync;
+pub type ExitFn = dyn Fn(&ReturnValue) + Send + Sync;
+
+// Context of an instrumented hook. Calls into other hooks from the callbacks
+// are not instrumented.
+pub(crate) struct Probes {
+    pub on_enter: Box<EnterFn>,
+    pub on_exit: Box<ExitFn>,
+}
+
+impl Hook<'static, DefaultMemoryController> {
+    // Calls `on_enter` before and `on_exit` after every call of `target`,
+    // which runs unchanged in between. A panicking probe doesn't stop the
+    // call. Like latency measurement this replaces the return address, see
+    // `StatsOptions::measure_latency`.
+    pub unsafe fn instrument(
+        target: *mut u8,
+        on_enter: impl Fn(&Arguments) + Send + Sync + 'static,
+        on_exit: impl Fn(&ReturnValue) + Send + Sync + 'static,
+    ) -> Result<Self> {
+        let hook_writer = HookWriter::from_static();
+        unsafe {
+            hook_writer.create_instrumented_hook(
+                NonNull::new(target as *mut _)
+                    .ok_or(HookingError::InvalidTarget(target as *const _))?,
+                Box::new(on_enter),
+                Box::new(on_exit),
+            )
+        }
+    }
+}
+
+impl<'a, M: MemoryController, A: HookAssembler> HookWriter<'a, M, A> {
+    pub unsafe fn create_instrumented_hook(
+        &self,
+        target: NonNull<c_void>,
+        on_enter: Box<EnterFn>,
+        on_exit: Box<ExitFn>,
+    ) -> Result<Hook<'a, M>> {
+        // The trampoline leaves the original in r10, so the destination only
+        // has to jump there
+        let destination = runtime::jump_to_original as *const () as *mut c_void;
+
+        unsafe {
+            let symbol_info = self.hook_heap.mem.get_symbol_info(target);
+            self.create_registered_hook(
+                target,
+                NonNull::new_unchecked(destination),
+                symbol_info,
+                Some(HookContext::new(Probes { on_enter, on_exit })),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::arch::naked_asm;
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    type WeighFn = extern "C" fn(i64, f64, i64, i64, i64, i64, i64, i64, i64) -> f64;
+
+    #[inline(never)]
+    #[allow(clippy::too_many_arguments)]
+    extern "C" fn weigh(
+        count: i64,
+        weight: f64,
+        a: i64,
+        b: i64,
+        c: i64,
+        d: i64,
+        e: i64,
+        f: i64,
+        g: i64,
+    ) -> f64 {
+        std::hint::black_box(count as f64 * weight + (a + b + c + d + e + f + g) as f64)
+    }
+
+    #[test]
+    fn instrumented_hooks_see_arguments_and_results() {
+        static CALLS: Mutex<Vec<(usize, f64, usize)>> = Mutex::new(Vec::new());
+        static RESULTS: Mutex<Vec<f64>> = Mutex::new(Vec::new());
+
+        // `g` is on the stack, the weight is the first float argument on
+        // linux and the second argument on windows
+        let (weight, last) = if cfg!(windows) { (1, 8) } else { (0, 7) };
+
+        let on_enter = move |arguments: &Arguments| {
+            let count = unsafe { arguments.integer(0) };
+            let last = unsafe { arguments.integer(last) };
+            CALLS
+                .lock()
+                .unwrap()
+                .push((count, arguments.float(weight), last));
+        };
+        let on_exit = |value: &ReturnValue| RESULTS.lock().unwrap().push(value.float());
+
+        let weigh_fn: WeighFn = std::hint::black_box(weigh);
+        let mut hook = unsafe { Hook::instrument(weigh as *mut u8, on_enter, on_exit).unwrap() };
+
+        unsafe {
+            hook.apply_hook().unwrap();
+            assert_eq!(weigh_fn(3, 1.5, 1, 1, 1, 1, 1, 1, 9), 19.5);
+            hook.disable();
+            assert_eq!(weigh_fn(2, 0.5, 0, 0, 0, 0, 0, 0, 0), 1.0);
+            hook.remove_hook().unwrap();
+        }
+
+        assert_eq!(*CALLS.lock().unwrap(), [(3, 1.5, 9)]);
+        assert_eq!(*RESULTS.lock().unwrap(), [19.5]);
+    }
+
+    // Sums up to `value` by recursing through the hook
+    #[inline(never)]
+    extern "C" fn triangle(value: u64) -> u64 {
+        let triangle_fn: extern "C" fn(u64) -> u64 = std::hint::black_box(triangle);
+        if value == 0 {
+            0
+        } else {
+            std::hint::black_box(triangle_fn(value - 1) + value)
+        }
+    }
+
+    #[test]
+    fn deep_recursion_skips_the_probes() {
+        static ENTERS: AtomicUsize = AtomicUsize::new(0);
+        static EXITS: AtomicUsize = AtomicUsize::new(0);
+
+        let on_enter = |_: &Arguments| {
+            ENTERS.fetch_add(1, Ordering::Relaxed);
+        };
+        let on_exit = |_: &ReturnValue| {
+            EXITS.fetch_add(1, Ordering::Relaxed);
+        };
+        let triangle_fn: extern "C" fn(u64) -> u64 = std::hint::black_box(triangle);
+        let mut hook = unsafe { Hook::instrument(triangle as *mut u8, on_enter, on_exit).unwrap() };
+
+        unsafe {
+            hook.apply_hook().unwrap();
+            assert_eq!(triangle_fn(400), 80200);
+            hook.remove_hook().unwrap();
+        }
+        assert_eq!(ENTERS.load(Ordering::Relaxed), 256);
+        assert_eq!(EXITS.load(Ordering::Relaxed), 256);
+    }
+
+    #[inline(never)]
+    extern "C" fn halve(value: i64) -> i64 {
+        std::hint::black_box(value / 2)
+    }
+
+    #[test]
+    fn panicking_probes_leave_the_call_alone() {
+        static EXITS: AtomicUsize = AtomicUsize::new(0);
+
+        let on_enter = |arguments: &Arguments| {
+            if unsafe { arguments.integer(0) } == 13 {
+                panic!("probe panicked");
+            }
+        };
+        let on_exit = |_: &ReturnValue| {
+            EXITS.fetch_add(1, Ordering::Relaxed);
+        };
+
+        let halve_fn: extern "C" fn(i64) -> i64 = std::hint::black_box(halve);
+        let mut hook = unsafe { Hook::instrument(halve as *mut u8, on_enter, on_exit).unwrap() };
+
+        unsafe {
+            hook.apply_hook().unwrap();
+            assert_eq!(halve_fn(13), 6);
+            // Still probed, the panic didn't leave the probe flag set
+            assert_eq!(halve_fn(8), 4);
+            hook.remove_hook().unwrap();
+        }
+        assert_eq!(EXITS.load(Ordering::Relaxed), 2);
+    }
+
+    // Returns ymm1 in ymm0
+    #[cfg(not(target_os = "windows"))]
+    #[unsafe(naked)]
+    extern "C" fn copy_ymm1() {
+        naked_asm!("vmovapd ymm0, ymm1", "nop", "ret")
+    }
+
+    // Calls `target` with ymm1 set to all ones, returns the upper half of
+    // ymm0 after it returned
+    #[cfg(not(target_os = "windows"))]
+    #[unsafe(naked)]
+    extern "C" fn ymm0_upper_after(target: extern "C" fn()) -> u64 {
+        naked_asm!(
+            "sub rsp, 8",
+            "vcmptruepd ymm1, ymm1, ymm1",
+            "call rdi",
+            "vextractf128 xmm0, ymm0, 1",
+            "vmovq rax, xmm0",
+            "vzeroupper",
+            "add rsp, 8",
+            "ret",
+        )
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn extended_state_survives_probes() {
+        if !std::arch::is_x86_feature_detected!("avx") {
+            return;
+        }
+        let clobber = || unsafe {
+            core::arch::asm!(
+                "vxorps ymm0, ymm0, ymm0",
+                "vxorps ymm1, ymm1, ymm1",
+                out("xmm0") _,
+                out("xmm1") _,
+            );
+        };
+
+        for (save, expected) in [(false, 0), (true, u64::MAX)] {
+            let writer = HookWriter::from_static().with_extended_state(save);
+            let mut hook = unsafe {
+                writer
+                    .create_instrumented_hook(
+                        NonNull::new_unchecked(copy_ymm1 as *const () as *mut _),
+                        Box::new(move |_| clobber()),
+                        Box::new(move |_| clobber()),
+                    )
+                    .unwrap()
+            };
+
+            unsafe {
+                hook.apply_hook().unwrap();
+                assert_eq!(ymm0_upper_after(copy_ymm1), expected);
+                hook.remove_hook().unwrap();
+            }
+        }
+    }
+}
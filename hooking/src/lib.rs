@@ -1,11 +1,56 @@
+extern crate self as hooking;
+
 pub mod asm;
+pub mod control;
+pub mod deferred;
 pub mod error;
+pub mod guard;
 pub mod hooks;
+pub mod instrument;
 pub mod mem;
+pub mod mid;
+pub mod patch;
+pub mod registry;
+mod runtime;
+pub mod scan;
+pub mod stub;
+pub mod threads;
+pub mod trace;
+pub mod transaction;
+pub mod typed;
+pub mod watchdog;
 
+pub use control::{HookStats, OriginalPointerMode, StatsOptions};
+pub use deferred::DeferredHook;
+pub use guard::{HookGuard, UnhookFailurePolicy};
+pub use hooking_macros::{hook, hook_extern};
 pub use hooks::{Hook, HookData, HookWriter};
+pub use patch::PatchOptions;
+pub use registry::{HookInfo, HookIntegrity};
+pub use scan::Pattern;
+pub use stub::StubReturn;
+pub use trace::Tracer;
+pub use transaction::HookTransaction;
+pub use typed::{HookFn, StaticHook, StaticHookGroup, TypedHook};
+pub use watchdog::HookWatchdog;
 
+// The function the current hook destination should continue to, the original
+// function or the next hook chained on the same target. Works anywhere in the
+// destination of hooks using `OriginalPointerMode::Tracked`, the default.
+// Panics outside of one.
 pub fn original_function_ptr() -> core::ptr::NonNull<core::ffi::c_void> {
+    try_original_function_ptr().expect("original_function_ptr called outside of a hook")
+}
+
+pub fn try_original_function_ptr() -> Option<core::ptr::NonNull<core::ffi::c_void>> {
+    runtime::current_original()
+}
+
+// Reads the original function from r10, where every trampoline leaves it. Only
+// valid as the first thing in the destination, before anything can overwrite
+// the register.
+#[inline(always)]
+pub fn register_original_function_ptr() -> core::ptr::NonNull<core::ffi::c_void> {
     let mut orig_addr: *mut core::ffi::c_void = core::ptr::null_mut();
     unsafe {
         core::arch::asm!(
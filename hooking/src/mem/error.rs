@@ -16,6 +16,9 @@ pub enum MemoryError {
     #[error("Failed to allocate page memory")]
     CantAllocate,
 
+    #[error("Failed to free page memory. Address: {0:?}")]
+    CantFree(*const std::ffi::c_void),
+
     #[error("Failed to set memroy protection. Address: {0:x}")]
     CantSetMemoryProtection(usize),
 
@@ -28,6 +31,9 @@ pub enum MemoryError {
     #[error("Cant find symbol with name {0}")]
     CantFindSymbol(String),
 
+    #[error("Offset {0:#x} is outside of the module")]
+    OffsetOutsideModule(usize),
+
     #[error("Address is not usable for this situation")]
     BadAdress(*const std::ffi::c_void),
 }
@@ -1,10 +1,17 @@
 use std::ffi::c_void;
 use std::mem::MaybeUninit;
+use std::ops::Range;
 use std::ptr::NonNull;
 
 use super::super::*;
 use libc;
 
+// Lowest address mmap will hand out by default (vm.mmap_min_addr).
+const MIN_MAP_ADDRESS: usize = 0x10000;
+
+// Top of the canonical 47bit user address space.
+const MAX_MAP_ADDRESS: usize = 0x7FFF_FFFF_F000;
+
 #[derive(Debug, Clone, Copy)]
 #[repr(transparent)]
 pub struct WindowsMemoryHandle(pub NonNull<c_void>);
@@ -49,26 +56,58 @@ impl LinuxMemoryController {
         unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
     }
 
-    #[cfg(not(feature = "win_close_alloc"))]
     unsafe fn allocate_system_memory(
         &self,
-        _page_size: usize,
+        page_size: usize,
         size: usize,
+        near: Option<NonNull<c_void>>,
     ) -> Result<WindowsMemoryHandle> {
+        // Anonymous mappings usually end up close to the shared libraries,
+        // but not to the main executable or anything loaded later. Try to
+        // map the memory within rel32 range of the target first and only
+        // fall back to "anywhere" if there is no free gap close enough.
+        if let Some(near) = near {
+            let near = near.as_ptr() as usize;
+            let regions = read_mapped_regions().unwrap_or_default();
+
+            for address in near_allocation_candidates(&regions, near, size, page_size) {
+                if let Some(handle) =
+                    unsafe { self.map_memory(address, size, libc::MAP_FIXED_NOREPLACE) }
+                {
+                    if handle.0.as_ptr() as usize == address {
+                        return Ok(handle);
+                    }
+                    // Kernels older than 4.17 ignore MAP_FIXED_NOREPLACE
+                    // and treat the address as a hint.
+                    unsafe { libc::munmap(handle.0.as_ptr(), size) };
+                }
+            }
+        }
+
+        unsafe { self.map_memory(0, size, 0) }.ok_or(MemoryError::CantAllocate)
+    }
+
+    unsafe fn map_memory(
+        &self,
+        address: usize,
+        size: usize,
+        flags: i32,
+    ) -> Option<WindowsMemoryHandle> {
         let handle = unsafe {
             libc::mmap(
-                std::ptr::null_mut(),
+                address as *mut c_void,
                 size,
                 libc::PROT_READ | libc::PROT_WRITE,
-                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | flags,
                 -1,
                 0,
             )
         };
 
-        NonNull::new(handle)
-            .map(WindowsMemoryHandle)
-            .ok_or(MemoryError::CantAllocate)
+        if handle == libc::MAP_FAILED {
+            return None;
+        }
+        NonNull::new(handle).map(WindowsMemoryHandle)
     }
 
     fn allign_up(&self, page_size: usize, address: usize) -> usize {
@@ -84,6 +123,9 @@ impl LinuxMemoryController {
             MemoryProtection::NoAccess => 0,
             MemoryProtection::ReadWrite => libc::PROT_READ | libc::PROT_WRITE,
             MemoryProtection::ReadExecute => libc::PROT_READ | libc::PROT_EXEC,
+            MemoryProtection::ReadWriteExecute => {
+                libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC
+            }
             MemoryProtection::Other(proc) => proc as i32,
         }
     }
@@ -108,7 +150,11 @@ impl MemoryController for LinuxMemoryController {
 
     type AllocationInfoType = LinuxMemoryAllocationInfo;
 
-    unsafe fn allocate_memory(&self, min_size: Option<usize>) -> Result<Self::AllocationInfoType> {
+    unsafe fn allocate_memory(
+        &self,
+        min_size: Option<usize>,
+        near: Option<NonNull<c_void>>,
+    ) -> Result<Self::AllocationInfoType> {
         let page_size = unsafe { self.sys_get_page_size() } as usize;
 
         let allocation_size = if let Some(min_size) = min_size {
@@ -117,7 +163,8 @@ impl MemoryController for LinuxMemoryController {
             page_size
         };
 
-        let allocation_start = unsafe { self.allocate_system_memory(page_size, allocation_size)? };
+        let allocation_start =
+            unsafe { self.allocate_system_memory(page_size, allocation_size, near)? };
 
         Ok(Self::AllocationInfoType {
             page_size,
@@ -125,6 +172,22 @@ impl MemoryController for LinuxMemoryController {
             allocation_start,
         })
     }
+    unsafe fn free_memory(&self, allocation: &Self::AllocationInfoType) -> Result<()> {
+        let success = unsafe {
+            libc::munmap(
+                allocation.allocation_start.as_ptr().as_ptr(),
+                allocation.allocation_size,
+            ) == 0
+        };
+        if !success {
+            Err(MemoryError::CantFree(
+                allocation.allocation_start.as_ptr().as_ptr(),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
     unsafe fn set_page_protection(
         &self,
         handle: Self::Handle,
@@ -145,8 +208,10 @@ impl MemoryController for LinuxMemoryController {
         module: Option<&CStr>,
         symbol: &CStr,
     ) -> Result<NonNull<c_void>> {
+        // RTLD_NOLOAD only returns modules that are already loaded
         let module_handle = if let Some(module) = module {
-            let module_addr = unsafe { libc::dlopen(module.as_ptr(), libc::RTLD_LAZY) };
+            let module_addr =
+                unsafe { libc::dlopen(module.as_ptr(), libc::RTLD_LAZY | libc::RTLD_NOLOAD) };
             if module_addr.is_null() {
                 return Err(MemoryError::CantFindModule(
                     module.to_str().unwrap_or("<invalid-module-name>").into(),
@@ -159,6 +224,9 @@ impl MemoryController for LinuxMemoryController {
 
         let proc_address = {
             let proc_address = unsafe { libc::dlsym(module_handle, symbol.as_ptr()) };
+            if module.is_some() {
+                unsafe { libc::dlclose(module_handle) };
+            }
 
             NonNull::new(proc_address).ok_or_else(|| {
                 MemoryError::CantFindModule(
@@ -170,6 +238,108 @@ impl MemoryController for LinuxMemoryController {
         Ok(proc_address)
     }
 
+    unsafe fn get_module_address(
+        &self,
+        module: Option<&CStr>,
+        offset: ModuleOffset,
+    ) -> Result<NonNull<c_void>> {
+        let address = with_module(module, |base, headers| match offset {
+            ModuleOffset::Rva(rva) => loaded_segments(headers)
+                .any(|header| {
+                    (header.p_vaddr as usize..(header.p_vaddr + header.p_memsz) as usize)
+                        .contains(&rva)
+                })
+                .then(|| base + rva),
+            ModuleOffset::File(offset) => loaded_segments(headers)
+                .find(|header| {
+                    (header.p_offset as usize..(header.p_offset + header.p_filesz) as usize)
+                        .contains(&offset)
+                })
+                .map(|header| base + header.p_vaddr as usize + offset - header.p_offset as usize),
+        })?;
+
+        let offset = match offset {
+            ModuleOffset::Rva(offset) | ModuleOffset::File(offset) => offset,
+        };
+        address
+            .and_then(|address| NonNull::new(address as *mut c_void))
+            .ok_or(MemoryError::OffsetOutsideModule(offset))
+    }
+
+    fn read_memory(&self, address: usize, buffer: &mut [u8]) -> usize {
+        // The read stops at the first iovec that can't be read as a whole, so
+        // it is split at every page
+        let page_size = unsafe { self.sys_get_page_size() };
+        // Nothing past the end of the address space is readable anyway
+        let end = address.saturating_add(buffer.len());
+        let mut remote = Vec::new();
+        let mut start = address;
+        while start < end {
+            let len = (page_size - start % page_size).min(end - start);
+            remote.push(libc::iovec {
+                iov_base: start as *mut c_void,
+                iov_len: len,
+            });
+            start += len;
+        }
+        let local = libc::iovec {
+            iov_base: buffer.as_mut_ptr().cast(),
+            iov_len: buffer.len(),
+        };
+
+        let read = unsafe {
+            libc::process_vm_readv(
+                libc::getpid(),
+                &local,
+                1,
+                remote.as_ptr(),
+                remote.len() as libc::c_ulong,
+                0,
+            )
+        };
+        read.max(0) as usize
+    }
+
+    unsafe fn get_module_code_regions(&self, module: Option<&CStr>) -> Result<Vec<Range<usize>>> {
+        with_module(module, |base, headers| {
+            loaded_segments(headers)
+                .filter(|header| header.p_flags & libc::PF_X != 0)
+                .map(|header| {
+                    let start = base + header.p_vaddr as usize;
+                    start..start + header.p_filesz as usize
+                })
+                .collect()
+        })
+    }
+
+    unsafe fn get_symbol_info(&self, address: NonNull<c_void>) -> SymbolInfo {
+        let mut info = MaybeUninit::<libc::Dl_info>::zeroed();
+        if unsafe { libc::dladdr(address.as_ptr(), info.as_mut_ptr()) } == 0 {
+            return SymbolInfo::default();
+        }
+        let info = unsafe { info.assume_init() };
+
+        let to_string = |name: *const libc::c_char| {
+            (!name.is_null()).then(|| {
+                unsafe { CStr::from_ptr(name) }
+                    .to_string_lossy()
+                    .into_owned()
+            })
+        };
+
+        let symbol = to_string(info.dli_sname).map(|symbol| {
+            match address.as_ptr() as usize - info.dli_saddr as usize {
+                0 => symbol,
+                offset => format!("{symbol}+{offset:#x}"),
+            }
+        });
+
+        SymbolInfo {
+            module: to_string(info.dli_fname),
+            symbol,
+        }
+    }
+
     fn protection_guard_for_page<'a>(
         &'a self,
         ptr: NonNull<c_void>,
@@ -199,3 +369,201 @@ impl MemoryController for LinuxMemoryController {
         )
     }
 }
+
+fn read_mapped_regions() -> Option<Vec<(usize, usize)>> {
+    let maps = std::fs::read_to_string("/proc/self/maps").ok()?;
+
+    let mut regions = maps
+        .lines()
+        .filter_map(|line| {
+            let range = line.split_whitespace().next()?;
+            let (start, end) = range.split_once('-')?;
+            Some((
+                usize::from_str_radix(start, 16).ok()?,
+                usize::from_str_radix(end, 16).ok()?,
+            ))
+        })
+        .collect::<Vec<_>>();
+
+    regions.sort_unstable();
+    Some(regions)
+}
+
+fn near_allocation_candidates(
+    regions: &[(usize, usize)],
+    near: usize,
+    size: usize,
+    page_size: usize,
+) -> Vec<usize> {
+    let gap_starts = std::iter::once(MIN_MAP_ADDRESS).chain(regions.iter().map(|(_, end)| *end));
+    let gap_ends = regions
+        .iter()
+        .map(|(start, _)| *start)
+        .chain(std::iter::once(MAX_MAP_ADDRESS));
+
+    let mut candidates = gap_starts
+        .zip(gap_ends)
+        .filter_map(|(gap_start, gap_end)| {
+            let gap_start = (gap_start + page_size - 1) & !(page_size - 1);
+            let gap_end = gap_end & !(page_size - 1);
+            if gap_end < gap_start + size {
+                return None;
+            }
+
+            // Pick the spot in the gap that is closest to the target
+            let address = if gap_end <= near {
+                gap_end - size
+            } else if gap_start >= near {
+                gap_start
+            } else {
+                (near & !(page_size - 1)).min(gap_end - size)
+            };
+
+            let distance = near.abs_diff(address).max(near.abs_diff(address + size));
+            (distance <= NEAR_JUMP_RANGE).then_some((distance, address))
+        })
+        .collect::<Vec<_>>();
+
+    candidates.sort_unstable();
+    candidates.into_iter().map(|(_, address)| address).collect()
+}
+
+struct ModuleSearch<'a> {
+    module: Option<&'a CStr>,
+    visit: &'a mut dyn FnMut(usize, &[libc::Elf64_Phdr]),
+}
+
+// Matches a module by its path or file name, the first one reported is the
+// main executable
+pub(crate) fn is_module(name: &CStr, module: Option<&CStr>) -> bool {
+    let Some(module) = module else {
+        return true;
+    };
+    let name = name.to_bytes();
+    let file_name = name.rsplit(|byte| *byte == b'/').next().unwrap_or(name);
+    name == module.to_bytes() || file_name == module.to_bytes()
+}
+
+unsafe extern "C" fn visit_module(
+    info: *mut libc::dl_phdr_info,
+    _size: usize,
+    data: *mut c_void,
+) -> libc::c_int {
+    let (info, search) = unsafe { (&*info, &mut *(data as *mut ModuleSearch)) };
+    let name = if info.dlpi_name.is_null() {
+        c""
+    } else {
+        unsafe { CStr::from_ptr(info.dlpi_name) }
+    };
+    if !is_module(name, search.module) {
+        return 0;
+    }
+
+    let headers = unsafe { std::slice::from_raw_parts(info.dlpi_phdr, info.dlpi_phnum as usize) };
+    (search.visit)(info.dlpi_addr as usize, headers);
+    1
+}
+
+// Calls `visit` with the load base and program headers of a loaded module
+fn with_module<T>(
+    module: Option<&CStr>,
+    visit: impl FnOnce(usize, &[libc::Elf64_Phdr]) -> T,
+) -> Result<T> {
+    let mut visit = Some(visit);
+    let mut result = None;
+    let mut search = ModuleSearch {
+        module,
+        visit: &mut |base, headers| {
+            if let Some(visit) = visit.take() {
+                result = Some(visit(base, headers));
+            }
+        },
+    };
+    unsafe { libc::dl_iterate_phdr(Some(visit_module), &mut search as *mut _ as *mut c_void) };
+
+    result.ok_or_else(|| {
+        MemoryError::CantFindModule(
+            module
+                .map(|module| module.to_str().unwrap_or("<invalid-module-name>"))
+                .unwrap_or("<main-module>")
+                .into(),
+        )
+    })
+}
+
+fn loaded_segments(headers: &[libc::Elf64_Phdr]) -> impl Iterator<Item = &libc::Elf64_Phdr> {
+    headers
+        .iter()
+        .filter(|header| header.p_type == libc::PT_LOAD)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: usize = 0x1000;
+
+    #[test]
+    fn near_candidates_are_sorted_by_distance() {
+        let regions = [
+            (0x7f00_0000_0000, 0x7f00_0001_0000),
+            (0x7f00_0002_0000, 0x7f00_0003_0000),
+            (0x7f00_0010_0000, 0x7f00_0020_0000),
+        ];
+        let near = 0x7f00_0002_8000;
+
+        let candidates = near_allocation_candidates(&regions, near, PAGE, PAGE);
+        assert_eq!(
+            &candidates[..3],
+            &[0x7f00_0001_f000, 0x7f00_0003_0000, 0x7f00_0000_0000 - PAGE]
+        );
+    }
+
+    #[test]
+    fn near_candidates_stay_in_rel32_range() {
+        let regions = [
+            (0x1000_0000, 0x1000_1000),
+            (0x7f00_0000_0000, 0x7f00_0001_0000),
+        ];
+        let near = 0x1000_0800;
+
+        let candidates = near_allocation_candidates(&regions, near, PAGE, PAGE);
+        assert!(!candidates.is_empty());
+        assert!(
+            candidates
+                .iter()
+                .all(|address| near.abs_diff(*address) <= NEAR_JUMP_RANGE)
+        );
+    }
+
+    #[test]
+    fn near_candidates_skip_small_gaps() {
+        let regions = [(0x10_0000, 0x10_1000), (0x10_2000, 0x10_3000)];
+        let near = 0x10_0800;
+
+        let candidates = near_allocation_candidates(&regions, near, 2 * PAGE, PAGE);
+        assert!(!candidates.contains(&0x10_1000));
+    }
+
+    #[test]
+    fn module_offsets_resolve_to_symbols() {
+        let mem = LinuxMemoryController::new();
+        let puts = unsafe { mem.get_symbol_address(Some(c"libc.so.6"), c"puts").unwrap() };
+
+        let mut info = MaybeUninit::<libc::Dl_info>::zeroed();
+        assert_ne!(unsafe { libc::dladdr(puts.as_ptr(), info.as_mut_ptr()) }, 0);
+        let rva = puts.as_ptr() as usize - unsafe { info.assume_init() }.dli_fbase as usize;
+
+        let by_rva = unsafe { mem.get_module_address(Some(c"libc.so.6"), ModuleOffset::Rva(rva)) };
+        assert_eq!(by_rva.unwrap(), puts);
+        assert!(
+            unsafe {
+                mem.get_module_address(Some(c"libc.so.6"), ModuleOffset::Rva(usize::MAX / 2))
+            }
+            .is_err()
+        );
+        assert!(
+            unsafe { mem.get_module_address(Some(c"missing.so"), ModuleOffset::Rva(0)) }.is_err()
+        );
+    }
+}
@@ -1,16 +1,26 @@
 use std::ffi::c_void;
 use std::mem::MaybeUninit;
+use std::ops::Range;
 use std::ptr::NonNull;
 
 use super::super::*;
 
-use windows_sys::Win32::System::LibraryLoader::{GetModuleHandleA, GetProcAddress};
+use windows_sys::Win32::System::LibraryLoader::{
+    GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS, GET_MODULE_HANDLE_EX_FLAG_UNCHANGED_REFCOUNT,
+    GetModuleFileNameA, GetModuleHandleA, GetModuleHandleExA, GetProcAddress,
+};
 use windows_sys::Win32::System::Memory::{
-    MEM_COMMIT, MEM_RESERVE, MEMORY_BASIC_INFORMATION, PAGE_EXECUTE_READ, PAGE_READWRITE,
-    VirtualAlloc, VirtualProtect, VirtualQuery,
+    MEM_COMMIT, MEM_RELEASE, MEM_RESERVE, MEMORY_BASIC_INFORMATION, PAGE_EXECUTE_READ,
+    PAGE_EXECUTE_READWRITE, PAGE_READWRITE, VirtualAlloc, VirtualFree, VirtualProtect,
+    VirtualQuery,
 };
 
+use windows_sys::Win32::System::Diagnostics::Debug::ReadProcessMemory;
 use windows_sys::Win32::System::SystemInformation::{GetSystemInfo, SYSTEM_INFO};
+use windows_sys::Win32::System::Threading::GetCurrentProcess;
+
+// Characteristics flag of sections that hold code
+const IMAGE_SCN_MEM_EXECUTE: usize = 0x2000_0000;
 
 #[derive(Debug, Clone, Copy)]
 #[repr(transparent)]
@@ -65,13 +75,17 @@ impl WindowsMemoryController {
         &self,
         page_size: usize,
         size: usize,
+        near: Option<NonNull<c_void>>,
     ) -> Result<WindowsMemoryHandle> {
         // Windows 64bit will allcoate memory very far away
         // from where modules are loaded by default.
         // So try and load some memory that is a bit closer
         let mut allocation_address = {
             fn dummy() {}
-            self.allign_up(page_size, dummy as *const c_void as usize) + page_size
+            let near = near
+                .map(|near| near.as_ptr() as usize)
+                .unwrap_or(dummy as *const c_void as usize);
+            self.allign_up(page_size, near) + page_size
         };
         let handle = 'memaddr: {
             for _ in 0..0x1000 {
@@ -101,6 +115,7 @@ impl WindowsMemoryController {
         &self,
         _page_size: usize,
         size: usize,
+        _near: Option<NonNull<c_void>>,
     ) -> Result<WindowsMemoryHandle> {
         let handle = unsafe {
             VirtualAlloc(
@@ -129,6 +144,7 @@ impl WindowsMemoryController {
             MemoryProtection::NoAccess => 0,
             MemoryProtection::ReadWrite => PAGE_READWRITE,
             MemoryProtection::ReadExecute => PAGE_EXECUTE_READ,
+            MemoryProtection::ReadWriteExecute => PAGE_EXECUTE_READWRITE,
             MemoryProtection::Other(proc) => proc as u32,
         }
     }
@@ -179,7 +195,11 @@ impl MemoryController for WindowsMemoryController {
 
     type AllocationInfoType = WindowsMemoryAllocationInfo;
 
-    unsafe fn allocate_memory(&self, min_size: Option<usize>) -> Result<Self::AllocationInfoType> {
+    unsafe fn allocate_memory(
+        &self,
+        min_size: Option<usize>,
+        near: Option<NonNull<c_void>>,
+    ) -> Result<Self::AllocationInfoType> {
         let page_size = unsafe { self.get_system_info().dwPageSize } as usize;
 
         let allocation_size = if let Some(min_size) = min_size {
@@ -188,7 +208,8 @@ impl MemoryController for WindowsMemoryController {
             page_size
         };
 
-        let allocation_start = unsafe { self.allocate_system_memory(page_size, allocation_size)? };
+        let allocation_start =
+            unsafe { self.allocate_system_memory(page_size, allocation_size, near)? };
 
         Ok(Self::AllocationInfoType {
             page_size,
@@ -196,6 +217,23 @@ impl MemoryController for WindowsMemoryController {
             allocation_start,
         })
     }
+    unsafe fn free_memory(&self, allocation: &Self::AllocationInfoType) -> Result<()> {
+        let success = unsafe {
+            VirtualFree(
+                allocation.allocation_start.as_ptr().as_ptr(),
+                0,
+                MEM_RELEASE,
+            ) != 0
+        };
+        if !success {
+            Err(MemoryError::CantFree(
+                allocation.allocation_start.as_ptr().as_ptr(),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
     unsafe fn set_page_protection(
         &self,
         handle: Self::Handle,
@@ -245,6 +283,85 @@ impl MemoryController for WindowsMemoryController {
         Ok(proc_address)
     }
 
+    unsafe fn get_module_address(
+        &self,
+        module: Option<&CStr>,
+        offset: ModuleOffset,
+    ) -> Result<NonNull<c_void>> {
+        let base = unsafe { module_base(module)? };
+        let rva = match offset {
+            ModuleOffset::Rva(rva) if rva < unsafe { image_size(base) } => rva,
+            ModuleOffset::Rva(rva) => return Err(MemoryError::OffsetOutsideModule(rva)),
+            ModuleOffset::File(file_offset) => unsafe { file_offset_to_rva(base, file_offset) }
+                .ok_or(MemoryError::OffsetOutsideModule(file_offset))?,
+        };
+
+        NonNull::new((base + rva) as *mut c_void).ok_or(MemoryError::OffsetOutsideModule(rva))
+    }
+
+    fn read_memory(&self, address: usize, buffer: &mut [u8]) -> usize {
+        // A read fails as a whole if any page of it isn't readable
+        let page_size = unsafe { self.get_system_info() }.dwPageSize as usize;
+        let mut copied = 0;
+        while copied < buffer.len() {
+            let Some(start) = address.checked_add(copied) else {
+                break;
+            };
+            let len = (page_size - start % page_size).min(buffer.len() - copied);
+            let mut read = 0;
+            let success = unsafe {
+                ReadProcessMemory(
+                    GetCurrentProcess(),
+                    start as *const c_void,
+                    buffer[copied..].as_mut_ptr().cast(),
+                    len,
+                    &mut read,
+                )
+            };
+            if success == 0 {
+                break;
+            }
+            copied += read;
+        }
+        copied
+    }
+
+    unsafe fn get_module_code_regions(&self, module: Option<&CStr>) -> Result<Vec<Range<usize>>> {
+        unsafe {
+            let base = module_base(module)?;
+            Ok(sections(base)
+                .filter(|section| read_u32(*section + 36) & IMAGE_SCN_MEM_EXECUTE != 0)
+                .map(|section| {
+                    let start = base + read_u32(section + 12);
+                    start..start + read_u32(section + 8)
+                })
+                .collect())
+        }
+    }
+
+    unsafe fn get_symbol_info(&self, address: NonNull<c_void>) -> SymbolInfo {
+        // Export names would need dbghelp, only the module is resolved
+        let mut module = std::ptr::null_mut();
+        let found = unsafe {
+            GetModuleHandleExA(
+                GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS
+                    | GET_MODULE_HANDLE_EX_FLAG_UNCHANGED_REFCOUNT,
+                address.as_ptr() as *const _,
+                &mut module,
+            )
+        };
+        if found == 0 {
+            return SymbolInfo::default();
+        }
+
+        let mut name = [0u8; 260];
+        let len = unsafe { GetModuleFileNameA(module, name.as_mut_ptr(), name.len() as u32) };
+        SymbolInfo {
+            module: (len != 0).then(|| String::from_utf8_lossy(&name[..len as usize]).into_owned()),
+            symbol: None,
+        }
+    }
+
     fn protection_guard_for_page<'a>(
         &'a self,
         ptr: NonNull<c_void>,
@@ -275,3 +392,59 @@ impl MemoryController for WindowsMemoryController {
         )
     }
 }
+
+unsafe fn read_u16(address: usize) -> usize {
+    unsafe { (address as *const u16).read_unaligned() as usize }
+}
+
+unsafe fn read_u32(address: usize) -> usize {
+    unsafe { (address as *const u32).read_unaligned() as usize }
+}
+
+unsafe fn module_base(module: Option<&CStr>) -> Result<usize> {
+    let module_name = module.map_or(std::ptr::null(), |module| module.as_ptr() as *const _);
+    let base = unsafe { GetModuleHandleA(module_name) } as usize;
+    if base == 0 {
+        return Err(MemoryError::CantFindModule(
+            module
+                .map(|module| module.to_str().unwrap_or("<invalid-module-name>"))
+                .unwrap_or("<main-module>")
+                .into(),
+        ));
+    }
+    Ok(base)
+}
+
+// SizeOfImage of the optional header, the same for PE32 and PE32+
+unsafe fn image_size(base: usize) -> usize {
+    unsafe {
+        let nt_headers = base + read_u32(base + 0x3C);
+        read_u32(nt_headers + 24 + 56)
+    }
+}
+
+// Addresses of the section headers of the loaded image
+unsafe fn sections(base: usize) -> impl Iterator<Item = usize> {
+    unsafe {
+        let nt_headers = base + read_u32(base + 0x3C);
+        let section_count = read_u16(nt_headers + 6);
+        let optional_header_size = read_u16(nt_headers + 20);
+        let first_section = nt_headers + 24 + optional_header_size;
+
+        (0..section_count).map(move |index| first_section + index * 40)
+    }
+}
+
+// Maps a file offset to an RVA with the section table of the loaded image
+unsafe fn file_offset_to_rva(base: usize, file_offset: usize) -> Option<usize> {
+    unsafe {
+        sections(base).find_map(|section| {
+            let virtual_address = read_u32(section + 12);
+            let raw_size = read_u32(section + 16);
+            let raw_pointer = read_u32(section + 20);
+            (raw_pointer..raw_pointer + raw_size)
+                .contains(&file_offset)
+                .then(|| virtual_address + file_offset - raw_pointer)
+        })
+    }
+}
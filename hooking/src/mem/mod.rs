@@ -19,24 +19,47 @@ pub mod page;
 pub mod table;
 use std::{
     ffi::{CStr, c_void},
+    ops::Range,
     ptr::NonNull,
 };
 
 pub use error::{MemoryError, Result};
-pub use table::{HeapState, HookHeap, MemoryHeapHandle, MemoryWriteHandle};
+pub use table::{HeapRegionStats, HeapState, HookHeap, MemoryHeapHandle, MemoryWriteHandle};
 
 use crate::mem::page::MemoryProtectionGuard;
 
 pub type DefaultMemoryController = inner::MemoryController;
 
+// rel32 jumps reach +-2GB from the end of the instruction. Leave some
+// headroom so every byte of an allocation stays reachable.
+pub const NEAR_JUMP_RANGE: usize = 0x7FF0_0000;
+
 #[derive(Debug, Clone, Copy)]
 pub enum MemoryProtection {
     NoAccess,
     ReadWrite,
     ReadExecute,
+    ReadWriteExecute,
     Other(usize),
 }
 
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymbolInfo {
+    pub module: Option<String>,
+    // Nearest exported symbol, with the offset into it if not at its start
+    pub symbol: Option<String>,
+}
+
+// Location of code inside a loaded module, for functions that aren't exported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModuleOffset {
+    // Relative to the load base, the address a disassembler shows for an
+    // image based at zero
+    Rva(usize),
+    // Offset into the module file on disk
+    File(usize),
+}
+
 pub trait MemoryHandle: Sized {
     fn from_ptr(ptr: NonNull<c_void>) -> Self;
     fn as_ptr(&self) -> NonNull<c_void>;
@@ -51,7 +74,24 @@ pub trait MemoryController {
         module: Option<&CStr>,
         symbol: &CStr,
     ) -> Result<NonNull<c_void>>;
-    unsafe fn allocate_memory(&self, min_size: Option<usize>) -> Result<Self::AllocationInfoType>;
+    unsafe fn get_symbol_info(&self, address: NonNull<c_void>) -> SymbolInfo;
+    // `None` is the main executable
+    unsafe fn get_module_address(
+        &self,
+        module: Option<&CStr>,
+        offset: ModuleOffset,
+    ) -> Result<NonNull<c_void>>;
+    // Address ranges of the executable parts of a module
+    unsafe fn get_module_code_regions(&self, module: Option<&CStr>) -> Result<Vec<Range<usize>>>;
+    // Copies the start of `address..` into `buffer`, up to the first byte that
+    // can't be read. Returns how many bytes were copied.
+    fn read_memory(&self, address: usize, buffer: &mut [u8]) -> usize;
+    unsafe fn allocate_memory(
+        &self,
+        min_size: Option<usize>,
+        near: Option<NonNull<c_void>>,
+    ) -> Result<Self::AllocationInfoType>;
+    unsafe fn free_memory(&self, allocation: &Self::AllocationInfoType) -> Result<()>;
     unsafe fn set_page_protection(
         &self,
         handle: Self::Handle,
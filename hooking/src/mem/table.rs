@@ -1,49 +1,255 @@
 use std::ffi::c_void;
 use std::ptr::NonNull;
-use std::sync::{Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
 
 use crate::mem::page::MemoryProtectionGuard;
-use crate::mem::{AllocationInfo, DefaultMemoryController, MemoryHandle, MemoryProtection};
+use crate::mem::{
+    AllocationInfo, DefaultMemoryController, MemoryHandle, MemoryProtection, NEAR_JUMP_RANGE,
+};
 
 use super::MemoryController;
 use super::{MemoryError, Result};
 
+// Blocks are kept 16 byte aligned so pointer slots can be updated atomically
+// and code starts on a fetch friendly boundary.
+const BLOCK_ALIGNMENT: usize = 16;
+
+// int3, so a thread that still runs through freed memory traps instead of
+// executing whatever is written there next.
+const FREED_MEMORY_FILL: u8 = 0xCC;
+
+fn align_block(size: usize) -> usize {
+    (size + BLOCK_ALIGNMENT - 1) & !(BLOCK_ALIGNMENT - 1)
+}
+
 #[derive(Debug)]
-pub struct HeapState<C: MemoryController> {
-    allocation: Option<C::AllocationInfoType>,
+struct FreeList {
+    size: usize,
     written: usize,
+    // (offset, size), sorted by offset and never adjacent to each other
+    // or to the end of the written area.
+    free_blocks: Vec<(usize, usize)>,
 }
 
-impl<C: MemoryController> HeapState<C> {
-    pub const fn empty() -> Self {
+impl FreeList {
+    const fn new(size: usize) -> Self {
         Self {
-            allocation: None,
+            size,
             written: 0,
+            free_blocks: Vec::new(),
         }
     }
 
-    pub unsafe fn ensure_allocated(&mut self, mem: &C, min_size: Option<usize>) -> Result<()> {
-        if self.allocation.is_none() {
-            self.allocation = Some(unsafe { mem.allocate_memory(min_size)? });
+    fn has_room(&self, size: usize) -> bool {
+        let size = align_block(size);
+        self.size - self.written >= size || self.free_blocks.iter().any(|(_, free)| *free >= size)
+    }
+
+    fn used(&self) -> usize {
+        self.written - self.free_blocks.iter().map(|(_, size)| size).sum::<usize>()
+    }
+
+    fn allocate(&mut self, size: usize) -> Option<usize> {
+        let size = align_block(size);
+
+        if let Some(index) = self.free_blocks.iter().position(|(_, free)| *free >= size) {
+            let (offset, free) = &mut self.free_blocks[index];
+            let allocated = *offset;
+            if *free == size {
+                self.free_blocks.remove(index);
+            } else {
+                *offset += size;
+                *free -= size;
+            }
+            return Some(allocated);
         }
-        Ok(())
+
+        if self.size - self.written < size {
+            return None;
+        }
+        let allocated = self.written;
+        self.written += size;
+        Some(allocated)
     }
 
-    fn allocation(&self) -> Result<&C::AllocationInfoType> {
-        match &self.allocation {
-            Some(allocation) => Ok(allocation),
-            None => Err(MemoryError::TableHeapNotAllocated),
+    fn release(&mut self, offset: usize, size: usize) {
+        let size = align_block(size);
+        let index = self.free_blocks.partition_point(|(free, _)| *free < offset);
+        self.free_blocks.insert(index, (offset, size));
+
+        if let Some((next_offset, next_size)) = self.free_blocks.get(index + 1).copied()
+            && offset + size == next_offset
+        {
+            self.free_blocks[index].1 += next_size;
+            self.free_blocks.remove(index + 1);
+        }
+
+        let index = if index > 0 {
+            let (previous_offset, previous_size) = self.free_blocks[index - 1];
+            if previous_offset + previous_size == offset {
+                self.free_blocks[index - 1].1 += self.free_blocks[index].1;
+                self.free_blocks.remove(index);
+                index - 1
+            } else {
+                index
+            }
+        } else {
+            index
+        };
+
+        let (offset, size) = self.free_blocks[index];
+        if offset + size == self.written {
+            self.written = offset;
+            self.free_blocks.remove(index);
         }
     }
 }
 
+#[derive(Debug)]
+pub struct HeapRegion<C: MemoryController> {
+    allocation: C::AllocationInfoType,
+    blocks: FreeList,
+}
+
+impl<C: MemoryController> HeapRegion<C> {
+    fn new(allocation: C::AllocationInfoType) -> Self {
+        Self {
+            blocks: FreeList::new(allocation.allocation_size()),
+            allocation,
+        }
+    }
+
+    fn start(&self) -> usize {
+        self.allocation.allocation_start().as_ptr().as_ptr() as usize
+    }
+
+    fn size(&self) -> usize {
+        self.allocation.allocation_size()
+    }
+
+    fn contains(&self, ptr: NonNull<c_void>) -> bool {
+        (self.start()..self.start() + self.size()).contains(&(ptr.as_ptr() as usize))
+    }
+
+    fn is_reachable_from(&self, near: NonNull<c_void>) -> bool {
+        let near = near.as_ptr() as usize;
+        near.abs_diff(self.start()) <= NEAR_JUMP_RANGE
+            && near.abs_diff(self.start() + self.size()) <= NEAR_JUMP_RANGE
+    }
+
+    fn stats(&self) -> HeapRegionStats {
+        HeapRegionStats {
+            start: self.allocation.allocation_start().as_ptr(),
+            size: self.size(),
+            used: self.blocks.used(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeapRegionStats {
+    pub start: NonNull<c_void>,
+    pub size: usize,
+    pub used: usize,
+}
+
+impl HeapRegionStats {
+    pub fn free(&self) -> usize {
+        self.size - self.used
+    }
+}
+
+pub struct HeapState<C: MemoryController> {
+    regions: Vec<HeapRegion<C>>,
+}
+
+impl<C: MemoryController> HeapState<C> {
+    pub const fn empty() -> Self {
+        Self {
+            regions: Vec::new(),
+        }
+    }
+
+    pub unsafe fn ensure_allocated(
+        &mut self,
+        mem: &C,
+        min_size: usize,
+        near: Option<NonNull<c_void>>,
+    ) -> Result<usize> {
+        let has_room = |region: &HeapRegion<C>| region.blocks.has_room(min_size);
+
+        if let Some(index) = self
+            .regions
+            .iter()
+            .position(|region| has_room(region) && near.is_none_or(|n| region.is_reachable_from(n)))
+        {
+            return Ok(index);
+        }
+
+        let region = HeapRegion::new(unsafe { mem.allocate_memory(Some(min_size), near)? });
+
+        if let Some(near) = near
+            && !region.is_reachable_from(near)
+        {
+            // The controller could not place the region close to the target,
+            // so there is no point growing the heap for every far away hook.
+            if let Some(index) = self.regions.iter().position(has_room) {
+                unsafe { mem.free_memory(&region.allocation)? };
+                return Ok(index);
+            }
+        }
+
+        self.regions.push(region);
+        Ok(self.regions.len() - 1)
+    }
+
+    pub fn stats(&self) -> Vec<HeapRegionStats> {
+        self.regions.iter().map(HeapRegion::stats).collect()
+    }
+
+    fn region(&self, index: usize) -> Result<&HeapRegion<C>> {
+        self.regions
+            .get(index)
+            .ok_or(MemoryError::TableHeapNotAllocated)
+    }
+
+    fn region_mut(&mut self, index: usize) -> Result<&mut HeapRegion<C>> {
+        self.regions
+            .get_mut(index)
+            .ok_or(MemoryError::TableHeapNotAllocated)
+    }
+}
+
+// A freed block that may still be running. It is released once `idle`
+// returns true, and `idle` is dropped after that.
+struct RetiredBlock {
+    ptr: NonNull<c_void>,
+    size: usize,
+    idle: Box<dyn FnMut() -> bool + Send>,
+}
+
+unsafe impl Send for RetiredBlock {}
+
 pub struct HookHeap<C: MemoryController> {
     pub mem: C,
     state: Mutex<HeapState<C>>,
+    retired: Mutex<Vec<RetiredBlock>>,
 }
 unsafe impl<C: MemoryController> Send for HookHeap<C> where C: Send {}
 unsafe impl<C: MemoryController> Sync for HookHeap<C> where C: Sync {}
 
+impl<C: MemoryController> std::fmt::Debug for HookHeap<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut debug = f.debug_struct("HookHeap");
+        match self.state.try_lock() {
+            Ok(state) => debug.field("regions", &state.stats()),
+            Err(_) => debug.field("regions", &"<locked>"),
+        };
+        debug.finish()
+    }
+}
+
 impl HookHeap<DefaultMemoryController> {
     pub const fn new() -> Self {
         Self::with_memory_controller(DefaultMemoryController::new())
@@ -55,34 +261,153 @@ impl<C: MemoryController> HookHeap<C> {
         Self {
             mem: controller,
             state: Mutex::new(HeapState::empty()),
+            retired: Mutex::new(Vec::new()),
         }
     }
 
+    // Dropping a block that may still run isn't an option, the list stays
+    // usable after a panic
+    fn retired(&self) -> MutexGuard<'_, Vec<RetiredBlock>> {
+        self.retired.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
     fn state<'a>(&'a self) -> Result<MutexGuard<'a, HeapState<C>>> {
         self.state
             .lock()
             .map_err(|_| MemoryError::BadTableHeapState)
     }
 
-    pub unsafe fn ensure_allocated(&self, min_size: Option<usize>) -> Result<()> {
-        unsafe { self.state()?.ensure_allocated(&self.mem, min_size) }
+    pub unsafe fn ensure_allocated(
+        &self,
+        min_size: usize,
+        near: Option<NonNull<c_void>>,
+    ) -> Result<()> {
+        unsafe { self.state()?.ensure_allocated(&self.mem, min_size, near)? };
+        Ok(())
+    }
+
+    pub fn region_stats(&self) -> Result<Vec<HeapRegionStats>> {
+        Ok(self.state()?.stats())
     }
 
-    pub fn get_handle<'a>(&'a self) -> Result<MemoryHeapHandle<'a, C>> {
+    pub fn get_handle<'a>(
+        &'a self,
+        min_size: usize,
+        near: Option<NonNull<c_void>>,
+    ) -> Result<MemoryHeapHandle<'a, C>> {
+        self.reclaim()?;
         let mut state = self.state()?;
-        unsafe {
-            state.ensure_allocated(&self.mem, None)?;
-        }
+        let region = unsafe { state.ensure_allocated(&self.mem, min_size, near)? };
+        let block =
+            state
+                .region_mut(region)?
+                .blocks
+                .allocate(min_size)
+                .ok_or(MemoryError::NoMemory {
+                    needs: min_size,
+                    has: 0,
+                })?;
 
         Ok(MemoryHeapHandle {
             state,
+            region,
+            block,
+            block_size: align_block(min_size),
+            written: 0,
             mem: &self.mem,
         })
     }
+
+    pub unsafe fn free(&self, ptr: NonNull<c_void>, size: usize) -> Result<()> {
+        let mut state = self.state()?;
+        let region = state
+            .regions
+            .iter_mut()
+            .find(|region| region.contains(ptr))
+            .ok_or(MemoryError::BadAdress(ptr.as_ptr()))?;
+
+        {
+            let _guard = self.mem.protection_guard(
+                region.allocation.allocation_start(),
+                region.size(),
+                MemoryProtection::ReadWriteExecute,
+                MemoryProtection::ReadExecute,
+            )?;
+            unsafe {
+                std::ptr::write_bytes(ptr.as_ptr() as *mut u8, FREED_MEMORY_FILL, size);
+            }
+        }
+
+        region
+            .blocks
+            .release(ptr.as_ptr() as usize - region.start(), size);
+        Ok(())
+    }
+
+    // Frees the block once `idle` says nothing runs it anymore, which is
+    // checked again whenever blocks are allocated or retired. Until then the
+    // block is left as it is.
+    pub unsafe fn retire(
+        &self,
+        ptr: NonNull<c_void>,
+        size: usize,
+        idle: Box<dyn FnMut() -> bool + Send>,
+    ) -> Result<()> {
+        self.retired().push(RetiredBlock { ptr, size, idle });
+        self.reclaim()
+    }
+
+    // Frees the retired blocks that became idle. `idle` runs without any
+    // lock of the heap held.
+    pub fn reclaim(&self) -> Result<()> {
+        let retired = std::mem::take(&mut *self.retired());
+        if retired.is_empty() {
+            return Ok(());
+        }
+
+        let mut result = Ok(());
+        let mut running = Vec::new();
+        for mut block in retired {
+            if !(block.idle)() {
+                running.push(block);
+                continue;
+            }
+            if let Err(error) = unsafe { self.free(block.ptr, block.size) } {
+                result = result.and(Err(error));
+            }
+        }
+        self.retired().append(&mut running);
+        result
+    }
+
+    // Atomically replaces a pointer sized value that live code may be reading
+    pub unsafe fn write_pointer(&self, ptr: NonNull<c_void>, value: usize) -> Result<()> {
+        let state = self.state()?;
+        let region = state
+            .regions
+            .iter()
+            .find(|region| region.contains(ptr))
+            .ok_or(MemoryError::BadAdress(ptr.as_ptr()))?;
+
+        let _guard = self.mem.protection_guard(
+            region.allocation.allocation_start(),
+            region.size(),
+            MemoryProtection::ReadWriteExecute,
+            MemoryProtection::ReadExecute,
+        )?;
+        unsafe {
+            AtomicUsize::from_ptr(ptr.as_ptr() as *mut usize).store(value, Ordering::Release)
+        };
+        Ok(())
+    }
 }
 
 pub struct MemoryHeapHandle<'a, C: MemoryController> {
     state: MutexGuard<'a, HeapState<C>>,
+    region: usize,
+    block: usize,
+    block_size: usize,
+    written: usize,
     mem: &'a C,
 }
 
@@ -91,34 +416,36 @@ impl<'a, M: MemoryController> MemoryHeapHandle<'a, M> {
         MemoryWriteHandle::new_from(self)
     }
 
-    pub unsafe fn write_address(&self) -> Result<NonNull<c_void>> {
-        let allocation = self.state.allocation()?;
+    pub unsafe fn block_address(&self) -> Result<NonNull<c_void>> {
+        let region = self.state.region(self.region)?;
         Ok(unsafe {
-            allocation
+            region
+                .allocation
                 .allocation_start()
                 .as_ptr()
-                .add(self.state.written)
+                .add(self.block)
         })
     }
 
-    pub unsafe fn reserve(&mut self, size: usize) -> Result<NonNull<c_void>> {
-        let allocation = self.state.allocation()?;
+    pub fn written(&self) -> usize {
+        self.written
+    }
 
-        if self.state.written + size > allocation.allocation_size() {
+    pub unsafe fn write_address(&self) -> Result<NonNull<c_void>> {
+        Ok(unsafe { self.block_address()?.add(self.written) })
+    }
+
+    pub unsafe fn reserve(&mut self, size: usize) -> Result<NonNull<c_void>> {
+        if self.written + size > self.block_size {
             return Err(MemoryError::NoMemory {
-                needs: self.state.written + size,
-                has: allocation.allocation_size(),
+                needs: self.written + size,
+                has: self.block_size,
             });
         }
 
-        let write_address = unsafe {
-            allocation
-                .allocation_start()
-                .as_ptr()
-                .add(self.state.written)
-        };
+        let write_address = unsafe { self.write_address()? };
 
-        self.state.written += size;
+        self.written += size;
 
         Ok(write_address)
     }
@@ -128,7 +455,7 @@ impl<'a, M: MemoryController> MemoryHeapHandle<'a, M> {
         on_enter: MemoryProtection,
         on_exit: MemoryProtection,
     ) -> Result<MemoryProtectionGuard<'a, M>> {
-        let allocation = self.state.allocation()?;
+        let allocation = &self.state.region(self.region)?.allocation;
 
         self.mem.protection_guard(
             allocation.allocation_start(),
@@ -139,6 +466,19 @@ impl<'a, M: MemoryController> MemoryHeapHandle<'a, M> {
     }
 }
 
+impl<'a, M: MemoryController> Drop for MemoryHeapHandle<'a, M> {
+    fn drop(&mut self) {
+        // Hand the part of the block that was not written back to the region
+        let used = align_block(self.written);
+        let (block, block_size) = (self.block, self.block_size);
+        if let Ok(region) = self.state.region_mut(self.region)
+            && used < block_size
+        {
+            region.blocks.release(block + used, block_size - used);
+        }
+    }
+}
+
 pub struct MemoryWriteHandle<'a, 'b, M: MemoryController> {
     heap: &'b mut MemoryHeapHandle<'a, M>,
     _guard: MemoryProtectionGuard<'a, M>,
@@ -147,8 +487,10 @@ pub struct MemoryWriteHandle<'a, 'b, M: MemoryController> {
 impl<'a, 'b, M: MemoryController> MemoryWriteHandle<'a, 'b, M> {
     pub fn new_from(handle: &'b mut MemoryHeapHandle<'a, M>) -> Result<Self> {
         let handle = Self {
-            _guard: handle
-                .protection_guard(MemoryProtection::ReadWrite, MemoryProtection::ReadExecute)?,
+            _guard: handle.protection_guard(
+                MemoryProtection::ReadWriteExecute,
+                MemoryProtection::ReadExecute,
+            )?,
             heap: handle,
         };
 
@@ -175,3 +517,57 @@ impl<'a, 'b, M: MemoryController> MemoryWriteHandle<'a, 'b, M> {
         Ok(write_address)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn freed_blocks_are_reused() {
+        let mut blocks = FreeList::new(0x100);
+        let first = blocks.allocate(0x20).unwrap();
+        let second = blocks.allocate(0x20).unwrap();
+        blocks.allocate(0x20).unwrap();
+
+        blocks.release(first, 0x20);
+        assert_eq!(blocks.allocate(0x10), Some(first));
+        assert_eq!(blocks.allocate(0x10), Some(first + 0x10));
+        assert_ne!(blocks.allocate(0x10), Some(second));
+    }
+
+    #[test]
+    fn adjacent_blocks_are_merged() {
+        let mut blocks = FreeList::new(0x100);
+        let first = blocks.allocate(0x20).unwrap();
+        let second = blocks.allocate(0x20).unwrap();
+        let third = blocks.allocate(0x20).unwrap();
+        blocks.allocate(0x20).unwrap();
+
+        blocks.release(first, 0x20);
+        blocks.release(third, 0x20);
+        blocks.release(second, 0x20);
+        assert_eq!(blocks.free_blocks, vec![(first, 0x60)]);
+        assert_eq!(blocks.allocate(0x60), Some(first));
+    }
+
+    #[test]
+    fn releasing_the_last_block_shrinks_the_written_area() {
+        let mut blocks = FreeList::new(0x100);
+        let first = blocks.allocate(0x20).unwrap();
+        let second = blocks.allocate(0x18).unwrap();
+
+        blocks.release(first, 0x20);
+        blocks.release(second, 0x18);
+        assert_eq!(blocks.written, 0);
+        assert!(blocks.free_blocks.is_empty());
+        assert_eq!(blocks.used(), 0);
+    }
+
+    #[test]
+    fn allocation_fails_when_full() {
+        let mut blocks = FreeList::new(0x40);
+        blocks.allocate(0x30).unwrap();
+        assert!(!blocks.has_room(0x20));
+        assert_eq!(blocks.allocate(0x20), None);
+    }
+}
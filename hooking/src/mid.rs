@@ -0,0 +1,210 @@
+use core::ptr::NonNull;
+use std::ffi::c_void;
+
+use crate::asm::{CpuContext, HookAssembler};
+use crate::control::{HookContext, HookControl};
+use crate::error::{HookingError, Result};
+use crate::hooks::{Hook, HookWriter, Trampoline};
+use crate::mem::{DefaultMemoryController, MemoryController};
+
+pub type MidHookFn = dyn Fn(&mut CpuContext) + Send + Sync;
+
+// Called by the context trampoline. Continues at the hooked instruction, or
+// the next hook on it, unless the callback changed rip.
+unsafe extern "C" fn mid_hook_entry(control: *const HookControl, cpu: *mut CpuContext) {
+    let (control, cpu) = unsafe { (&*control, &mut *cpu) };
+    let target = cpu.rip;
+
+    if control.is_enabled()
+        && let Some(callback) = control
+            .context()
+            .and_then(|context| context.get::<Box<MidHookFn>>())
+    {
+        callback(cpu);
+    }
+    if cpu.rip == target {
+        cpu.rip = control.original().as_ptr() as usize;
+    }
+}
+
+impl Hook<'static, DefaultMemoryController> {
+    // Hooks any instruction, not only the start of a function. At least 5
+    // bytes from `address` are moved, none of them can be a branch target.
+    pub unsafe fn mid_function(
+        address: *mut u8,
+        callback: impl Fn(&mut CpuContext) + Send + Sync + 'static,
+    ) -> Result<Self> {
+        let hook_writer = HookWriter::from_static();
+        unsafe {
+            hook_writer.create_mid_hook(
+                NonNull::new(address as *mut _)
+                    .ok_or(HookingError::InvalidTarget(address as *const _))?,
+                Box::new(callback),
+            )
+        }
+    }
+}
+
+impl<'a, M: MemoryController, A: HookAssembler> HookWriter<'a, M, A> {
+    pub unsafe fn create_mid_hook(
+        &self,
+        address: NonNull<c_void>,
+        callback: Box<MidHookFn>,
+    ) -> Result<Hook<'a, M>> {
+        let handler = mid_hook_entry as *const () as *mut c_void;
+
+        unsafe {
+            let symbol_info = self.hook_heap.mem.get_symbol_info(address);
+            self.create_registered_trampoline(
+                address,
+                Trampoline::Context(NonNull::new_unchecked(handler)),
+                symbol_info,
+                Some(HookContext::new(callback)),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::arch::naked_asm;
+
+    #[cfg(not(target_os = "windows"))]
+    #[unsafe(naked)]
+    extern "C" fn clamp_to_ten(value: u64) -> u64 {
+        naked_asm!(
+            "mov rax, rdi",
+            "cmp rax, 10",
+            "jbe 2f",
+            "mov rax, 10",
+            "2:",
+            "ret",
+        )
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn mid_function_hooks_change_registers() {
+        // Offset of the compare and of the return
+        const CLAMP_COMPARE: usize = 3;
+        const CLAMP_RETURN: usize = 16;
+
+        let clamp = clamp_to_ten as *const () as usize;
+        let clamp_fn: extern "C" fn(u64) -> u64 = std::hint::black_box(clamp_to_ten);
+        assert_eq!(unsafe { *((clamp + CLAMP_RETURN) as *const u8) }, 0xC3);
+
+        let mut hook = unsafe {
+            Hook::mid_function((clamp + CLAMP_COMPARE) as *mut u8, move |cpu| {
+                match cpu.rdi {
+                    7 => cpu.rax = 5,
+                    100 => cpu.rip = clamp + CLAMP_RETURN,
+                    _ => {}
+                }
+            })
+            .unwrap()
+        };
+
+        unsafe {
+            hook.apply_hook().unwrap();
+            assert_eq!(clamp_fn(3), 3);
+            assert_eq!(clamp_fn(50), 10);
+            assert_eq!(clamp_fn(7), 5);
+            assert_eq!(clamp_fn(100), 100);
+            hook.remove_hook().unwrap();
+        }
+        assert_eq!(clamp_fn(100), 10);
+    }
+
+    static RELOCATED_BIAS: u32 = 40;
+
+    // Leaf functions keeping their argument in the red zone across a rip
+    // relative load, hooked at offset 5
+    #[cfg(not(target_os = "windows"))]
+    #[unsafe(naked)]
+    extern "C" fn red_zone_bias(value: u64) -> u64 {
+        naked_asm!(
+            "mov qword ptr [rsp - 8], rdi",
+            "mov eax, dword ptr [rip + {bias}]",
+            "add rax, qword ptr [rsp - 8]",
+            "ret",
+            bias = sym RELOCATED_BIAS,
+        )
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[unsafe(naked)]
+    extern "C" fn red_zone_bias_r10(value: u64) -> u64 {
+        naked_asm!(
+            "mov qword ptr [rsp - 8], rdi",
+            "mov r10d, dword ptr [rip + {bias}]",
+            "mov rax, qword ptr [rsp - 8]",
+            "add rax, r10",
+            "ret",
+            bias = sym RELOCATED_BIAS,
+        )
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn relocated_rip_relative_loads_keep_the_red_zone() {
+        for function in [red_zone_bias, red_zone_bias_r10] {
+            let address = function as *const () as usize + 5;
+            let bias_fn: extern "C" fn(u64) -> u64 = std::hint::black_box(function);
+
+            let mut hook = unsafe { Hook::mid_function(address as *mut u8, |_| {}).unwrap() };
+
+            unsafe {
+                hook.apply_hook().unwrap();
+                assert_eq!(bias_fn(2), 42);
+                hook.remove_hook().unwrap();
+            }
+            assert_eq!(bias_fn(2), 42);
+        }
+    }
+
+    // Offset of the hooked `mov eax, 1`
+    const UPPER_HOOK: usize = 5;
+
+    // Upper half of ymm3 after the hooked instruction, set to all ones before
+    #[unsafe(naked)]
+    extern "C" fn ymm3_upper() -> u64 {
+        naked_asm!(
+            "vcmptruepd ymm3, ymm3, ymm3",
+            "mov eax, 1",
+            "vextractf128 xmm0, ymm3, 1",
+            "vmovq rax, xmm0",
+            "vzeroupper",
+            "ret",
+        )
+    }
+
+    #[test]
+    fn extended_state_survives_callbacks() {
+        if !std::arch::is_x86_feature_detected!("avx") {
+            return;
+        }
+        let address = ymm3_upper as *const () as usize + UPPER_HOOK;
+        assert_eq!(unsafe { *(address as *const u8) }, 0xB8);
+        let upper_fn: extern "C" fn() -> u64 = std::hint::black_box(ymm3_upper);
+
+        let clobber = |_: &mut CpuContext| unsafe {
+            core::arch::asm!("vxorps ymm3, ymm3, ymm3", out("xmm3") _);
+        };
+
+        for (save, expected) in [(false, 0), (true, u64::MAX)] {
+            let writer = HookWriter::from_static().with_extended_state(save);
+            let mut hook = unsafe {
+                writer
+                    .create_mid_hook(NonNull::new_unchecked(address as *mut _), Box::new(clobber))
+                    .unwrap()
+            };
+
+            unsafe {
+                hook.apply_hook().unwrap();
+                assert_eq!(upper_fn(), expected);
+                hook.remove_hook().unwrap();
+            }
+        }
+    }
+}
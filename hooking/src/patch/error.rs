@@ -0,0 +1,15 @@
+use thiserror::Error;
+
+pub type Result<T> = std::result::Result<T, PatchError>;
+
+#[derive(Debug, Error)]
+pub enum PatchError {
+    #[error("Breakpoint patching is not supported on this platform")]
+    Unsupported,
+
+    #[error("Breakpoint patching state is poisoned")]
+    BadBreakpointState,
+
+    #[error("Failed to install the breakpoint signal handler")]
+    CantInstallHandler,
+}
@@ -0,0 +1,207 @@
+use std::cell::UnsafeCell;
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+use super::super::INT3;
+use super::super::error::{PatchError, Result};
+use crate::threads::SuspendedThreads;
+use libc;
+
+// Old entries are kept around so a thread that trapped just before the
+// breakpoint was removed can still be sent back to the patched instruction.
+const BREAKPOINT_SLOTS: usize = 16;
+
+const MEMBARRIER_CMD_PRIVATE_EXPEDITED_SYNC_CORE: i32 = 1 << 5;
+const MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED_SYNC_CORE: i32 = 1 << 6;
+
+struct Breakpoint {
+    address: AtomicUsize,
+    redirect: AtomicUsize,
+    active: AtomicBool,
+}
+
+impl Breakpoint {
+    const fn new() -> Self {
+        Self {
+            address: AtomicUsize::new(0),
+            redirect: AtomicUsize::new(0),
+            active: AtomicBool::new(false),
+        }
+    }
+}
+
+static BREAKPOINTS: [Breakpoint; BREAKPOINT_SLOTS] =
+    [const { Breakpoint::new() }; BREAKPOINT_SLOTS];
+static NEXT_BREAKPOINT: AtomicUsize = AtomicUsize::new(0);
+
+// Serializes breakpoint patches, the handler is only installed while one is
+// being written
+static BREAKPOINT_LOCK: Mutex<()> = Mutex::new(());
+
+// Action the handler replaced. Only written under `BREAKPOINT_LOCK` before the
+// handler is installed, the handler only reads it.
+struct PreviousAction(UnsafeCell<Option<libc::sigaction>>);
+
+unsafe impl Sync for PreviousAction {}
+
+static PREVIOUS_ACTION: PreviousAction = PreviousAction(UnsafeCell::new(None));
+
+static SYNC_CORE_REGISTERED: OnceLock<bool> = OnceLock::new();
+
+// Everything in here has to be async signal safe, so no allocations or locks.
+extern "C" fn trap_handler(signal: i32, info: *mut libc::siginfo_t, context: *mut c_void) {
+    unsafe {
+        let ucontext = context as *mut libc::ucontext_t;
+        let rip = &mut (*ucontext).uc_mcontext.gregs[libc::REG_RIP as usize];
+        let trap_address = (*rip as usize).wrapping_sub(1);
+
+        for breakpoint in &BREAKPOINTS {
+            if breakpoint.address.load(Ordering::Acquire) != trap_address {
+                continue;
+            }
+
+            if (trap_address as *const u8).read_volatile() != INT3 {
+                // The patch was finished in the meantime, run it instead
+                *rip = trap_address as i64;
+                return;
+            }
+            if breakpoint.active.load(Ordering::Acquire) {
+                *rip = breakpoint.redirect.load(Ordering::Acquire) as i64;
+                return;
+            }
+        }
+
+        forward_signal(signal, info, context);
+    }
+}
+
+unsafe fn forward_signal(signal: i32, info: *mut libc::siginfo_t, context: *mut c_void) {
+    let Some(previous) = (unsafe { &*PREVIOUS_ACTION.0.get() }) else {
+        return;
+    };
+
+    unsafe {
+        match previous.sa_sigaction {
+            libc::SIG_IGN => {}
+            libc::SIG_DFL => {
+                // Not one of ours, let the default action take the process down
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = libc::SIG_DFL;
+                libc::sigaction(signal, &action, std::ptr::null_mut());
+                libc::raise(signal);
+            }
+            handler if previous.sa_flags & libc::SA_SIGINFO != 0 => {
+                let handler: extern "C" fn(i32, *mut libc::siginfo_t, *mut c_void) =
+                    std::mem::transmute(handler);
+                handler(signal, info, context);
+            }
+            handler => {
+                let handler: extern "C" fn(i32) = std::mem::transmute(handler);
+                handler(signal);
+            }
+        }
+    }
+}
+
+// Has to be called with `BREAKPOINT_LOCK` held
+unsafe fn install_handler() -> Result<()> {
+    unsafe {
+        let mut current: libc::sigaction = std::mem::zeroed();
+        if libc::sigaction(libc::SIGTRAP, std::ptr::null(), &mut current) != 0 {
+            return Err(PatchError::CantInstallHandler);
+        }
+        *PREVIOUS_ACTION.0.get() = Some(current);
+
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = trap_handler as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+
+        if libc::sigaction(libc::SIGTRAP, &action, std::ptr::null_mut()) != 0 {
+            return Err(PatchError::CantInstallHandler);
+        }
+    }
+    Ok(())
+}
+
+// Puts back the action that was there before `install_handler`. A thread
+// that hit the breakpoint gets its SIGTRAP when it returns to user space, so
+// the others are stopped once first: synchronous signals are delivered
+// before the suspend signal, which makes every pending trap take our handler.
+unsafe fn restore_handler(others_suspended: bool) {
+    let flushed = others_suspended || SuspendedThreads::suspend_others().is_ok();
+    if !flushed {
+        // Better to leave the handler in place than to kill a trapped thread
+        return;
+    }
+    unsafe {
+        if let Some(previous) = &*PREVIOUS_ACTION.0.get() {
+            libc::sigaction(libc::SIGTRAP, previous, std::ptr::null_mut());
+        }
+    }
+}
+
+// Sends threads that execute the breakpoint at `address` to `redirect` until
+// dropped. The trap handler is only installed for that long.
+pub struct BreakpointRedirect {
+    slot: usize,
+    others_suspended: bool,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl BreakpointRedirect {
+    // `others_suspended` if every other thread is already stopped, they are
+    // suspended briefly to remove the handler otherwise
+    pub fn install(address: usize, redirect: usize, others_suspended: bool) -> Result<Self> {
+        let lock = BREAKPOINT_LOCK
+            .lock()
+            .map_err(|_| PatchError::BadBreakpointState)?;
+        unsafe { install_handler()? };
+
+        let slot = NEXT_BREAKPOINT.fetch_add(1, Ordering::AcqRel) % BREAKPOINT_SLOTS;
+        let breakpoint = &BREAKPOINTS[slot];
+        breakpoint.address.store(0, Ordering::Release);
+        breakpoint.redirect.store(redirect, Ordering::Release);
+        breakpoint.active.store(true, Ordering::Release);
+        breakpoint.address.store(address, Ordering::Release);
+
+        Ok(Self {
+            slot,
+            others_suspended,
+            _lock: lock,
+        })
+    }
+}
+
+impl Drop for BreakpointRedirect {
+    fn drop(&mut self) {
+        BREAKPOINTS[self.slot]
+            .active
+            .store(false, Ordering::Release);
+        unsafe { restore_handler(self.others_suspended) };
+    }
+}
+
+// Makes every other thread of the process serialize its instruction stream
+// so none of them keeps executing stale bytes. Best effort, kernels without
+// membarrier sync core support only get the cache coherency of x86.
+pub fn sync_core() {
+    let registered = SYNC_CORE_REGISTERED.get_or_init(|| unsafe {
+        libc::syscall(
+            libc::SYS_membarrier,
+            MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED_SYNC_CORE,
+            0,
+        ) == 0
+    });
+
+    if *registered {
+        unsafe {
+            libc::syscall(
+                libc::SYS_membarrier,
+                MEMBARRIER_CMD_PRIVATE_EXPEDITED_SYNC_CORE,
+                0,
+            );
+        }
+    }
+}
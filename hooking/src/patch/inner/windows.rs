@@ -0,0 +1,11 @@
+use super::super::error::{PatchError, Result};
+
+pub struct BreakpointRedirect;
+
+impl BreakpointRedirect {
+    pub fn install(_address: usize, _redirect: usize, _others_suspended: bool) -> Result<Self> {
+        Err(PatchError::Unsupported)
+    }
+}
+
+pub fn sync_core() {}
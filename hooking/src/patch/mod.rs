@@ -0,0 +1,291 @@
+#[cfg(target_os = "windows")]
+pub mod inner {
+    pub mod windows;
+    pub use windows::*;
+}
+
+#[cfg(target_os = "linux")]
+pub mod inner {
+    pub mod linux;
+    pub use linux::*;
+}
+
+pub mod error;
+pub use error::PatchError;
+
+use core::ptr::NonNull;
+use std::ffi::c_void;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::error::Result;
+use crate::mem::{MemoryController, MemoryProtection};
+use crate::threads::SuspendedThreads;
+use inner::BreakpointRedirect;
+
+pub(crate) const INT3: u8 = 0xCC;
+
+// A patch writer must never be stopped by another one that suspends threads
+static PATCH_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PatchOptions {
+    // Stop every other thread while the patch is written and move the ones
+    // that are inside the overwritten instructions.
+    pub suspend_threads: bool,
+    // Never let another thread execute a partially written patch.
+    pub atomic: bool,
+}
+
+impl PatchOptions {
+    pub const fn new() -> Self {
+        Self {
+            suspend_threads: false,
+            atomic: false,
+        }
+    }
+
+    pub const fn with_suspend_threads(mut self, suspend_threads: bool) -> Self {
+        self.suspend_threads = suspend_threads;
+        self
+    }
+
+    pub const fn with_atomic(mut self, atomic: bool) -> Self {
+        self.atomic = atomic;
+        self
+    }
+}
+
+// Writes `bytes` over `target`. With `suspend_threads` set every other thread
+// is stopped for the duration and `relocate` is given the instruction pointer
+// of each of them, it must not allocate. Threads that hit the breakpoint of
+// an atomic write that did not fit a single store continue at `redirect`.
+pub(crate) unsafe fn write_patch<M: MemoryController>(
+    mem: &M,
+    target: NonNull<c_void>,
+    bytes: &[u8],
+    redirect: NonNull<c_void>,
+    options: PatchOptions,
+    relocate: impl Fn(usize) -> Option<usize>,
+) -> Result<()> {
+    let _lock = PATCH_LOCK
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    let _protection_guard =
+        mem.protection_guard_for_page(target, MemoryProtection::ReadWriteExecute, None)?;
+
+    // The patch may run into the next page
+    let last_byte = unsafe { target.byte_add(bytes.len().saturating_sub(1)) };
+    let _tail_protection_guard =
+        mem.protection_guard_for_page(last_byte, MemoryProtection::ReadWriteExecute, None)?;
+
+    let mut suspended = if options.suspend_threads {
+        Some(SuspendedThreads::suspend_others()?)
+    } else {
+        None
+    };
+
+    if options.atomic {
+        unsafe {
+            write_atomic(
+                target.as_ptr() as usize,
+                bytes,
+                redirect.as_ptr() as usize,
+                suspended.is_some(),
+            )?
+        };
+    } else {
+        unsafe {
+            std::ptr::copy_nonoverlapping(bytes.as_ptr(), target.as_ptr() as *mut u8, bytes.len());
+        }
+    }
+
+    if let Some(suspended) = &mut suspended {
+        unsafe {
+            suspended.for_each_instruction_pointer(|instruction_pointer| {
+                if let Some(relocated) = relocate(*instruction_pointer) {
+                    *instruction_pointer = relocated;
+                }
+            });
+        }
+    }
+
+    Ok(())
+}
+
+// Start of the `size` aligned window that holds all of `len` bytes at `address`
+fn aligned_window(address: usize, len: usize, size: usize) -> Option<usize> {
+    let start = address & !(size - 1);
+    (address + len <= start + size).then_some(start)
+}
+
+fn overlay(window: &mut [u8], offset: usize, bytes: &[u8]) {
+    window[offset..offset + bytes.len()].copy_from_slice(bytes);
+}
+
+unsafe fn write_atomic(
+    address: usize,
+    bytes: &[u8],
+    redirect: usize,
+    others_suspended: bool,
+) -> Result<()> {
+    if bytes.is_empty() {
+        return Ok(());
+    }
+
+    if let Some(start) = aligned_window(address, bytes.len(), 8) {
+        let window = unsafe { AtomicU64::from_ptr(start as *mut u64) };
+        let mut current = window.load(Ordering::Acquire);
+        loop {
+            let mut new = current.to_ne_bytes();
+            overlay(&mut new, address - start, bytes);
+            match window.compare_exchange(
+                current,
+                u64::from_ne_bytes(new),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => break,
+                Err(actual) => current = actual,
+            }
+        }
+    } else if let Some(start) = aligned_window(address, bytes.len(), 16)
+        && std::arch::is_x86_feature_detected!("cmpxchg16b")
+    {
+        let mut current = unsafe { (start as *const u128).read_volatile() };
+        loop {
+            let mut new = current.to_ne_bytes();
+            overlay(&mut new, address - start, bytes);
+            let actual =
+                unsafe { cmpxchg16b(start as *mut u128, current, u128::from_ne_bytes(new)) };
+            if actual == current {
+                break;
+            }
+            current = actual;
+        }
+    } else {
+        // Too wide for a single store. Park the first byte on a breakpoint
+        // while the rest is written so nobody runs a half written patch.
+        let _redirect = BreakpointRedirect::install(address, redirect, others_suspended)?;
+        let head = address as *mut u8;
+        unsafe {
+            head.write_volatile(INT3);
+            inner::sync_core();
+
+            std::ptr::copy_nonoverlapping(bytes[1..].as_ptr(), head.add(1), bytes.len() - 1);
+            inner::sync_core();
+
+            head.write_volatile(bytes[0]);
+            inner::sync_core();
+        }
+    }
+
+    Ok(())
+}
+
+// Returns the value that was in memory, the store happened if it equals `current`
+unsafe fn cmpxchg16b(address: *mut u128, current: u128, new: u128) -> u128 {
+    let (low, high): (u64, u64);
+    unsafe {
+        // rbx is reserved by llvm, swap it in and out around the instruction
+        core::arch::asm!(
+            "xchg {new_low}, rbx",
+            "lock cmpxchg16b xmmword ptr [{address}]",
+            "mov rbx, {new_low}",
+            address = in(reg) address,
+            new_low = inout(reg) new as u64 => _,
+            in("rcx") (new >> 64) as u64,
+            inout("rax") current as u64 => low,
+            inout("rdx") (current >> 64) as u64 => high,
+            options(nostack),
+        );
+    }
+    (high as u128) << 64 | low as u128
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aligned_window_holds_whole_patch() {
+        assert_eq!(aligned_window(0x1003, 5, 8), Some(0x1000));
+        assert_eq!(aligned_window(0x1004, 5, 8), None);
+        assert_eq!(aligned_window(0x1004, 5, 16), Some(0x1000));
+        assert_eq!(aligned_window(0x100c, 5, 16), None);
+    }
+
+    // Breakpoint patches swap the SIGTRAP action for a moment
+    static TRAP_LOCK: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn atomic_writes_only_touch_the_patch() {
+        let _lock = TRAP_LOCK.lock().unwrap();
+        #[repr(align(16))]
+        struct Buffer([u8; 48]);
+
+        // single store, cmpxchg16b and breakpoint sequence
+        for (offset, len) in [(3, 5), (9, 6), (13, 14)] {
+            let mut buffer = Buffer([0x90; 48]);
+            let patch = (1..=len as u8).collect::<Vec<_>>();
+            let address = buffer.0.as_mut_ptr() as usize + offset;
+
+            unsafe { write_atomic(address, &patch, 0, false).unwrap() };
+
+            assert!(buffer.0[..offset].iter().all(|b| *b == 0x90));
+            assert_eq!(&buffer.0[offset..offset + len], &patch[..]);
+            assert!(buffer.0[offset + len..].iter().all(|b| *b == 0x90));
+        }
+    }
+
+    // A sled the hook can be placed anywhere in
+    #[cfg(target_os = "linux")]
+    #[unsafe(naked)]
+    extern "C" fn nop_sled() -> i32 {
+        core::arch::naked_asm!(".rept 32", "nop", ".endr", "mov eax, 1717", "ret")
+    }
+
+    #[cfg(target_os = "linux")]
+    extern "C" fn hooked_sled() -> i32 {
+        std::hint::black_box(-1717)
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn sigtrap_action() -> usize {
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            libc::sigaction(libc::SIGTRAP, std::ptr::null(), &mut action);
+            action.sa_sigaction
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn breakpoint_patches_restore_the_trap_handler() {
+        use crate::hooks::Hook;
+
+        let _lock = TRAP_LOCK.lock().unwrap();
+        // Across a 16 byte boundary, too wide for a single store
+        let sled = nop_sled as *const () as usize;
+        let target = (sled + 16) & !15 | 13;
+        let sled_fn: extern "C" fn() -> i32 = std::hint::black_box(nop_sled);
+        let previous = unsafe { sigtrap_action() };
+
+        let mut hook = unsafe {
+            Hook::create(target as *mut u8, hooked_sled as *const () as *mut u8).unwrap()
+        };
+        hook.set_patch_options(PatchOptions::new().with_atomic(true));
+        assert!(aligned_window(target, hook.data.patch_data.len(), 16).is_none());
+
+        unsafe {
+            hook.apply_hook().unwrap();
+            assert_eq!(sigtrap_action(), previous);
+            assert_eq!(sled_fn(), -1717);
+
+            hook.remove_hook().unwrap();
+            assert_eq!(sigtrap_action(), previous);
+            assert_eq!(sled_fn(), 1717);
+        }
+    }
+}
@@ -0,0 +1,477 @@
+use core::ptr::NonNull;
+use std::backtrace::Backtrace;
+use std::collections::BTreeMap;
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::error::{HookingError, Result};
+use crate::guard::UnhookFailurePolicy;
+use crate::mem::{MemoryController, SymbolInfo};
+use crate::patch::{PatchOptions, write_patch};
+
+static CHAINS: Mutex<BTreeMap<usize, HookChain>> = Mutex::new(BTreeMap::new());
+
+// Every hook that was created and not freed, by hook table address
+static HOOKS: Mutex<BTreeMap<usize, HookRecord>> = Mutex::new(BTreeMap::new());
+
+// Table addresses are reused once a hook is freed, this orders the records
+static NEXT_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+static ATEXIT_INSTALLED: AtomicBool = AtomicBool::new(false);
+
+// What happens when the hooks can't be removed at exit
+static ATEXIT_POLICY: Mutex<UnhookFailurePolicy> = Mutex::new(UnhookFailurePolicy::Ignore);
+
+pub(crate) fn chains() -> MutexGuard<'static, BTreeMap<usize, HookChain>> {
+    CHAINS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// Writes patches with the memory controller a hook was created with, so code
+// that only has the registry doesn't fall back to the default one
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Patcher {
+    mem: *const (),
+    write: WritePatch,
+}
+
+type WritePatch =
+    unsafe fn(*const (), NonNull<c_void>, &[u8], NonNull<c_void>, PatchOptions) -> Result<()>;
+
+// The controller is only used by whoever holds the chains
+unsafe impl Send for Patcher {}
+
+impl Patcher {
+    // `mem` has to outlive every link made with the patcher. Hooks borrow
+    // their heap, which owns the controller, for as long as they exist.
+    pub fn new<M: MemoryController>(mem: &M) -> Self {
+        unsafe fn write<M: MemoryController>(
+            mem: *const (),
+            target: NonNull<c_void>,
+            bytes: &[u8],
+            redirect: NonNull<c_void>,
+            options: PatchOptions,
+        ) -> Result<()> {
+            unsafe {
+                write_patch(
+                    &*(mem as *const M),
+                    target,
+                    bytes,
+                    redirect,
+                    options,
+                    |_| None,
+                )
+            }
+        }
+        Self {
+            mem: (mem as *const M).cast(),
+            write: write::<M>,
+        }
+    }
+
+    pub unsafe fn write(
+        &self,
+        target: usize,
+        bytes: &[u8],
+        redirect: usize,
+        options: PatchOptions,
+    ) -> Result<()> {
+        let target = NonNull::new(target as *mut c_void)
+            .ok_or(HookingError::InvalidTarget(target as *const _))?;
+        let redirect = NonNull::new(redirect as *mut c_void)
+            .ok_or(HookingError::InvalidTarget(redirect as *const _))?;
+        unsafe { (self.write)(self.mem, target, bytes, redirect, options) }
+    }
+}
+
+// A hook that is currently part of a chain
+#[derive(Debug, Clone)]
+pub(crate) struct ChainLink {
+    // Address of the hook table, unique for every hook
+    pub id: usize,
+    pub trampoline: usize,
+    pub stub: usize,
+    // Where the trampoline loads the original function pointer from
+    pub slot: usize,
+    pub patch: Vec<u8>,
+    pub patcher: Patcher,
+}
+
+// Every applied hook of one target. The first link is called last and runs
+// the original function, the target jumps to the last link.
+#[derive(Debug)]
+pub(crate) struct HookChain {
+    // Bytes of the target before any hook was written
+    pub original: Vec<u8>,
+    pub links: Vec<ChainLink>,
+}
+
+impl HookChain {
+    // `original` has to hold the unhooked bytes of the target
+    pub fn new(original: Vec<u8>) -> Self {
+        Self {
+            original,
+            links: Vec::new(),
+        }
+    }
+
+    pub fn head(&self) -> Option<&ChainLink> {
+        self.links.last()
+    }
+
+    pub fn position(&self, id: usize) -> Option<usize> {
+        self.links.iter().position(|link| link.id == id)
+    }
+
+    // Reads more of the target if needed, every byte past the longest patch
+    // is still the original.
+    pub unsafe fn extend_original(&mut self, target: usize, len: usize) {
+        let start = self.original.len();
+        if start < len {
+            let tail =
+                unsafe { std::slice::from_raw_parts((target + start) as *const u8, len - start) };
+            self.original.extend_from_slice(tail);
+        }
+    }
+
+    // Original bytes with `patch` written over the start, long enough to also
+    // cover a `previous_len` byte patch.
+    pub fn patched_bytes(&self, patch: Option<&[u8]>, previous_len: usize) -> Vec<u8> {
+        let patch = patch.unwrap_or_default();
+        let len = patch.len().max(previous_len);
+        let mut bytes = self.original[..len].to_vec();
+        bytes[..patch.len()].copy_from_slice(patch);
+        bytes
+    }
+
+    // What the target should currently hold, at least `len` bytes
+    pub fn expected_bytes(&self, len: usize) -> Vec<u8> {
+        self.patched_bytes(self.head().map(|head| &head.patch[..]), len)
+    }
+
+    pub fn head_len(&self) -> usize {
+        self.head().map_or(0, |head| head.patch.len())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HookIntegrity {
+    // The target jumps into our hooks as expected
+    Intact,
+    // The target holds its original instructions
+    Removed,
+    // Something else wrote over the target, holds what was found there
+    Overwritten(Vec<u8>),
+}
+
+pub(crate) unsafe fn read_target(target: usize, len: usize) -> Vec<u8> {
+    unsafe { std::slice::from_raw_parts(target as *const u8, len) }.to_vec()
+}
+
+pub(crate) fn integrity(current: &[u8], expected: &[u8], original: &[u8]) -> HookIntegrity {
+    if current == expected {
+        HookIntegrity::Intact
+    } else if current == original {
+        HookIntegrity::Removed
+    } else {
+        HookIntegrity::Overwritten(current.to_vec())
+    }
+}
+
+// Writes the head of the chain over the target again if it was changed, with
+// the memory controller of the head. Returns what the target looked like
+// before.
+pub(crate) unsafe fn reapply_chain(
+    target: usize,
+    chain: &HookChain,
+    options: PatchOptions,
+) -> Result<HookIntegrity> {
+    let (Some(tail), Some(head)) = (chain.links.first(), chain.head()) else {
+        return Ok(HookIntegrity::Removed);
+    };
+    let len = chain.head_len();
+    let expected = chain.expected_bytes(len);
+    let current = unsafe { read_target(target, len) };
+
+    let integrity = integrity(&current, &expected, &chain.original[..len]);
+    if integrity != HookIntegrity::Intact {
+        unsafe { head.patcher.write(target, &expected, tail.stub, options)? };
+    }
+    Ok(integrity)
+}
+
+// Unhooked bytes of the target, from the chain if it is already hooked.
+pub(crate) unsafe fn original_bytes(target: NonNull<c_void>, len: usize) -> Vec<u8> {
+    let target = target.as_ptr() as usize;
+    let mut chains = chains();
+    match chains.get_mut(&target) {
+        Some(chain) => {
+            unsafe { chain.extend_original(target, len) };
+            chain.original[..len].to_vec()
+        }
+        None => unsafe { std::slice::from_raw_parts(target as *const u8, len) }.to_vec(),
+    }
+}
+
+struct HookRecord {
+    sequence: u64,
+    target: usize,
+    symbol_info: SymbolInfo,
+    trampoline: usize,
+    stub: usize,
+    backtrace: Arc<Backtrace>,
+}
+
+#[derive(Debug, Clone)]
+pub struct HookInfo {
+    pub target: NonNull<c_void>,
+    pub module: Option<String>,
+    pub symbol: Option<String>,
+    pub trampoline: NonNull<c_void>,
+    pub stub: NonNull<c_void>,
+    pub applied: bool,
+    // Only captured if enabled through RUST_BACKTRACE or RUST_LIB_BACKTRACE
+    pub backtrace: Arc<Backtrace>,
+}
+
+fn hook_records() -> MutexGuard<'static, BTreeMap<usize, HookRecord>> {
+    HOOKS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+pub(crate) fn register(
+    id: usize,
+    target: usize,
+    symbol_info: SymbolInfo,
+    trampoline: usize,
+    stub: usize,
+) {
+    hook_records().insert(
+        id,
+        HookRecord {
+            sequence: NEXT_SEQUENCE.fetch_add(1, Ordering::Relaxed),
+            target,
+            symbol_info,
+            trampoline,
+            stub,
+            backtrace: Arc::new(Backtrace::capture()),
+        },
+    );
+}
+
+pub(crate) fn unregister(id: usize) {
+    hook_records().remove(&id);
+}
+
+pub(crate) fn is_applied(target: usize, id: usize) -> bool {
+    chains()
+        .get(&target)
+        .is_some_and(|chain| chain.position(id).is_some())
+}
+
+fn info(record: &HookRecord, applied: bool) -> HookInfo {
+    let pointer = |address: usize| unsafe { NonNull::new_unchecked(address as *mut c_void) };
+    HookInfo {
+        target: pointer(record.target),
+        module: record.symbol_info.module.clone(),
+        symbol: record.symbol_info.symbol.clone(),
+        trampoline: pointer(record.trampoline),
+        stub: pointer(record.stub),
+        applied,
+        backtrace: record.backtrace.clone(),
+    }
+}
+
+pub(crate) fn hook_info(id: usize) -> Option<HookInfo> {
+    let records = hook_records();
+    let record = records.get(&id)?;
+    Some(info(record, is_applied(record.target, id)))
+}
+
+// Snapshot of every hook that has not been freed, in creation order
+pub fn hooks() -> Vec<HookInfo> {
+    let records = hook_records();
+    let chains = chains();
+    let mut records = records.iter().collect::<Vec<_>>();
+    records.sort_by_key(|(_, record)| record.sequence);
+    records
+        .into_iter()
+        .map(|(id, record)| {
+            let applied = chains
+                .get(&record.target)
+                .is_some_and(|chain| chain.position(*id).is_some());
+            info(record, applied)
+        })
+        .collect()
+}
+
+// Restores the original bytes of every hooked target, each with the memory
+// controller of its most recent hook. The hooks stay allocated and can be
+// applied again.
+pub unsafe fn remove_all() -> Result<()> {
+    let mut chains = chains();
+    let mut first_error = None;
+
+    let targets = chains.keys().copied().collect::<Vec<_>>();
+    for target in targets {
+        let chain = &chains[&target];
+        let Some(head) = chain.head() else {
+            chains.remove(&target);
+            continue;
+        };
+        let bytes = chain.patched_bytes(None, head.patch.len());
+        let redirect = chain.links[0].stub;
+
+        let result = unsafe {
+            head.patcher
+                .write(target, &bytes, redirect, PatchOptions::new())
+        };
+
+        match result {
+            Ok(()) => {
+                chains.remove(&target);
+            }
+            Err(error) => {
+                first_error.get_or_insert(error);
+            }
+        }
+    }
+
+    first_error.map_or(Ok(()), Err)
+}
+
+// State of every hooked target
+pub fn verify_all() -> Vec<(NonNull<c_void>, HookIntegrity)> {
+    chains()
+        .iter()
+        .map(|(target, chain)| {
+            let len = chain.head_len();
+            let current = unsafe { read_target(*target, len) };
+            let integrity = integrity(&current, &chain.expected_bytes(len), &chain.original[..len]);
+            (
+                unsafe { NonNull::new_unchecked(*target as *mut c_void) },
+                integrity,
+            )
+        })
+        .collect()
+}
+
+// Restores the patch of every hooked target that was changed and returns
+// those targets with what was found there.
+pub unsafe fn reapply_all(options: PatchOptions) -> Result<Vec<(NonNull<c_void>, HookIntegrity)>> {
+    let chains = chains();
+
+    let mut repaired = Vec::new();
+    for (target, chain) in chains.iter() {
+        let integrity = unsafe { reapply_chain(*target, chain, options)? };
+        if integrity != HookIntegrity::Intact {
+            repaired.push((
+                unsafe { NonNull::new_unchecked(*target as *mut c_void) },
+                integrity,
+            ));
+        }
+    }
+    Ok(repaired)
+}
+
+extern "C" fn remove_all_at_exit() {
+    if let Err(error) = unsafe { remove_all() } {
+        let policy = *ATEXIT_POLICY
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        policy.handle(error);
+    }
+}
+
+unsafe extern "C" {
+    fn atexit(callback: extern "C" fn()) -> i32;
+}
+
+// Removes every hook when the process exits normally, so code that runs
+// during shutdown no longer reaches detours that may already be torn down.
+// A failure is handled by `policy`, where `Panic` aborts like `Abort` since
+// it can't unwind out of the exit handler. Installing it again only changes
+// the policy.
+pub fn install_atexit_handler(policy: UnhookFailurePolicy) {
+    *ATEXIT_POLICY
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = policy;
+    if !ATEXIT_INSTALLED.swap(true, Ordering::AcqRel) {
+        unsafe { atexit(remove_all_at_exit) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Hook;
+
+    #[inline(never)]
+    extern "C" fn tracked() -> i32 {
+        std::hint::black_box(3)
+    }
+
+    extern "C" fn hooked_tracked() -> i32 {
+        4
+    }
+
+    #[test]
+    fn hooks_are_listed_until_freed() {
+        let target = tracked as *mut u8;
+        let listed = || {
+            super::hooks()
+                .into_iter()
+                .filter(|info| info.target.as_ptr() as *mut u8 == target)
+                .collect::<Vec<_>>()
+        };
+
+        let mut hook = unsafe { Hook::create(target, hooked_tracked as *mut u8).unwrap() };
+        let info = listed();
+        assert_eq!(info.len(), 1);
+        assert!(!info[0].applied);
+        assert!(info[0].module.is_some());
+
+        unsafe { hook.apply_hook().unwrap() };
+        assert!(listed()[0].applied);
+        assert!(hook.info().unwrap().applied);
+
+        unsafe {
+            hook.remove_hook().unwrap();
+            hook.free().unwrap();
+        }
+        assert!(listed().is_empty());
+    }
+
+    #[inline(never)]
+    extern "C" fn ordered() -> i32 {
+        std::hint::black_box(5)
+    }
+
+    #[test]
+    fn hooks_are_listed_in_creation_order() {
+        let target = ordered as *mut u8;
+        let listed = || {
+            super::hooks()
+                .into_iter()
+                .filter(|info| info.target.as_ptr() as *mut u8 == target)
+                .map(|info| info.trampoline)
+                .collect::<Vec<_>>()
+        };
+
+        unsafe {
+            let first = Hook::create(target, hooked_tracked as *mut u8).unwrap();
+            let second = Hook::create(target, hooked_tracked as *mut u8).unwrap();
+            // The third hook may reuse the table of the first one
+            first.free().unwrap();
+            let third = Hook::create(target, hooked_tracked as *mut u8).unwrap();
+
+            let expected = [&second, &third].map(|hook| hook.info().unwrap().trampoline);
+            assert_eq!(listed(), expected);
+
+            second.free().unwrap();
+            third.free().unwrap();
+        }
+    }
+}
@@ -0,0 +1,651 @@
+use core::arch::naked_asm;
+use core::ptr::NonNull;
+use std::cell::UnsafeCell;
+use std::ffi::c_void;
+use std::ops::Range;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, compiler_fence};
+use std::sync::{Mutex, PoisonError};
+
+use crate::asm::AssemblyError;
+use crate::asm::inner::xsave_area_size;
+use crate::control::HookControl;
+use crate::instrument::{Arguments, Probes, ReturnValue};
+use crate::threads::{StoppedThread, SuspendedThreads};
+
+// Timed or probed calls nested deeper than this on one thread run untimed
+// and without probes, their return address is left alone.
+const SHADOW_STACK_DEPTH: usize = 256;
+
+// Read by `extended_exit_stub`, set before the first hook that uses it
+static XSAVE_AREA_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+// Every exit stub saves the return registers this far below the slot it
+// returns through, which is where the return address of the hooked call was
+const RETURN_SLOT_OFFSET: usize = 56;
+
+#[derive(Clone, Copy)]
+struct Frame {
+    control: *const HookControl,
+    // Where the return address was, frames deeper than the returning call
+    // were skipped by a longjmp
+    return_slot: usize,
+    return_address: usize,
+    start: u64,
+    // `on_exit` of the hook's probes is due
+    probed: bool,
+}
+
+impl Frame {
+    const EMPTY: Self = Self {
+        control: std::ptr::null(),
+        return_slot: 0,
+        return_address: 0,
+        start: 0,
+        probed: false,
+    };
+}
+
+// Hooked calls that haven't returned yet on one thread that
+// `original_function_ptr` can tell apart. Recursing deeper forgets the
+// outermost ones.
+const ENTERED_DEPTH: usize = 256;
+
+#[derive(Clone, Copy)]
+struct EnteredCall {
+    control: *const HookControl,
+    // Read on entry, only the control has to outlive the call
+    original: usize,
+    // The return address of the call and where it is. Once the slot is at
+    // or below the stack pointer or holds anything else, the call returned.
+    return_slot: usize,
+    return_address: usize,
+}
+
+impl EnteredCall {
+    const EMPTY: Self = Self {
+        control: std::ptr::null(),
+        original: 0,
+        return_slot: 0,
+        return_address: 0,
+    };
+
+    // `stack_pointer` is below every frame that is still running, so only
+    // stack that is in use is read
+    fn returned(&self, stack_pointer: usize) -> bool {
+        self.return_slot <= stack_pointer
+            || unsafe { (self.return_slot as *const usize).read_volatile() } != self.return_address
+    }
+}
+
+// Hooked calls entered on this thread, outermost first. Nothing is removed
+// when a call returns, the calls that returned are dropped whenever the
+// stack is looked at. Every call holds one count of its hook's calls in
+// flight until it is dropped. Calls on another stack of the thread, like a
+// signal handler on an alternate stack, hide the ones on the stack it
+// interrupted, which then no longer keep their hook from being freed.
+struct EnteredCalls {
+    calls: [EnteredCall; ENTERED_DEPTH],
+    len: usize,
+    // Set while the calls change, `inspect_stopped` can interrupt that
+    busy: bool,
+}
+
+impl EnteredCalls {
+    fn set_busy(&mut self, busy: bool) {
+        compiler_fence(Ordering::SeqCst);
+        unsafe { (&raw mut self.busy).write_volatile(busy) };
+        compiler_fence(Ordering::SeqCst);
+    }
+
+    // Every call after the first one that returned was made inside of it
+    fn drop_returned(&mut self, stack_pointer: usize) {
+        if let Some(returned) = self.calls[..self.len]
+            .iter()
+            .position(|call| call.returned(stack_pointer))
+        {
+            self.truncate(returned);
+        }
+    }
+
+    fn truncate(&mut self, len: usize) {
+        for call in &self.calls[len..self.len] {
+            unsafe { (*call.control).finish_call() };
+        }
+        self.len = len;
+    }
+
+    fn innermost(&mut self) -> Option<EnteredCall> {
+        let stack_pointer: usize;
+        unsafe {
+            core::arch::asm!(
+                "mov {}, rsp",
+                out(reg) stack_pointer,
+                options(nomem, nostack, preserves_flags),
+            );
+        }
+        self.set_busy(true);
+        self.drop_returned(stack_pointer);
+        self.set_busy(false);
+        self.len.checked_sub(1).map(|last| self.calls[last])
+    }
+}
+
+// No destructor, so it can be used until the thread is gone and from a
+// signal handler
+thread_local! {
+    static ENTERED_CALLS: UnsafeCell<EnteredCalls> = const {
+        UnsafeCell::new(EnteredCalls {
+            calls: [EnteredCall::EMPTY; ENTERED_DEPTH],
+            len: 0,
+            busy: false,
+        })
+    };
+}
+
+// Ends the calls a thread still has recorded when it exits. Calls made
+// after this is gone aren't recorded.
+struct EnteredCallsOwner;
+
+impl Drop for EnteredCallsOwner {
+    fn drop(&mut self) {
+        ENTERED_CALLS.with(|entered| {
+            let entered = unsafe { &mut *entered.get() };
+            entered.set_busy(true);
+            entered.truncate(0);
+            entered.set_busy(false);
+        });
+    }
+}
+
+thread_local! {
+    static ENTERED_CALLS_OWNER: EnteredCallsOwner = const { EnteredCallsOwner };
+}
+
+// What `inspect_stopped` looks for, one query at a time
+static RUNNING_QUERY: Mutex<()> = Mutex::new(());
+static QUERY_START: AtomicUsize = AtomicUsize::new(0);
+static QUERY_END: AtomicUsize = AtomicUsize::new(0);
+static QUERY_FOUND: AtomicBool = AtomicBool::new(false);
+
+// A stopped thread in the middle of recording a call looks like it runs the
+// hook, it is usually done by the next check
+const RUNNING_CHECKS: usize = 8;
+
+// Return addresses replaced by `exit_stub`. Fixed size so hooking the
+// allocator can't recurse, and without a destructor so it is usable until
+// the thread is gone.
+struct ShadowStack {
+    frames: [Frame; SHADOW_STACK_DEPTH],
+    depth: usize,
+    // Set while probes run, hooks they call into aren't probed
+    in_probe: bool,
+    // Frame of the call the running probe is for
+    probe_depth: usize,
+}
+
+thread_local! {
+    static SHADOW_STACK: UnsafeCell<ShadowStack> = const {
+        UnsafeCell::new(ShadowStack {
+            frames: [Frame::EMPTY; SHADOW_STACK_DEPTH],
+            depth: 0,
+            in_probe: false,
+            probe_depth: 0,
+        })
+    };
+}
+
+// Size of the buffer to save the extended state into
+pub(crate) fn extended_state_size() -> crate::asm::Result<usize> {
+    let size = xsave_area_size().ok_or(AssemblyError::XsaveUnsupported)?;
+    XSAVE_AREA_SIZE.store(size, Ordering::Relaxed);
+    Ok(size)
+}
+
+fn timestamp() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+// Probes of `control` that should run on this thread
+unsafe fn probes(control: &HookControl, stack: *const ShadowStack) -> Option<&Probes> {
+    if unsafe { (*stack).in_probe } || !control.is_enabled() {
+        return None;
+    }
+    control.context()?.get::<Probes>()
+}
+
+// Runs a probe for the call in frame `depth`. The stack is only accessed
+// through raw pointers since probes can call into other hooks. A panic can't
+// unwind into the hooked function, it stops at the probe once the panic hook
+// reported it.
+unsafe fn run_probe(stack: *mut ShadowStack, depth: usize, probe: impl FnOnce()) {
+    unsafe {
+        (*stack).in_probe = true;
+        (*stack).probe_depth = depth;
+    }
+    let _ = std::panic::catch_unwind(AssertUnwindSafe(probe));
+    unsafe { (*stack).in_probe = false };
+}
+
+// Called by the trampoline with the argument registers saved. `return_slot`
+// points at the return address of the hooked call, it is only replaced for
+// calls that are timed or probed.
+pub(crate) unsafe extern "C" fn hook_enter(control: *const HookControl, return_slot: *mut usize) {
+    SHADOW_STACK.with(|stack| {
+        let stack = stack.get();
+        let control = unsafe { &*control };
+        let probes = unsafe { probes(control, stack) };
+        if !control.measures_latency() && probes.is_none() {
+            return;
+        }
+
+        let depth = unsafe { (*stack).depth };
+        if depth == SHADOW_STACK_DEPTH {
+            return;
+        }
+        unsafe {
+            (*stack).frames[depth] = Frame {
+                control,
+                return_slot: return_slot as usize,
+                return_address: *return_slot,
+                start: if control.measures_latency() {
+                    timestamp()
+                } else {
+                    0
+                },
+                probed: probes.is_some(),
+            };
+            (*stack).depth += 1;
+        }
+
+        if let Some(probes) = probes {
+            let arguments = unsafe { Arguments::from_return_slot(return_slot) };
+            unsafe { run_probe(stack, depth, || (probes.on_enter)(&arguments)) };
+        }
+        let exit_stub = if control.saves_extended_state() {
+            extended_exit_stub as *const ()
+        } else {
+            exit_stub as *const ()
+        };
+        unsafe { *return_slot = exit_stub as usize };
+    });
+    unsafe { enter_call(&*control, return_slot) };
+}
+
+// Called with the saved return registers, returns where the hooked call has
+// to return to
+extern "C" fn hook_exit(value: *const ReturnValue) -> usize {
+    let end = timestamp();
+    let return_slot = value as usize + RETURN_SLOT_OFFSET;
+    SHADOW_STACK.with(|stack| {
+        let stack = stack.get();
+        // Calls a longjmp jumped out of never return, their frames are
+        // dropped without being timed or probed
+        let frame = loop {
+            let frame = unsafe {
+                (*stack).depth -= 1;
+                (*stack).frames[(*stack).depth]
+            };
+            if frame.return_slot >= return_slot || unsafe { (*stack).depth } == 0 {
+                break frame;
+            }
+        };
+
+        let control = unsafe { &*frame.control };
+        if control.measures_latency() {
+            control.record_latency(end.wrapping_sub(frame.start));
+        }
+        if let Some(probes) = control
+            .context()
+            .and_then(|context| context.get::<Probes>())
+            && frame.probed
+        {
+            unsafe {
+                run_probe(stack, (*stack).depth, || (probes.on_exit)(&*value));
+            }
+        }
+        frame.return_address
+    })
+}
+
+// Shadow stack depth of the call whose probe is running. The enter and exit
+// probe of a call see the same depth, no other unfinished call on the thread
+// has it.
+pub(crate) fn probe_depth() -> usize {
+    SHADOW_STACK.with(|stack| unsafe { (*stack.get()).probe_depth })
+}
+
+// Records the call for `current_original`, after `hook_enter` replaced its
+// return address if it had to. Takes over the count of the call in flight
+// from the trampoline.
+unsafe fn enter_call(control: &HookControl, return_slot: *mut usize) {
+    if ENTERED_CALLS_OWNER.try_with(|_| ()).is_err() {
+        control.finish_call();
+        return;
+    }
+    ENTERED_CALLS.with(|entered| {
+        let entered = unsafe { &mut *entered.get() };
+        entered.set_busy(true);
+        // The original is read while busy, a hook skipped over after this
+        // can still be found in the call
+        let call = EnteredCall {
+            control,
+            original: control.original().as_ptr() as usize,
+            return_slot: return_slot as usize,
+            return_address: unsafe { *return_slot },
+        };
+        entered.drop_returned(call.return_slot);
+        if entered.len > 0 && entered.calls[entered.len - 1].control == call.control {
+            // Recursion into the same hook finds the same original and ends
+            // before the call it recursed from
+            control.finish_call();
+        } else {
+            if entered.len == ENTERED_DEPTH {
+                // Its hook stays in flight and is never freed
+                entered.calls.copy_within(1.., 0);
+                entered.len -= 1;
+            }
+            entered.calls[entered.len] = call;
+            entered.len += 1;
+        }
+        entered.set_busy(false);
+    });
+}
+
+// Where the innermost tracked hooked call on this thread that hasn't
+// returned yet continues to
+pub(crate) fn current_original() -> Option<NonNull<c_void>> {
+    let call = ENTERED_CALLS
+        .try_with(|entered| unsafe { (*entered.get()).innermost() })
+        .ok()??;
+    NonNull::new(call.original as *mut c_void)
+}
+
+// Calls recorded on this thread, returned or not
+#[cfg(test)]
+pub(crate) fn entered_call_count() -> usize {
+    ENTERED_CALLS.with(|entered| unsafe { (*entered.get()).len })
+}
+
+// Whether a thread may still run `code` of a removed hook or continue
+// into it. Only hooks that call `hook_enter` have their calls counted.
+// Without a way to stop the other threads it can't be ruled out.
+pub(crate) fn hook_running(control: &HookControl, code: Range<usize>) -> bool {
+    (0..RUNNING_CHECKS).all(|_| hook_running_once(control, code.clone()))
+}
+
+fn hook_running_once(control: &HookControl, code: Range<usize>) -> bool {
+    let _query = RUNNING_QUERY.lock().unwrap_or_else(PoisonError::into_inner);
+    QUERY_START.store(code.start, Ordering::Release);
+    QUERY_END.store(code.end, Ordering::Release);
+    QUERY_FOUND.store(false, Ordering::Release);
+
+    let continues_here = ENTERED_CALLS.with(|entered| {
+        let entered = unsafe { &mut *entered.get() };
+        entered.innermost();
+        entered.calls[..entered.len]
+            .iter()
+            .any(|call| code.contains(&call.original))
+    });
+    // Every other thread ended its returned calls once this succeeded
+    let Ok(_suspended) = SuspendedThreads::suspend_others_with(inspect_stopped) else {
+        return true;
+    };
+    continues_here || QUERY_FOUND.load(Ordering::Acquire) || control.calls_in_flight() != 0
+}
+
+// Runs in the signal handler of every thread `hook_running` stopped. Ends
+// its calls that returned and looks for the queried code in its registers
+// and the originals of its calls.
+fn inspect_stopped(thread: &StoppedThread) {
+    let code = QUERY_START.load(Ordering::Acquire)..QUERY_END.load(Ordering::Acquire);
+    let in_registers = code.contains(&thread.instruction_pointer)
+        || thread
+            .general_registers
+            .iter()
+            .any(|register| code.contains(register));
+    let in_calls = ENTERED_CALLS
+        .try_with(|entered| {
+            let entered = entered.get();
+            // The thread was stopped in the middle of changing its calls
+            if unsafe { (&raw const (*entered).busy).read_volatile() } {
+                return true;
+            }
+            let entered = unsafe { &mut *entered };
+            entered.drop_returned(thread.stack_pointer);
+            entered.calls[..entered.len]
+                .iter()
+                .any(|call| code.contains(&call.original))
+        })
+        .unwrap_or(true);
+    if in_registers || in_calls {
+        QUERY_FOUND.store(true, Ordering::Release);
+    }
+}
+
+// Hook of the innermost hooked call on this thread that hasn't returned
+// yet, valid while that call runs
+pub(crate) fn entered_hook() -> Option<&'static HookControl> {
+    let call = ENTERED_CALLS
+        .try_with(|entered| unsafe { (*entered.get()).innermost() })
+        .ok()??;
+    unsafe { call.control.as_ref() }
+}
+
+// The hooked function returns here instead of its caller. Keeps the return
+// registers intact and jumps to the real return address.
+#[cfg(not(target_os = "windows"))]
+#[unsafe(naked)]
+unsafe extern "C" fn exit_stub() {
+    naked_asm!(
+        "sub rsp, 8",
+        "push rax",
+        "push rdx",
+        "sub rsp, 40",
+        "movdqu [rsp], xmm0",
+        "movdqu [rsp + 16], xmm1",
+        "mov rdi, rsp",
+        "call {exit}",
+        "mov [rsp + 56], rax",
+        "movdqu xmm0, [rsp]",
+        "movdqu xmm1, [rsp + 16]",
+        "add rsp, 40",
+        "pop rdx",
+        "pop rax",
+        "ret",
+        exit = sym hook_exit,
+    )
+}
+
+#[cfg(target_os = "windows")]
+#[unsafe(naked)]
+unsafe extern "C" fn exit_stub() {
+    naked_asm!(
+        "sub rsp, 8",
+        "push rax",
+        "push rdx",
+        "sub rsp, 72",
+        "movdqu [rsp + 32], xmm0",
+        "movdqu [rsp + 48], xmm1",
+        "lea rcx, [rsp + 32]",
+        "call {exit}",
+        "mov [rsp + 88], rax",
+        "movdqu xmm0, [rsp + 32]",
+        "movdqu xmm1, [rsp + 48]",
+        "add rsp, 72",
+        "pop rdx",
+        "pop rax",
+        "ret",
+        exit = sym hook_exit,
+    )
+}
+
+// `exit_stub` that also preserves the extended state, like the trampolines of
+// hooks created with `HookWriter::with_extended_state`
+#[cfg(not(target_os = "windows"))]
+#[unsafe(naked)]
+unsafe extern "C" fn extended_exit_stub() {
+    naked_asm!(
+        "sub rsp, 8",
+        "push rax",
+        "push rdx",
+        "sub rsp, 40",
+        "movdqu [rsp], xmm0",
+        "movdqu [rsp + 16], xmm1",
+        "push rbp",
+        "mov rbp, rsp",
+        "and rsp, -64",
+        "sub rsp, [rip + {size}]",
+        "mov qword ptr [rsp + 512], 0",
+        "mov qword ptr [rsp + 520], 0",
+        "mov qword ptr [rsp + 528], 0",
+        "mov qword ptr [rsp + 536], 0",
+        "mov qword ptr [rsp + 544], 0",
+        "mov qword ptr [rsp + 552], 0",
+        "mov qword ptr [rsp + 560], 0",
+        "mov qword ptr [rsp + 568], 0",
+        "mov eax, -1",
+        "mov edx, -1",
+        "xsave64 [rsp]",
+        "lea rdi, [rbp + 8]",
+        "call {exit}",
+        "mov [rbp + 64], rax",
+        "mov eax, -1",
+        "mov edx, -1",
+        "xrstor64 [rsp]",
+        "mov rsp, rbp",
+        "pop rbp",
+        "add rsp, 40",
+        "pop rdx",
+        "pop rax",
+        "ret",
+        size = sym XSAVE_AREA_SIZE,
+        exit = sym hook_exit,
+    )
+}
+
+#[cfg(target_os = "windows")]
+#[unsafe(naked)]
+unsafe extern "C" fn extended_exit_stub() {
+    naked_asm!(
+        "sub rsp, 8",
+        "push rax",
+        "push rdx",
+        "sub rsp, 72",
+        "movdqu [rsp + 32], xmm0",
+        "movdqu [rsp + 48], xmm1",
+        "push rbp",
+        "mov rbp, rsp",
+        "and rsp, -64",
+        "sub rsp, [rip + {size}]",
+        "mov qword ptr [rsp + 512], 0",
+        "mov qword ptr [rsp + 520], 0",
+        "mov qword ptr [rsp + 528], 0",
+        "mov qword ptr [rsp + 536], 0",
+        "mov qword ptr [rsp + 544], 0",
+        "mov qword ptr [rsp + 552], 0",
+        "mov qword ptr [rsp + 560], 0",
+        "mov qword ptr [rsp + 568], 0",
+        "mov eax, -1",
+        "mov edx, -1",
+        "xsave64 [rsp]",
+        "lea rcx, [rbp + 40]",
+        "sub rsp, 32",
+        "call {exit}",
+        "add rsp, 32",
+        "mov [rbp + 96], rax",
+        "mov eax, -1",
+        "mov edx, -1",
+        "xrstor64 [rsp]",
+        "mov rsp, rbp",
+        "pop rbp",
+        "add rsp, 72",
+        "pop rdx",
+        "pop rax",
+        "ret",
+        size = sym XSAVE_AREA_SIZE,
+        exit = sym hook_exit,
+    )
+}
+
+// Destination of instrumented hooks, the trampoline loads the original into
+// r10
+#[unsafe(naked)]
+pub(crate) unsafe extern "C" fn jump_to_original() {
+    naked_asm!("jmp r10")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Like the trampoline does
+    unsafe fn enter(control: &HookControl, return_slot: *mut usize) {
+        unsafe { (*control.in_flight_counter()).fetch_add(1, Ordering::Relaxed) };
+        unsafe { hook_enter(control, return_slot) };
+    }
+
+    #[test]
+    fn entered_calls_end_with_their_frame() {
+        let mut originals = [0x1111usize, 0x2222];
+        let [outer, inner] = originals
+            .each_mut()
+            .map(|original| HookControl::leak(NonNull::from(original).cast(), false, false, None));
+        let original = |value: usize| NonNull::new(value as *mut c_void);
+        // Return slots of an outer call and of one nested in it, the stack
+        // grows down
+        let mut slots = [0usize; 16];
+        slots[12] = 0x1212;
+        slots[4] = 0x0404;
+
+        unsafe {
+            enter(outer, &mut slots[12]);
+            assert_eq!(current_original(), original(0x1111));
+            enter(inner, &mut slots[4]);
+            assert_eq!(current_original(), original(0x2222));
+            // Recursing into the same hook doesn't use up the stack
+            enter(inner, &mut slots[2]);
+            assert_eq!(entered_call_count(), 2);
+            assert_eq!(inner.calls_in_flight(), 1);
+
+            // Another call reused the slot of the inner one
+            std::hint::black_box(&mut slots)[4] = 0x0505;
+            assert_eq!(current_original(), original(0x1111));
+            assert_eq!(inner.calls_in_flight(), 0);
+            std::hint::black_box(&mut slots)[12] = 0;
+            assert_eq!(current_original(), None);
+            assert_eq!(outer.calls_in_flight(), 0);
+
+            HookControl::free(outer);
+            HookControl::free(inner);
+        }
+    }
+
+    #[test]
+    fn exits_drop_frames_skipped_by_longjmp() {
+        let mut original = 0x7777usize;
+        let control = HookControl::leak(NonNull::from(&mut original).cast(), true, false, None);
+        // Return slots of an outer call and of one nested in it that a
+        // longjmp left, the stack grows down
+        let mut slots = [0usize; 16];
+        slots[12] = 0x1212;
+        slots[4] = 0x0404;
+
+        unsafe {
+            enter(control, &mut slots[12]);
+            enter(control, &mut slots[4]);
+        }
+        let value =
+            (&slots[12] as *const usize as usize - RETURN_SLOT_OFFSET) as *const ReturnValue;
+        assert_eq!(hook_exit(value), 0x1212);
+
+        SHADOW_STACK.with(|stack| assert_eq!(unsafe { (*stack.get()).depth }, 0));
+        assert_eq!(control.stats().timed_calls, 1);
+
+        // The outer call returned as well
+        std::hint::black_box(&mut slots)[12] = 0;
+        assert_eq!(current_original(), None);
+        assert_eq!(control.calls_in_flight(), 0);
+        unsafe { HookControl::free(control) };
+    }
+}
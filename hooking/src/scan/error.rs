@@ -0,0 +1,21 @@
+use thiserror::Error;
+
+pub type Result<T> = std::result::Result<T, ScanError>;
+
+#[derive(Debug, Error)]
+pub enum ScanError {
+    #[error("Invalid pattern token \"{0}\"")]
+    InvalidToken(String),
+
+    #[error("Pattern has no bytes")]
+    EmptyPattern,
+
+    #[error("Mask is {mask} long but the pattern has {bytes} bytes")]
+    MaskLength { bytes: usize, mask: usize },
+
+    #[error("Pattern {0} was not found")]
+    NotFound(String),
+
+    #[error("Pattern {0} was found more than once")]
+    NotUnique(String),
+}
@@ -0,0 +1,256 @@
+pub mod error;
+pub use error::ScanError;
+
+use core::fmt;
+use core::ops::Range;
+use core::ptr::NonNull;
+use core::str::FromStr;
+use std::ffi::{CStr, c_void};
+
+use crate::mem::{DefaultMemoryController, MemoryController};
+use error::Result;
+
+// Bytes to search for, `None` matches any byte
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    bytes: Vec<Option<u8>>,
+}
+
+impl Pattern {
+    // IDA style, "48 8B ?? ?? E8 ? ? ? ?"
+    pub fn parse(pattern: &str) -> Result<Self> {
+        let bytes = pattern
+            .split_whitespace()
+            .map(|token| match token {
+                "?" | "??" => Ok(None),
+                token if token.len() == 2 => u8::from_str_radix(token, 16)
+                    .map(Some)
+                    .map_err(|_| ScanError::InvalidToken(token.into())),
+                token => Err(ScanError::InvalidToken(token.into())),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Self::from_bytes(bytes)
+    }
+
+    // Code style, `b"\x48\x8B\x00\x00"` with "xx??". Bytes under a `?` are
+    // ignored.
+    pub fn from_mask(bytes: &[u8], mask: &str) -> Result<Self> {
+        if bytes.len() != mask.len() {
+            return Err(ScanError::MaskLength {
+                bytes: bytes.len(),
+                mask: mask.len(),
+            });
+        }
+        let bytes = bytes
+            .iter()
+            .zip(mask.chars())
+            .map(|(byte, mask)| match mask {
+                'x' => Ok(Some(*byte)),
+                '?' => Ok(None),
+                mask => Err(ScanError::InvalidToken(mask.into())),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Self::from_bytes(bytes)
+    }
+
+    pub fn from_bytes(bytes: Vec<Option<u8>>) -> Result<Self> {
+        if bytes.is_empty() {
+            return Err(ScanError::EmptyPattern);
+        }
+        Ok(Self { bytes })
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    // Whether `data` starts with the pattern
+    pub fn matches(&self, data: &[u8]) -> bool {
+        data.len() >= self.bytes.len()
+            && self
+                .bytes
+                .iter()
+                .zip(data)
+                .all(|(expected, byte)| expected.is_none_or(|expected| expected == *byte))
+    }
+
+    // Offsets of every match in `haystack`
+    pub fn find_iter<'a>(&'a self, haystack: &'a [u8]) -> impl Iterator<Item = usize> + 'a {
+        // Only positions holding the first fixed byte can start a match
+        let anchor = self.bytes.iter().position(Option::is_some);
+        let candidates = haystack.len().saturating_sub(self.bytes.len() - 1);
+
+        (0..candidates).filter(move |start| {
+            anchor.is_none_or(|anchor| Some(haystack[start + anchor]) == self.bytes[anchor])
+                && self.matches(&haystack[*start..])
+        })
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = ScanError;
+
+    fn from_str(pattern: &str) -> Result<Self> {
+        Self::parse(pattern)
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, byte) in self.bytes.iter().enumerate() {
+            if index != 0 {
+                f.write_str(" ")?;
+            }
+            match byte {
+                Some(byte) => write!(f, "{byte:02X}")?,
+                None => f.write_str("??")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// Start of a pattern found in memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Match {
+    address: NonNull<c_void>,
+}
+
+impl Match {
+    pub fn address(&self) -> NonNull<c_void> {
+        self.address
+    }
+
+    pub fn offset(&self, offset: usize) -> NonNull<c_void> {
+        unsafe { self.address.byte_add(offset) }
+    }
+
+    /// Resolves a rel32 operand `operand` bytes into the match, relative to
+    /// the end of its instruction `instruction_end` bytes into the match.
+    ///
+    /// # Safety
+    /// The operand has to be readable.
+    pub unsafe fn rel32(&self, operand: usize, instruction_end: usize) -> NonNull<c_void> {
+        let displacement =
+            unsafe { (self.offset(operand).as_ptr() as *const i32).read_unaligned() };
+        unsafe {
+            self.offset(instruction_end)
+                .byte_offset(displacement as isize)
+        }
+    }
+
+    /// Target of the `call rel32` or `jmp rel32` `offset` bytes into the match.
+    ///
+    /// # Safety
+    /// There has to be a rel32 call or jump at `offset`.
+    pub unsafe fn branch_target(&self, offset: usize) -> NonNull<c_void> {
+        unsafe { self.rel32(offset + 1, offset + 5) }
+    }
+}
+
+/// Every match of `pattern` in `region`.
+///
+/// # Safety
+/// The whole region has to be readable.
+pub unsafe fn scan_region(region: Range<usize>, pattern: &Pattern) -> Vec<Match> {
+    let data = unsafe { core::slice::from_raw_parts(region.start as *const u8, region.len()) };
+    pattern
+        .find_iter(data)
+        .filter_map(|offset| NonNull::new((region.start + offset) as *mut c_void))
+        .map(|address| Match { address })
+        .collect()
+}
+
+/// Every match of `pattern` in the executable segments of a loaded module,
+/// `None` being the main executable.
+///
+/// # Safety
+/// The module can't be unloaded during the scan.
+pub unsafe fn find_all(
+    module: Option<&CStr>,
+    pattern: &Pattern,
+) -> crate::error::Result<Vec<Match>> {
+    let mem = DefaultMemoryController::new();
+    let regions = unsafe { mem.get_module_code_regions(module)? };
+    Ok(regions
+        .into_iter()
+        .flat_map(|region| unsafe { scan_region(region, pattern) })
+        .collect())
+}
+
+/// The only match of `pattern` in a loaded module. Errors if the pattern is
+/// missing or ambiguous.
+///
+/// # Safety
+/// The module can't be unloaded during the scan.
+pub unsafe fn find(module: Option<&CStr>, pattern: &Pattern) -> crate::error::Result<Match> {
+    match unsafe { find_all(module, pattern)? }.as_slice() {
+        [found] => Ok(*found),
+        [] => Err(ScanError::NotFound(pattern.to_string()).into()),
+        _ => Err(ScanError::NotUnique(pattern.to_string()).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns_parse_and_match() {
+        let pattern = Pattern::parse("48 8B ?? ? E8").unwrap();
+        assert_eq!(pattern.to_string(), "48 8B ?? ?? E8");
+        assert_eq!(
+            pattern,
+            Pattern::from_mask(b"\x48\x8B\x00\x00\xE8", "xx??x").unwrap()
+        );
+        assert!(Pattern::parse("48 8G").is_err());
+        assert!(Pattern::parse("").is_err());
+        assert!(Pattern::from_mask(b"\x48", "xx").is_err());
+
+        let haystack: [u8; 10] = [0x90, 0x48, 0x8B, 0x01, 0x02, 0xE8, 0x48, 0x8B, 0x03, 0x04];
+        assert_eq!(pattern.find_iter(&haystack).collect::<Vec<_>>(), [1]);
+        let wildcard_start = Pattern::parse("?? 8B").unwrap();
+        assert_eq!(
+            wildcard_start.find_iter(&haystack).collect::<Vec<_>>(),
+            [1, 6]
+        );
+    }
+
+    #[test]
+    fn branch_targets_are_resolved() {
+        // nop; call +0x10
+        let code: [u8; 6] = [0x90, 0xE8, 0x10, 0x00, 0x00, 0x00];
+        let pattern = Pattern::parse("90 E8 ?? ?? ?? ??").unwrap();
+        let region = code.as_ptr() as usize..code.as_ptr() as usize + code.len();
+
+        let found = unsafe { scan_region(region, &pattern) };
+        assert_eq!(found.len(), 1);
+        assert_eq!(
+            unsafe { found[0].branch_target(1) }.as_ptr() as usize,
+            code.as_ptr() as usize + 6 + 0x10
+        );
+    }
+
+    #[inline(never)]
+    extern "C" fn scanned(value: u64) -> u64 {
+        std::hint::black_box(value.rotate_left(13) ^ 0x5EED_5EED_5EED_5EED)
+    }
+
+    #[test]
+    fn modules_are_scanned_for_code() {
+        let address = scanned as *const () as usize;
+        let code = unsafe { core::slice::from_raw_parts(address as *const u8, 16) };
+        let pattern = Pattern::from_bytes(code.iter().copied().map(Some).collect()).unwrap();
+
+        let found = unsafe { find_all(None, &pattern).unwrap() };
+        assert!(
+            found
+                .iter()
+                .any(|found| found.address().as_ptr() as usize == address)
+        );
+    }
+}
@@ -0,0 +1,277 @@
+use core::ptr::NonNull;
+use std::ffi::c_void;
+use std::sync::atomic::AtomicUsize;
+
+use crate::asm::{HookAssembler, ReturnStub};
+use crate::control::HookContext;
+use crate::error::{HookingError, Result};
+use crate::hooks::{Hook, HookWriter, Trampoline};
+use crate::mem::{DefaultMemoryController, MemoryController};
+
+// Values a stub can return, passed as the bits of rax and xmm0
+pub trait StubValue {
+    fn into_bits(self) -> u64;
+}
+
+macro_rules! impl_stub_value {
+    ($($type:ty),*) => {
+        $(impl StubValue for $type {
+            fn into_bits(self) -> u64 {
+                // Signed values are sign extended to the whole register
+                self as i64 as u64
+            }
+        })*
+    };
+}
+
+impl_stub_value!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize, bool);
+
+impl StubValue for f32 {
+    fn into_bits(self) -> u64 {
+        self.to_bits() as u64
+    }
+}
+
+impl StubValue for f64 {
+    fn into_bits(self) -> u64 {
+        self.to_bits()
+    }
+}
+
+impl<T> StubValue for *const T {
+    fn into_bits(self) -> u64 {
+        self as usize as u64
+    }
+}
+
+impl<T> StubValue for *mut T {
+    fn into_bits(self) -> u64 {
+        self as usize as u64
+    }
+}
+
+// What a stubbed function returns instead of running
+#[derive(Debug, Clone, PartialEq)]
+pub struct StubReturn {
+    values: Vec<u64>,
+    errno: Option<i32>,
+}
+
+impl StubReturn {
+    pub fn value(value: impl StubValue) -> Self {
+        Self::sequence([value])
+    }
+
+    // One value per call, the last one repeats. Panics without any values.
+    pub fn sequence<V: StubValue>(values: impl IntoIterator<Item = V>) -> Self {
+        let values = values
+            .into_iter()
+            .map(StubValue::into_bits)
+            .collect::<Vec<_>>();
+        assert!(!values.is_empty(), "a stub needs at least one value");
+        Self {
+            values,
+            errno: None,
+        }
+    }
+
+    // Also sets errno of the calling thread on every call
+    pub fn with_errno(mut self, errno: i32) -> Self {
+        self.errno = Some(errno);
+        self
+    }
+}
+
+impl<V: StubValue> From<V> for StubReturn {
+    fn from(value: V) -> Self {
+        Self::value(value)
+    }
+}
+
+// Context of a stub hook, only read by the stub itself
+struct StubState {
+    values: Box<[u64]>,
+    next: AtomicUsize,
+}
+
+#[cfg(target_os = "linux")]
+unsafe fn errno_fn() -> Result<NonNull<c_void>> {
+    Ok(unsafe { NonNull::new_unchecked(libc::__errno_location as *const () as *mut c_void) })
+}
+
+#[cfg(windows)]
+unsafe fn errno_fn() -> Result<NonNull<c_void>> {
+    unsafe { DefaultMemoryController::new().get_symbol_address(Some(c"ucrtbase.dll"), c"_errno") }
+}
+
+impl Hook<'static, DefaultMemoryController> {
+    // Makes `target` return `value` right away without running, for mocking
+    // functions in tests. The stub is machine code, no rust runs on a call
+    // unless the hook is created by a writer that measures latency.
+    pub unsafe fn stub_return(target: *mut u8, value: impl Into<StubReturn>) -> Result<Self> {
+        let hook_writer = HookWriter::from_static();
+        unsafe {
+            hook_writer.create_return_stub(
+                NonNull::new(target as *mut _)
+                    .ok_or(HookingError::InvalidTarget(target as *const _))?,
+                value.into(),
+            )
+        }
+    }
+}
+
+impl<'a, M: MemoryController, A: HookAssembler> HookWriter<'a, M, A> {
+    // Only a writer that measures latency runs rust on a call, to time it
+    // through the return address. The original pointer mode doesn't apply.
+    pub unsafe fn create_return_stub(
+        &self,
+        target: NonNull<c_void>,
+        value: StubReturn,
+    ) -> Result<Hook<'a, M>> {
+        let errno = match value.errno {
+            Some(errno) => Some((unsafe { errno_fn()? }, errno)),
+            None => None,
+        };
+
+        // The state lives as long as the hook, the stub only has its address
+        let context = HookContext::new(StubState {
+            values: value.values.into(),
+            next: AtomicUsize::new(0),
+        });
+        let state = context.get::<StubState>().expect("stub context");
+        let stub = ReturnStub {
+            values: NonNull::from(&state.values[0]),
+            count: state.values.len(),
+            next: NonNull::from(&state.next).cast(),
+            errno,
+        };
+
+        unsafe {
+            let symbol_info = self.hook_heap.mem.get_symbol_info(target);
+            self.create_registered_trampoline(
+                target,
+                Trampoline::Return(stub),
+                symbol_info,
+                Some(context),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::{OriginalPointerMode, StatsOptions};
+    use crate::runtime;
+
+    #[inline(never)]
+    extern "C" fn answer() -> i32 {
+        std::hint::black_box(41)
+    }
+
+    #[inline(never)]
+    extern "C" fn ratio(value: f64) -> f64 {
+        std::hint::black_box(value * 0.25)
+    }
+
+    #[test]
+    fn stubs_return_constants() {
+        let answer_fn: extern "C" fn() -> i32 = std::hint::black_box(answer);
+        let mut hook = unsafe { Hook::stub_return(answer as *mut u8, -42).unwrap() };
+
+        unsafe {
+            hook.apply_hook().unwrap();
+            assert_eq!(answer_fn(), -42);
+            hook.disable();
+            assert_eq!(answer_fn(), 41);
+            hook.enable();
+            assert_eq!(answer_fn(), -42);
+            hook.remove_hook().unwrap();
+        }
+        assert_eq!(answer_fn(), 41);
+    }
+
+    #[test]
+    fn stubs_return_sequences() {
+        let ratio_fn: extern "C" fn(f64) -> f64 = std::hint::black_box(ratio);
+        let mut hook = unsafe {
+            Hook::stub_return(ratio as *mut u8, StubReturn::sequence([1.5, 2.5, -1.0])).unwrap()
+        };
+
+        unsafe {
+            hook.apply_hook().unwrap();
+            let returned = (0..5).map(|_| ratio_fn(8.0)).collect::<Vec<_>>();
+            assert_eq!(returned, [1.5, 2.5, -1.0, -1.0, -1.0]);
+            hook.remove_hook().unwrap();
+        }
+        assert_eq!(ratio_fn(8.0), 2.0);
+    }
+
+    #[inline(never)]
+    extern "C" fn sample() -> u64 {
+        std::hint::black_box(2718)
+    }
+
+    #[test]
+    fn stubs_only_take_the_return_address_to_time_calls() {
+        let sample_fn: extern "C" fn() -> u64 = std::hint::black_box(sample);
+        let target = || NonNull::new(sample as *mut c_void).unwrap();
+
+        // Tracked hooks would otherwise record the call on the thread
+        let untimed = std::thread::spawn(move || {
+            let writer = HookWriter::from_static()
+                .with_original_pointer_mode(OriginalPointerMode::Tracked)
+                .with_stats(StatsOptions::new().with_count_calls(true));
+            let mut hook = unsafe { writer.create_return_stub(target(), 7u64.into()).unwrap() };
+            unsafe {
+                hook.apply_hook().unwrap();
+                assert_eq!(sample_fn(), 7);
+                hook.remove_hook().unwrap();
+            }
+            (hook.stats(), runtime::entered_call_count() == 0)
+        })
+        .join()
+        .unwrap();
+        assert_eq!(untimed.0.calls, 1);
+        assert_eq!(untimed.0.timed_calls, 0);
+        assert!(untimed.1);
+
+        let writer =
+            HookWriter::from_static().with_stats(StatsOptions::new().with_measure_latency(true));
+        let mut hook = unsafe { writer.create_return_stub(target(), 8u64.into()).unwrap() };
+        unsafe {
+            hook.apply_hook().unwrap();
+            assert_eq!(sample_fn(), 8);
+            hook.remove_hook().unwrap();
+        }
+        assert_eq!(hook.stats().timed_calls, 1);
+        assert_eq!(sample_fn(), 2718);
+    }
+
+    #[inline(never)]
+    extern "C" fn read_byte() -> isize {
+        std::hint::black_box(63)
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn stubs_set_errno() {
+        let read_fn: extern "C" fn() -> isize = std::hint::black_box(read_byte);
+        let mut hook = unsafe {
+            Hook::stub_return(
+                read_byte as *mut u8,
+                StubReturn::value(-1).with_errno(libc::EAGAIN),
+            )
+            .unwrap()
+        };
+
+        unsafe {
+            *libc::__errno_location() = 0;
+            hook.apply_hook().unwrap();
+            assert_eq!(read_fn(), -1);
+            assert_eq!(*libc::__errno_location(), libc::EAGAIN);
+            hook.remove_hook().unwrap();
+        }
+        assert_eq!(read_fn(), 63);
+    }
+}
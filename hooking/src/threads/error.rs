@@ -0,0 +1,24 @@
+use thiserror::Error;
+
+pub type Result<T> = std::result::Result<T, ThreadError>;
+
+#[derive(Debug, Error)]
+pub enum ThreadError {
+    #[error("Suspending threads is not supported on this platform")]
+    Unsupported,
+
+    #[error("Thread suspension state is poisoned")]
+    BadSuspendState,
+
+    #[error("Failed to install the thread suspension signal handler")]
+    CantInstallHandler,
+
+    #[error("Failed to enumerate the threads of this process")]
+    CantEnumerateThreads,
+
+    #[error("More threads than the suspension table can hold ({0})")]
+    TooManyThreads(usize),
+
+    #[error("Thread {0} did not stop in time")]
+    SuspendTimeout(i64),
+}
@@ -0,0 +1,448 @@
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicPtr, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use super::super::*;
+use libc;
+
+const SUSPEND_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Real time signal (relative to SIGRTMIN) used to stop the other threads.
+const SUSPEND_SIGNAL_OFFSET: i32 = 3;
+
+const INITIAL_SLOT_COUNT: usize = 64;
+
+const SLOT_SIGNALLED: u32 = 0;
+const SLOT_STOPPED: u32 = 1;
+const SLOT_RESUMED: u32 = 2;
+const SLOT_GONE: u32 = 3;
+
+struct ThreadSlot {
+    tid: AtomicI32,
+    state: AtomicU32,
+    context: AtomicPtr<libc::ucontext_t>,
+}
+
+impl ThreadSlot {
+    const fn new() -> Self {
+        Self {
+            tid: AtomicI32::new(0),
+            state: AtomicU32::new(SLOT_SIGNALLED),
+            context: AtomicPtr::new(std::ptr::null_mut()),
+        }
+    }
+}
+
+static SUSPEND_LOCK: Mutex<()> = Mutex::new(());
+static HANDLER_INSTALLED: AtomicBool = AtomicBool::new(false);
+
+// Slot tables are never freed, a thread that was too slow to stop may
+// still be reading one long after the suspension finished.
+static SLOTS: AtomicPtr<ThreadSlot> = AtomicPtr::new(std::ptr::null_mut());
+static SLOT_CAPACITY: AtomicUsize = AtomicUsize::new(0);
+static SLOT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// Futex word the stopped threads wait on, non zero once they may continue
+static RESUME: AtomicU32 = AtomicU32::new(0);
+
+// `fn(&StoppedThread)` the stopping threads run, 0 for none
+static ON_STOP: AtomicUsize = AtomicUsize::new(0);
+
+// Registers of a thread where it was stopped
+#[derive(Debug, Clone, Copy)]
+pub struct StoppedThread {
+    pub instruction_pointer: usize,
+    pub stack_pointer: usize,
+    // rax through r15 in encoding order
+    pub general_registers: [usize; 16],
+}
+
+impl StoppedThread {
+    unsafe fn from_context(context: *const libc::ucontext_t) -> Self {
+        let registers = unsafe { &(*context).uc_mcontext.gregs };
+        let general_registers = [
+            libc::REG_RAX,
+            libc::REG_RCX,
+            libc::REG_RDX,
+            libc::REG_RBX,
+            libc::REG_RSP,
+            libc::REG_RBP,
+            libc::REG_RSI,
+            libc::REG_RDI,
+            libc::REG_R8,
+            libc::REG_R9,
+            libc::REG_R10,
+            libc::REG_R11,
+            libc::REG_R12,
+            libc::REG_R13,
+            libc::REG_R14,
+            libc::REG_R15,
+        ]
+        .map(|register| registers[register as usize] as usize);
+        Self {
+            instruction_pointer: registers[libc::REG_RIP as usize] as usize,
+            stack_pointer: registers[libc::REG_RSP as usize] as usize,
+            general_registers,
+        }
+    }
+}
+
+fn suspend_signal() -> i32 {
+    libc::SIGRTMIN() + SUSPEND_SIGNAL_OFFSET
+}
+
+// Everything in here has to be async signal safe, so no allocations or locks.
+extern "C" fn suspend_handler(_: i32, _: *mut libc::siginfo_t, context: *mut c_void) {
+    unsafe {
+        let errno = *libc::__errno_location();
+        let tid = libc::gettid();
+
+        let slots = SLOTS.load(Ordering::Acquire);
+        let count = SLOT_COUNT.load(Ordering::Acquire);
+        if !slots.is_null() {
+            let slots = std::slice::from_raw_parts(slots, count);
+            if let Some(slot) = slots
+                .iter()
+                .find(|slot| slot.tid.load(Ordering::Acquire) == tid)
+            {
+                let on_stop = ON_STOP.load(Ordering::Acquire);
+                if on_stop != 0 {
+                    let on_stop: fn(&StoppedThread) = std::mem::transmute(on_stop);
+                    on_stop(&StoppedThread::from_context(context as *const _));
+                }
+
+                slot.context
+                    .store(context as *mut libc::ucontext_t, Ordering::Release);
+                slot.state.store(SLOT_STOPPED, Ordering::Release);
+
+                while RESUME.load(Ordering::Acquire) == 0 {
+                    futex_wait(&RESUME, 0);
+                }
+
+                slot.state.store(SLOT_RESUMED, Ordering::Release);
+            }
+        }
+
+        *libc::__errno_location() = errno;
+    }
+}
+
+unsafe fn futex_wait(word: &AtomicU32, expected: u32) {
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            word.as_ptr(),
+            libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG,
+            expected,
+            std::ptr::null::<libc::timespec>(),
+        );
+    }
+}
+
+unsafe fn futex_wake_all(word: &AtomicU32) {
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            word.as_ptr(),
+            libc::FUTEX_WAKE | libc::FUTEX_PRIVATE_FLAG,
+            i32::MAX,
+        );
+    }
+}
+
+fn slot_table(capacity: usize) -> &'static [ThreadSlot] {
+    let slots = SLOTS.load(Ordering::Acquire);
+    let current_capacity = SLOT_CAPACITY.load(Ordering::Acquire);
+
+    let slots = if slots.is_null() || current_capacity < capacity {
+        let slots: &'static [ThreadSlot] =
+            Box::leak((0..capacity).map(|_| ThreadSlot::new()).collect());
+        SLOT_COUNT.store(0, Ordering::Release);
+        SLOTS.store(slots.as_ptr() as *mut _, Ordering::Release);
+        SLOT_CAPACITY.store(capacity, Ordering::Release);
+        slots
+    } else {
+        unsafe { std::slice::from_raw_parts(slots, current_capacity) }
+    };
+
+    for slot in slots {
+        slot.tid.store(0, Ordering::Release);
+        slot.state.store(SLOT_SIGNALLED, Ordering::Release);
+        slot.context.store(std::ptr::null_mut(), Ordering::Release);
+    }
+    slots
+}
+
+fn install_handler() -> Result<()> {
+    if HANDLER_INSTALLED.load(Ordering::Acquire) {
+        return Ok(());
+    }
+
+    // The handler stays installed for the lifetime of the process, a thread
+    // that was too slow to stop may still receive the signal much later.
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = suspend_handler as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+        libc::sigfillset(&mut action.sa_mask);
+
+        if libc::sigaction(suspend_signal(), &action, std::ptr::null_mut()) != 0 {
+            return Err(ThreadError::CantInstallHandler);
+        }
+    }
+
+    HANDLER_INSTALLED.store(true, Ordering::Release);
+    Ok(())
+}
+
+// Reads /proc/self/task without allocating, other threads may already be
+// stopped while holding the allocator lock.
+fn for_each_thread(mut f: impl FnMut(i32) -> Result<()>) -> Result<()> {
+    #[repr(C)]
+    struct DirentHeader {
+        d_ino: u64,
+        d_off: i64,
+        d_reclen: u16,
+        d_type: u8,
+    }
+    const NAME_OFFSET: usize = std::mem::offset_of!(DirentHeader, d_type) + 1;
+
+    let fd = unsafe {
+        libc::open(
+            c"/proc/self/task".as_ptr(),
+            libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(ThreadError::CantEnumerateThreads);
+    }
+
+    let mut buffer = [0u8; 4096];
+    let result = 'read: loop {
+        let read =
+            unsafe { libc::syscall(libc::SYS_getdents64, fd, buffer.as_mut_ptr(), buffer.len()) };
+        if read < 0 {
+            break 'read Err(ThreadError::CantEnumerateThreads);
+        }
+        if read == 0 {
+            break 'read Ok(());
+        }
+
+        let mut offset = 0;
+        while offset < read as usize {
+            let header =
+                unsafe { (buffer.as_ptr().add(offset) as *const DirentHeader).read_unaligned() };
+            let name = &buffer[offset + NAME_OFFSET..offset + header.d_reclen as usize];
+            offset += header.d_reclen as usize;
+
+            let tid = name
+                .iter()
+                .take_while(|c| c.is_ascii_digit())
+                .fold(None, |tid: Option<i32>, c| {
+                    Some(tid.unwrap_or(0) * 10 + (c - b'0') as i32)
+                });
+
+            if let Some(tid) = tid
+                && let Err(error) = f(tid)
+            {
+                break 'read Err(error);
+            }
+        }
+    };
+
+    unsafe { libc::close(fd) };
+    result
+}
+
+pub struct SuspendedThreads {
+    slots: &'static [ThreadSlot],
+    count: usize,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl SuspendedThreads {
+    pub fn suspend_others() -> Result<Self> {
+        Self::suspend_others_and_run(None)
+    }
+
+    // Every other thread runs `on_stop` in a signal handler before it stops,
+    // so it has to be async signal safe. A thread that was too slow to stop
+    // in time may still run it after this returned.
+    pub fn suspend_others_with(on_stop: fn(&StoppedThread)) -> Result<Self> {
+        Self::suspend_others_and_run(Some(on_stop))
+    }
+
+    fn suspend_others_and_run(on_stop: Option<fn(&StoppedThread)>) -> Result<Self> {
+        let mut capacity = INITIAL_SLOT_COUNT;
+        loop {
+            match Self::try_suspend_others(capacity, on_stop) {
+                Err(ThreadError::TooManyThreads(count)) => capacity = count * 4,
+                result => return result,
+            }
+        }
+    }
+
+    fn try_suspend_others(capacity: usize, on_stop: Option<fn(&StoppedThread)>) -> Result<Self> {
+        let lock = SUSPEND_LOCK
+            .lock()
+            .map_err(|_| ThreadError::BadSuspendState)?;
+        install_handler()?;
+        ON_STOP.store(
+            on_stop.map_or(0, |on_stop| on_stop as usize),
+            Ordering::Release,
+        );
+
+        RESUME.store(0, Ordering::Release);
+        SLOT_COUNT.store(0, Ordering::Release);
+        let slots = slot_table(capacity);
+
+        // From here on dropping `suspended` resumes everything that stopped
+        let mut suspended = Self {
+            slots,
+            count: 0,
+            _lock: lock,
+        };
+
+        let (pid, own_tid) = unsafe { (libc::getpid(), libc::gettid()) };
+
+        // Threads can be spawned while we are stopping the others, so keep
+        // going until a pass over /proc/self/task finds nothing new.
+        loop {
+            let mut found_new = false;
+            for_each_thread(|tid| {
+                if tid == own_tid || suspended.contains(tid) {
+                    return Ok(());
+                }
+                if suspended.count == suspended.slots.len() {
+                    return Err(ThreadError::TooManyThreads(suspended.count));
+                }
+
+                let slot = &suspended.slots[suspended.count];
+                slot.tid.store(tid, Ordering::Release);
+                suspended.count += 1;
+                SLOT_COUNT.store(suspended.count, Ordering::Release);
+
+                if unsafe { libc::tgkill(pid, tid, suspend_signal()) } != 0 {
+                    slot.state.store(SLOT_GONE, Ordering::Release);
+                }
+                found_new = true;
+                Ok(())
+            })?;
+
+            suspended.wait_until_stopped(pid)?;
+
+            if !found_new {
+                break;
+            }
+        }
+
+        Ok(suspended)
+    }
+
+    fn contains(&self, tid: i32) -> bool {
+        self.slots[..self.count]
+            .iter()
+            .any(|slot| slot.tid.load(Ordering::Acquire) == tid)
+    }
+
+    fn wait_until_stopped(&self, pid: i32) -> Result<()> {
+        let started = Instant::now();
+        for slot in &self.slots[..self.count] {
+            while slot.state.load(Ordering::Acquire) == SLOT_SIGNALLED {
+                let tid = slot.tid.load(Ordering::Acquire);
+                if unsafe { libc::tgkill(pid, tid, 0) } != 0 {
+                    slot.state.store(SLOT_GONE, Ordering::Release);
+                    break;
+                }
+                if started.elapsed() > SUSPEND_TIMEOUT {
+                    return Err(ThreadError::SuspendTimeout(tid as i64));
+                }
+                std::thread::sleep(Duration::from_micros(50));
+            }
+        }
+        Ok(())
+    }
+
+    fn stopped(&self) -> impl Iterator<Item = &ThreadSlot> {
+        self.slots[..self.count]
+            .iter()
+            .filter(|slot| slot.state.load(Ordering::Acquire) == SLOT_STOPPED)
+    }
+
+    pub fn len(&self) -> usize {
+        self.stopped().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // `f` runs while the other threads are stopped and must not allocate
+    // or take locks they might be holding.
+    pub unsafe fn for_each_instruction_pointer(&mut self, mut f: impl FnMut(&mut usize)) {
+        for slot in self.stopped() {
+            let context = slot.context.load(Ordering::Acquire);
+            unsafe {
+                let rip = &mut (*context).uc_mcontext.gregs[libc::REG_RIP as usize];
+                let mut instruction_pointer = *rip as usize;
+                f(&mut instruction_pointer);
+                *rip = instruction_pointer as i64;
+            }
+        }
+    }
+}
+
+impl Drop for SuspendedThreads {
+    fn drop(&mut self) {
+        RESUME.store(1, Ordering::Release);
+        unsafe { futex_wake_all(&RESUME) };
+
+        for slot in &self.slots[..self.count] {
+            while slot.state.load(Ordering::Acquire) == SLOT_STOPPED {
+                std::thread::yield_now();
+            }
+        }
+
+        SLOT_COUNT.store(0, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn suspends_and_resumes_other_threads() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let stop = Arc::new(AtomicBool::new(false));
+        let worker = {
+            let (counter, stop) = (counter.clone(), stop.clone());
+            std::thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+        };
+        while counter.load(Ordering::Relaxed) == 0 {
+            std::thread::yield_now();
+        }
+
+        {
+            let suspended = SuspendedThreads::suspend_others().unwrap();
+            assert!(!suspended.is_empty());
+
+            let count = counter.load(Ordering::Relaxed);
+            std::thread::sleep(Duration::from_millis(10));
+            assert_eq!(counter.load(Ordering::Relaxed), count);
+        }
+
+        let count = counter.load(Ordering::Relaxed);
+        while counter.load(Ordering::Relaxed) == count {
+            std::thread::yield_now();
+        }
+        stop.store(true, Ordering::Relaxed);
+        worker.join().unwrap();
+    }
+}
@@ -0,0 +1,32 @@
+use super::super::*;
+
+// Registers of a thread where it was stopped
+#[derive(Debug, Clone, Copy)]
+pub struct StoppedThread {
+    pub instruction_pointer: usize,
+    pub stack_pointer: usize,
+    // rax through r15 in encoding order
+    pub general_registers: [usize; 16],
+}
+
+pub struct SuspendedThreads;
+
+impl SuspendedThreads {
+    pub fn suspend_others() -> Result<Self> {
+        Err(ThreadError::Unsupported)
+    }
+
+    pub fn suspend_others_with(_on_stop: fn(&StoppedThread)) -> Result<Self> {
+        Err(ThreadError::Unsupported)
+    }
+
+    pub fn len(&self) -> usize {
+        0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        true
+    }
+
+    pub unsafe fn for_each_instruction_pointer(&mut self, _f: impl FnMut(&mut usize)) {}
+}
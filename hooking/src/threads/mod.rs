@@ -0,0 +1,15 @@
+#[cfg(target_os = "windows")]
+pub mod inner {
+    pub mod windows;
+    pub use windows::*;
+}
+
+#[cfg(target_os = "linux")]
+pub mod inner {
+    pub mod linux;
+    pub use linux::*;
+}
+
+pub mod error;
+pub use error::{Result, ThreadError};
+pub use inner::{StoppedThread, SuspendedThreads};
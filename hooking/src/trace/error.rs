@@ -0,0 +1,18 @@
+use thiserror::Error;
+
+pub type Result<T> = std::result::Result<T, TraceError>;
+
+#[derive(Debug, Error)]
+pub enum TraceError {
+    #[error("Invalid signature \"{0}\"")]
+    InvalidSignature(String),
+
+    #[error("Unknown type \"{0}\"")]
+    UnknownType(String),
+
+    #[error("\"{0}\" can't be passed by value")]
+    UnsupportedType(String),
+
+    #[error("Symbol name \"{0}\" contains a nul byte")]
+    InvalidName(String),
+}
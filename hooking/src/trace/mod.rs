@@ -0,0 +1,439 @@
+pub mod error;
+pub mod signature;
+
+pub use error::TraceError;
+pub use signature::{Signature, ValueType};
+
+use core::ffi::CStr;
+use core::ptr::NonNull;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::{CString, c_void};
+use std::sync::Arc;
+
+use crate::hooks::{Hook, HookWriter};
+use crate::instrument::{Arguments, INTEGER_REGISTERS, ReturnValue, VECTOR_REGISTERS};
+use crate::mem::{DefaultMemoryController, MemoryController};
+use crate::runtime;
+
+// Longest string printed before it is cut off
+const MAX_STRING: usize = 64;
+
+thread_local! {
+    // Traced calls that haven't returned yet with the shadow stack depth of
+    // their probes, innermost last. Gone while the thread is torn down, calls
+    // traced after that aren't logged.
+    static CALLS: RefCell<Vec<(usize, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+type Output = dyn Fn(&str) + Send + Sync;
+
+// Logs every call of a function with its decoded arguments and return value,
+// like `open("/etc/hosts", 0, 0) = 3`. The function is described by its C
+// declaration.
+pub struct Tracer {
+    signature: Signature,
+    enums: HashMap<String, Vec<(i64, String)>>,
+    output: Box<Output>,
+}
+
+impl Tracer {
+    pub fn new(declaration: &str) -> error::Result<Self> {
+        Ok(Self {
+            signature: Signature::parse(declaration)?,
+            enums: HashMap::new(),
+            output: Box::new(|line| eprintln!("{line}")),
+        })
+    }
+
+    // Names printed for the values of `enum name`, unnamed values are
+    // printed as numbers
+    pub fn with_enum(mut self, name: &str, values: &[(i64, &str)]) -> Self {
+        self.enums.insert(
+            name.into(),
+            values
+                .iter()
+                .map(|(value, name)| (*value, name.to_string()))
+                .collect(),
+        );
+        self
+    }
+
+    // Where the lines go, stderr by default
+    pub fn with_output(mut self, output: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.output = Box::new(output);
+        self
+    }
+
+    pub fn signature(&self) -> &Signature {
+        &self.signature
+    }
+
+    // Hooks the function named by the signature in `module`. The hook still
+    // has to be applied.
+    pub unsafe fn attach(self, module: Option<&CStr>) -> crate::error::Result<Hook<'static>> {
+        let symbol = CString::new(self.signature.name.as_str())
+            .map_err(|_| TraceError::InvalidName(self.signature.name.clone()))?;
+        let target = unsafe { DefaultMemoryController::new().get_symbol_address(module, &symbol)? };
+        unsafe { self.attach_to(target) }
+    }
+
+    // Hooks `target`, which has to match the signature
+    pub unsafe fn attach_to(self, target: NonNull<c_void>) -> crate::error::Result<Hook<'static>> {
+        let tracer = Arc::new(self);
+        let entered = tracer.clone();
+        unsafe {
+            HookWriter::from_static().create_instrumented_hook(
+                target,
+                Box::new(move |arguments| entered.enter(arguments)),
+                Box::new(move |value| tracer.exit(value)),
+            )
+        }
+    }
+
+    fn enter(&self, arguments: &Arguments) {
+        let mut cursor = ArgumentCursor::default();
+        let mut decoded = self
+            .signature
+            .parameters
+            .iter()
+            .map(|parameter| {
+                let bits = unsafe { cursor.next(arguments, parameter.is_float()) };
+                self.format_value(parameter, bits)
+            })
+            .collect::<Vec<_>>();
+        if self.signature.variadic {
+            decoded.push("...".into());
+        }
+
+        let call = format!("{}({})", self.signature.name, decoded.join(", "));
+        let depth = runtime::probe_depth();
+        let _ = CALLS.try_with(|calls| {
+            let mut calls = calls.borrow_mut();
+            // Left by calls a longjmp jumped out of
+            calls.retain(|(pending, _)| *pending < depth);
+            calls.push((depth, call));
+        });
+    }
+
+    fn exit(&self, value: &ReturnValue) {
+        let depth = runtime::probe_depth();
+        let call = CALLS.try_with(|calls| {
+            let mut calls = calls.borrow_mut();
+            calls.retain(|(pending, _)| *pending <= depth);
+            calls
+                .pop_if(|(pending, _)| *pending == depth)
+                .map(|(_, call)| call)
+        });
+        let Ok(Some(call)) = call else {
+            return;
+        };
+        let line = match &self.signature.return_type {
+            ValueType::Void => call,
+            return_type if return_type.is_float() => {
+                let value = self.format_value(return_type, value.float().to_bits());
+                format!("{call} = {value}")
+            }
+            return_type => {
+                let value = self.format_value(return_type, value.integer() as u64);
+                format!("{call} = {value}")
+            }
+        };
+        (self.output)(&line);
+    }
+
+    // `bits` is the whole register or stack slot the value was passed in
+    fn format_value(&self, value_type: &ValueType, bits: u64) -> String {
+        match value_type {
+            ValueType::Void => String::new(),
+            ValueType::Bool => (bits as u8 != 0).to_string(),
+            ValueType::Char => format!("{:?}", bits as u8 as char),
+            ValueType::Signed(size) => {
+                let unused = 64 - size * 8;
+                (((bits << unused) as i64) >> unused).to_string()
+            }
+            ValueType::Unsigned(size) => {
+                let unused = 64 - size * 8;
+                ((bits << unused) >> unused).to_string()
+            }
+            ValueType::Float => f32::from_bits(bits as u32).to_string(),
+            ValueType::Double => f64::from_bits(bits).to_string(),
+            ValueType::Pointer => format_pointer(bits),
+            ValueType::String => format_string(bits),
+            ValueType::Enum(name) => {
+                // Enums are ints
+                let value = bits as i32 as i64;
+                self.enums
+                    .get(name)
+                    .and_then(|values| values.iter().find(|(known, _)| *known == value))
+                    .map_or_else(|| value.to_string(), |(_, name)| name.clone())
+            }
+        }
+    }
+}
+
+fn format_pointer(bits: u64) -> String {
+    if bits == 0 {
+        "NULL".into()
+    } else {
+        format!("{bits:#x}")
+    }
+}
+
+// Strings that can't be read up to their end or the length limit are printed
+// as pointers
+fn format_string(bits: u64) -> String {
+    if bits == 0 {
+        return format_pointer(bits);
+    }
+    let mut buffer = [0u8; MAX_STRING + 1];
+    let read = DefaultMemoryController::new().read_memory(bits as usize, &mut buffer);
+
+    let (end, truncated) = match buffer[..read].iter().position(|byte| *byte == 0) {
+        Some(end) => (end, false),
+        None if read > MAX_STRING => (MAX_STRING, true),
+        None => return format_pointer(bits),
+    };
+    let text = String::from_utf8_lossy(&buffer[..end]);
+    format!("{text:?}{}", if truncated { "..." } else { "" })
+}
+
+// Walks the arguments of a call in order, like the calling convention assigns
+// them
+#[derive(Default)]
+struct ArgumentCursor {
+    integers: usize,
+    vectors: usize,
+    stack: usize,
+}
+
+impl ArgumentCursor {
+    // The raw bits of the next argument
+    #[cfg(not(target_os = "windows"))]
+    unsafe fn next(&mut self, arguments: &Arguments, is_float: bool) -> u64 {
+        if is_float && self.vectors < VECTOR_REGISTERS {
+            self.vectors += 1;
+            return arguments.float(self.vectors - 1).to_bits();
+        }
+        if !is_float && self.integers < INTEGER_REGISTERS {
+            self.integers += 1;
+            return unsafe { arguments.integer(self.integers - 1) } as u64;
+        }
+        self.stack += 1;
+        unsafe { arguments.stack_slot(self.stack - 1) as u64 }
+    }
+
+    // Every argument takes the next position, whichever register it is in
+    #[cfg(target_os = "windows")]
+    unsafe fn next(&mut self, arguments: &Arguments, is_float: bool) -> u64 {
+        let index = self.integers + self.vectors + self.stack;
+        if index >= INTEGER_REGISTERS.min(VECTOR_REGISTERS) {
+            self.stack += 1;
+            return unsafe { arguments.stack_slot(index) as u64 };
+        }
+        if is_float {
+            self.vectors += 1;
+            arguments.float(index).to_bits()
+        } else {
+            self.integers += 1;
+            unsafe { arguments.integer(index) as u64 }
+        }
+    }
+}
+
+impl Hook<'static, DefaultMemoryController> {
+    // Logs every call of the function declared by `declaration` to stderr,
+    // see `Tracer` for more options
+    pub unsafe fn trace(module: Option<&CStr>, declaration: &str) -> crate::error::Result<Self> {
+        unsafe { Tracer::new(declaration)?.attach(module) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::ffi::c_char;
+    use std::sync::Mutex;
+
+    #[test]
+    fn signatures_parse() {
+        let open = Signature::parse("int open(const char *path, int flags, mode_t mode)").unwrap();
+        assert_eq!(open.name, "open");
+        assert_eq!(open.return_type, ValueType::Signed(4));
+        assert_eq!(
+            open.parameters,
+            [
+                ValueType::String,
+                ValueType::Signed(4),
+                ValueType::Unsigned(4)
+            ]
+        );
+
+        let printf = Signature::parse("int printf(const char*, ...)").unwrap();
+        assert_eq!(printf.parameters, [ValueType::String]);
+        assert!(printf.variadic);
+
+        let free = Signature::parse("void free(void *)").unwrap();
+        assert_eq!(free.return_type, ValueType::Void);
+        assert_eq!(free.parameters, [ValueType::Pointer]);
+
+        let seek = Signature::parse("off_t lseek(int, off_t, enum whence)").unwrap();
+        assert_eq!(seek.parameters[2], ValueType::Enum("whence".into()));
+        assert!(
+            Signature::parse("int getpid(void)")
+                .unwrap()
+                .parameters
+                .is_empty()
+        );
+
+        assert!(matches!(
+            Signature::parse("int f(struct point p)"),
+            Err(TraceError::UnsupportedType(_))
+        ));
+        assert!(matches!(
+            Signature::parse("int f(widget)"),
+            Err(TraceError::UnknownType(_))
+        ));
+        for invalid in ["open(int)", "int open(int", "int (int)", "int f(..., int)"] {
+            assert!(
+                matches!(
+                    Signature::parse(invalid),
+                    Err(TraceError::InvalidSignature(_))
+                ),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[inline(never)]
+    extern "C" fn describe(
+        fd: i32,
+        offset: i64,
+        _whence: i32,
+        _path: *const c_char,
+        _scale: f64,
+        _data: *mut c_void,
+    ) -> i64 {
+        std::hint::black_box(fd as i64 + offset + 50)
+    }
+
+    #[test]
+    fn traced_calls_are_decoded() {
+        static LINES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+        let tracer = Tracer::new(
+            "long describe(int fd, off_t offset, enum whence w, const char *path, double scale, void *data)",
+        )
+        .unwrap()
+        .with_enum("whence", &[(0, "SEEK_SET"), (1, "SEEK_CUR"), (2, "SEEK_END")])
+        .with_output(|line| LINES.lock().unwrap().push(line.into()));
+
+        let describe_fn: extern "C" fn(i32, i64, i32, *const c_char, f64, *mut c_void) -> i64 =
+            std::hint::black_box(describe);
+        let long_path = CString::new("x".repeat(80)).unwrap();
+        let mut hook = unsafe {
+            tracer
+                .attach_to(NonNull::new_unchecked(describe as *const () as *mut _))
+                .unwrap()
+        };
+
+        unsafe {
+            hook.apply_hook().unwrap();
+            let null = std::ptr::null_mut();
+            assert_eq!(describe_fn(-1, -42, 2, c"hi\n".as_ptr(), 2.5, null), 7);
+            assert_eq!(
+                describe_fn(3, 0, 9, 0x10 as *const _, 0.5, 0x10 as *mut _),
+                53
+            );
+            assert_eq!(describe_fn(0, 0, 0, long_path.as_ptr(), 0.0, null), 50);
+            let near_end = (usize::MAX - 8) as *const c_char;
+            assert_eq!(describe_fn(1, 1, 1, near_end, 1.0, null), 52);
+            hook.remove_hook().unwrap();
+        }
+
+        assert_eq!(
+            *LINES.lock().unwrap(),
+            [
+                r#"describe(-1, -42, SEEK_END, "hi\n", 2.5, NULL) = 7"#.to_string(),
+                "describe(3, 0, 9, 0x10, 0.5, 0x10) = 53".into(),
+                format!(
+                    r#"describe(0, 0, SEEK_SET, "{}"..., 0, NULL) = 50"#,
+                    "x".repeat(MAX_STRING)
+                ),
+                "describe(1, 1, SEEK_CUR, 0xfffffffffffffff7, 1, NULL) = 52".into(),
+            ]
+        );
+    }
+
+    #[inline(never)]
+    extern "C" fn traced_inner(value: i32) -> i32 {
+        std::hint::black_box(value * 3)
+    }
+
+    #[inline(never)]
+    extern "C" fn traced_outer(value: i32) -> i32 {
+        let inner: extern "C" fn(i32) -> i32 = std::hint::black_box(traced_inner);
+        std::hint::black_box(inner(value) + 61)
+    }
+
+    #[test]
+    fn nested_tracers_log_their_own_calls() {
+        static LINES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+        let hook = |declaration: &str, target: extern "C" fn(i32) -> i32| unsafe {
+            Tracer::new(declaration)
+                .unwrap()
+                .with_output(|line| LINES.lock().unwrap().push(line.into()))
+                .attach_to(NonNull::new_unchecked(target as *const () as *mut _))
+                .unwrap()
+        };
+        let mut inner_hook = hook("int traced_inner(int value)", traced_inner);
+        let mut outer_hook = hook("int traced_outer(int value)", traced_outer);
+
+        let outer_fn: extern "C" fn(i32) -> i32 = std::hint::black_box(traced_outer);
+        unsafe {
+            inner_hook.apply_hook().unwrap();
+            outer_hook.apply_hook().unwrap();
+            assert_eq!(outer_fn(2), 67);
+            assert_eq!(outer_fn(-20), 1);
+            outer_hook.remove_hook().unwrap();
+            inner_hook.remove_hook().unwrap();
+        }
+
+        assert_eq!(
+            *LINES.lock().unwrap(),
+            [
+                "traced_inner(2) = 6",
+                "traced_outer(2) = 67",
+                "traced_inner(-20) = -60",
+                "traced_outer(-20) = 1",
+            ]
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn library_functions_are_traced_by_name() {
+        static LINES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+        let tracer = Tracer::new("int strncasecmp(const char *s1, const char *s2, size_t n)")
+            .unwrap()
+            .with_output(|line| LINES.lock().unwrap().push(line.into()));
+        let mut hook = unsafe { tracer.attach(None).unwrap() };
+
+        let compare: unsafe extern "C" fn(*const c_char, *const c_char, usize) -> i32 =
+            std::hint::black_box(libc::strncasecmp);
+        unsafe {
+            hook.apply_hook().unwrap();
+            assert_eq!(compare(c"Hello".as_ptr(), c"HELLO".as_ptr(), 5), 0);
+            hook.remove_hook().unwrap();
+        }
+
+        assert!(
+            LINES
+                .lock()
+                .unwrap()
+                .contains(&r#"strncasecmp("Hello", "HELLO", 5) = 0"#.to_string())
+        );
+    }
+}
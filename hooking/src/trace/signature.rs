@@ -0,0 +1,204 @@
+use super::error::{Result, TraceError};
+
+const LONG_SIZE: usize = size_of::<core::ffi::c_long>();
+
+// Words that can only be part of a type, never a parameter name
+const TYPE_WORDS: &[&str] = &[
+    "void", "bool", "_Bool", "char", "short", "int", "long", "signed", "unsigned", "float",
+    "double",
+];
+
+const QUALIFIERS: &[&str] = &["const", "volatile", "restrict"];
+
+// How a value is decoded and printed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValueType {
+    Void,
+    Bool,
+    Char,
+    // Size in bytes
+    Signed(usize),
+    Unsigned(usize),
+    Float,
+    Double,
+    Pointer,
+    // `char *`, printed as a string
+    String,
+    // `enum name`, printed by the names given to the tracer
+    Enum(String),
+}
+
+impl ValueType {
+    // Passed in vector registers
+    pub fn is_float(&self) -> bool {
+        matches!(self, Self::Float | Self::Double)
+    }
+
+    fn parse(tokens: &[&str]) -> Result<Self> {
+        let pointers = tokens.iter().filter(|token| **token == "*").count();
+        let words = tokens
+            .iter()
+            .copied()
+            .filter(|token| *token != "*" && !QUALIFIERS.contains(token))
+            .collect::<Vec<_>>();
+        let name = words.join(" ");
+        if words.is_empty() {
+            return Err(TraceError::UnknownType(tokens.join(" ")));
+        }
+
+        // Whatever is pointed to doesn't have to be known
+        if pointers > 0 {
+            let is_string =
+                pointers == 1 && matches!(name.as_str(), "char" | "signed char" | "unsigned char");
+            return Ok(if is_string {
+                Self::String
+            } else {
+                Self::Pointer
+            });
+        }
+        match words.as_slice() {
+            ["enum", tag] => return Ok(Self::Enum(tag.to_string())),
+            ["struct" | "union", ..] | ["long", "double"] => {
+                return Err(TraceError::UnsupportedType(name));
+            }
+            _ => {}
+        }
+
+        Ok(match name.as_str() {
+            "void" => Self::Void,
+            "bool" | "_Bool" => Self::Bool,
+            "char" => Self::Char,
+            "signed char" | "int8_t" => Self::Signed(1),
+            "unsigned char" | "uint8_t" => Self::Unsigned(1),
+            "short" | "short int" | "signed short" | "signed short int" | "int16_t" => {
+                Self::Signed(2)
+            }
+            "unsigned short" | "unsigned short int" | "uint16_t" => Self::Unsigned(2),
+            "int" | "signed" | "signed int" | "int32_t" | "pid_t" => Self::Signed(4),
+            "unsigned" | "unsigned int" | "uint32_t" | "mode_t" | "uid_t" | "gid_t" => {
+                Self::Unsigned(4)
+            }
+            "long" | "long int" | "signed long" | "signed long int" => Self::Signed(LONG_SIZE),
+            "unsigned long" | "unsigned long int" => Self::Unsigned(LONG_SIZE),
+            "long long"
+            | "long long int"
+            | "signed long long"
+            | "signed long long int"
+            | "int64_t"
+            | "ssize_t"
+            | "off_t"
+            | "intptr_t"
+            | "ptrdiff_t"
+            | "time_t" => Self::Signed(8),
+            "unsigned long long"
+            | "unsigned long long int"
+            | "uint64_t"
+            | "size_t"
+            | "uintptr_t" => Self::Unsigned(8),
+            "float" => Self::Float,
+            "double" => Self::Double,
+            _ => return Err(TraceError::UnknownType(name)),
+        })
+    }
+}
+
+// A parsed C function declaration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    pub name: String,
+    pub return_type: ValueType,
+    pub parameters: Vec<ValueType>,
+    // Ends with `...`
+    pub variadic: bool,
+}
+
+impl Signature {
+    // A declaration like "int open(const char *path, int flags, mode_t mode)",
+    // parameter names are optional
+    pub fn parse(declaration: &str) -> Result<Self> {
+        let invalid = || TraceError::InvalidSignature(declaration.into());
+
+        let tokens = tokenize(declaration);
+        let open = tokens
+            .iter()
+            .position(|token| *token == "(")
+            .ok_or_else(invalid)?;
+        let Some((")", inner)) = tokens[open + 1..].split_last().map(|(l, i)| (*l, i)) else {
+            return Err(invalid());
+        };
+        let Some((name, return_type)) = tokens[..open].split_last() else {
+            return Err(invalid());
+        };
+        if !is_identifier(name) || return_type.is_empty() {
+            return Err(invalid());
+        }
+
+        let mut parameters = Vec::new();
+        let mut variadic = false;
+        let groups = match inner {
+            [] | ["void"] => Vec::new(),
+            inner => inner.split(|token| *token == ",").collect(),
+        };
+        for (index, group) in groups.iter().enumerate() {
+            match group {
+                ["..."] if index > 0 && index == groups.len() - 1 => variadic = true,
+                group if group.contains(&"...") => return Err(invalid()),
+                group => match ValueType::parse(without_name(group))? {
+                    ValueType::Void => return Err(invalid()),
+                    parameter => parameters.push(parameter),
+                },
+            }
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            return_type: ValueType::parse(return_type)?,
+            parameters,
+            variadic,
+        })
+    }
+}
+
+fn is_identifier(token: &str) -> bool {
+    token
+        .chars()
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && token
+            .chars()
+            .all(|char| char.is_ascii_alphanumeric() || char == '_')
+}
+
+// Identifiers, `...` and single punctuation characters
+fn tokenize(declaration: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut rest = declaration.trim_start();
+    while !rest.is_empty() {
+        let len = if rest.starts_with("...") {
+            3
+        } else {
+            match rest.find(|char: char| !(char.is_ascii_alphanumeric() || char == '_')) {
+                Some(0) => rest.chars().next().map_or(1, char::len_utf8),
+                end => end.unwrap_or(rest.len()),
+            }
+        };
+        tokens.push(&rest[..len]);
+        rest = rest[len..].trim_start();
+    }
+    tokens
+}
+
+// Drops the name from the tokens of a parameter
+fn without_name<'a>(tokens: &'a [&'a str]) -> &'a [&'a str] {
+    match tokens {
+        [.., previous, last]
+            if is_identifier(last)
+                && !TYPE_WORDS.contains(last)
+                && !QUALIFIERS.contains(last)
+                && !matches!(*previous, "enum" | "struct" | "union") =>
+        {
+            &tokens[..tokens.len() - 1]
+        }
+        tokens => tokens,
+    }
+}
@@ -0,0 +1,271 @@
+use core::ffi::CStr;
+use core::ptr::NonNull;
+use std::ffi::c_void;
+
+use crate::asm::{DefaultHookAssembler, HookAssembler};
+use crate::error::{HookingError, Result};
+use crate::hooks::{Hook, HookWriter};
+use crate::mem::{DefaultMemoryController, MemoryController};
+
+pub struct HookTransaction<
+    'w,
+    'a,
+    M: MemoryController = DefaultMemoryController,
+    A: HookAssembler = DefaultHookAssembler,
+> {
+    writer: &'w HookWriter<'a, M, A>,
+    hooks: Vec<Hook<'a, M>>,
+}
+
+impl<'w, 'a, M: MemoryController, A: HookAssembler> HookTransaction<'w, 'a, M, A> {
+    pub fn new(writer: &'w HookWriter<'a, M, A>) -> Self {
+        Self {
+            writer,
+            hooks: Vec::new(),
+        }
+    }
+
+    pub unsafe fn add(
+        &mut self,
+        target: NonNull<c_void>,
+        destination: NonNull<c_void>,
+    ) -> Result<&mut Self> {
+        let hook = unsafe { self.writer.create_hook(target, destination)? };
+        self.hooks.push(hook);
+        Ok(self)
+    }
+
+    pub unsafe fn add_by_name(
+        &mut self,
+        module: Option<&CStr>,
+        symbol: &CStr,
+        destination: *mut u8,
+    ) -> Result<&mut Self> {
+        let hook = unsafe {
+            self.writer
+                .create_hook_by_name(module, symbol, destination)?
+        };
+        self.hooks.push(hook);
+        Ok(self)
+    }
+
+    pub fn hooks(&self) -> &[Hook<'a, M>] {
+        &self.hooks
+    }
+
+    pub fn into_hooks(self) -> Vec<Hook<'a, M>> {
+        self.hooks
+    }
+
+    pub unsafe fn apply(&mut self) -> Result<()> {
+        let mut applied = Vec::with_capacity(self.hooks.len());
+        for index in 0..self.hooks.len() {
+            if self.hooks[index].is_applied() {
+                continue;
+            }
+            if let Err(error) = unsafe { self.hooks[index].apply_hook() } {
+                return Err(unsafe { self.rollback(&applied, error, Hook::remove_hook) });
+            }
+            applied.push(index);
+        }
+        Ok(())
+    }
+
+    pub unsafe fn remove(&mut self) -> Result<()> {
+        let mut removed = Vec::with_capacity(self.hooks.len());
+        for index in (0..self.hooks.len()).rev() {
+            if !self.hooks[index].is_applied() {
+                continue;
+            }
+            if let Err(error) = unsafe { self.hooks[index].remove_hook() } {
+                return Err(unsafe { self.rollback(&removed, error, Hook::apply_hook) });
+            }
+            removed.push(index);
+        }
+        Ok(())
+    }
+
+    // Undo the hooks that were changed before the failure, most recent first.
+    // A hook that can't be undone doesn't stop the others, the first failure
+    // is reported.
+    unsafe fn rollback(
+        &mut self,
+        changed: &[usize],
+        error: HookingError,
+        undo: unsafe fn(&mut Hook<'a, M>) -> Result<()>,
+    ) -> HookingError {
+        let mut failed = None;
+        for index in changed.iter().rev() {
+            if let Err(rollback) = unsafe { undo(&mut self.hooks[*index]) } {
+                failed.get_or_insert(rollback);
+            }
+        }
+        match failed {
+            Some(rollback) => HookingError::RollbackFailed {
+                error: Box::new(error),
+                rollback: Box::new(rollback),
+            },
+            None => error,
+        }
+    }
+}
+
+impl<'a, M: MemoryController, A: HookAssembler> HookWriter<'a, M, A> {
+    pub fn transaction(&self) -> HookTransaction<'_, 'a, M, A> {
+        HookTransaction::new(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patch::{PatchOptions, write_patch};
+
+    macro_rules! targets {
+        ($($name:ident = $value:literal),*) => {
+            $(
+                #[inline(never)]
+                extern "C" fn $name() -> i32 {
+                    std::hint::black_box($value)
+                }
+            )*
+        };
+    }
+
+    // Every test hooks its own functions
+    targets!(
+        applied_first = 501,
+        applied_second = 502,
+        rolled_back_first = 511,
+        rolled_back_second = 512,
+        rolled_back_third = 513,
+        stuck_first = 521,
+        stuck_second = 522,
+        stuck_third = 523
+    );
+
+    extern "C" fn hooked() -> i32 {
+        std::hint::black_box(-500)
+    }
+
+    fn function(f: extern "C" fn() -> i32) -> NonNull<c_void> {
+        NonNull::new(f as *const () as *mut c_void).unwrap()
+    }
+
+    // Writes `bytes` over the target of `hook`
+    unsafe fn overwrite<M: MemoryController>(hook: &Hook<'_, M>, bytes: &[u8]) {
+        let target = hook.data.symbol_address;
+        unsafe {
+            write_patch(
+                &DefaultMemoryController::new(),
+                target,
+                bytes,
+                target,
+                PatchOptions::new(),
+                |_| None,
+            )
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn transactions_apply_and_remove_every_hook() {
+        let calls: [extern "C" fn() -> i32; 2] =
+            std::hint::black_box([applied_first, applied_second]);
+        let writer = HookWriter::from_static();
+        let mut transaction = writer.transaction();
+        unsafe {
+            transaction
+                .add(function(applied_first), function(hooked))
+                .unwrap()
+                .add(function(applied_second), function(hooked))
+                .unwrap();
+
+            transaction.apply().unwrap();
+            assert!(transaction.hooks().iter().all(Hook::is_applied));
+            assert_eq!(calls.map(|call| call()), [-500, -500]);
+
+            transaction.remove().unwrap();
+            assert!(!transaction.hooks().iter().any(Hook::is_applied));
+            assert_eq!(calls.map(|call| call()), [501, 502]);
+        }
+    }
+
+    #[test]
+    fn failed_transactions_roll_back() {
+        let calls: [extern "C" fn() -> i32; 3] =
+            std::hint::black_box([rolled_back_first, rolled_back_second, rolled_back_third]);
+        let writer = HookWriter::from_static();
+        let mut transaction = writer.transaction();
+        unsafe {
+            transaction
+                .add(function(rolled_back_first), function(hooked))
+                .unwrap()
+                .add(function(rolled_back_second), function(hooked))
+                .unwrap()
+                .add(function(rolled_back_third), function(hooked))
+                .unwrap();
+
+            // The hook in the middle no longer matches its target
+            let original = transaction.hooks[1].data.original_instructions.clone();
+            overwrite(&transaction.hooks[1], &[0xC3; 5]);
+
+            let error = transaction.apply().unwrap_err();
+            assert!(matches!(error, HookingError::TargetModified(_)), "{error}");
+            assert!(!transaction.hooks().iter().any(Hook::is_applied));
+            assert_eq!(calls[0](), 511);
+            assert_eq!(calls[2](), 513);
+
+            overwrite(&transaction.hooks[1], &original);
+            assert_eq!(calls[1](), 512);
+        }
+    }
+
+    // Removes the hook unless it is on the first or third stuck target, like
+    // a target that can't be made writable again
+    unsafe fn remove_unless_stuck(hook: &mut Hook) -> Result<()> {
+        let target = hook.data.symbol_address;
+        if [function(stuck_first), function(stuck_third)].contains(&target) {
+            return Err(HookingError::InvalidTarget(target.as_ptr()));
+        }
+        unsafe { hook.remove_hook() }
+    }
+
+    #[test]
+    fn failed_rollbacks_undo_the_rest() {
+        let calls: [extern "C" fn() -> i32; 3] =
+            std::hint::black_box([stuck_first, stuck_second, stuck_third]);
+        let writer = HookWriter::from_static();
+        let mut transaction = writer.transaction();
+        unsafe {
+            transaction
+                .add(function(stuck_first), function(hooked))
+                .unwrap()
+                .add(function(stuck_second), function(hooked))
+                .unwrap()
+                .add(function(stuck_third), function(hooked))
+                .unwrap();
+            transaction.apply().unwrap();
+
+            let error = HookingError::StillApplied(std::ptr::null());
+            match transaction.rollback(&[0, 1, 2], error, remove_unless_stuck) {
+                HookingError::RollbackFailed { error, rollback } => {
+                    assert!(matches!(*error, HookingError::StillApplied(_)), "{error}");
+                    // The most recent hook is undone first
+                    assert!(
+                        matches!(
+                            *rollback,
+                            HookingError::InvalidTarget(target) if target == function(stuck_third).as_ptr()
+                        ),
+                        "{rollback}"
+                    );
+                }
+                error => panic!("{error}"),
+            }
+            assert_eq!(calls.map(|call| call()), [-500, 522, -500]);
+
+            transaction.remove().unwrap();
+            assert_eq!(calls.map(|call| call()), [521, 522, 523]);
+        }
+    }
+}
@@ -0,0 +1,610 @@
+use core::ffi::CStr;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+use crate::asm::HookAssembler;
+use crate::control::{HookContext, HookControl};
+use crate::error::{HookingError, Result};
+use crate::hooks::{Hook, HookWriter, Trampoline};
+use crate::mem::{DefaultMemoryController, MemoryController};
+use crate::runtime;
+
+// Function pointers that can be hooked, implemented for `extern "C"` and
+// `extern "system"` functions with up to 12 arguments.
+pub trait HookFn: Copy + Send + Sync + 'static {
+    // Closure that can stand in for the function as a hook destination
+    type Closure: ?Sized + Send + Sync + 'static;
+
+    fn as_ptr(self) -> *mut c_void;
+
+    /// # Safety
+    /// `ptr` has to point to a function with the signature of `Self`.
+    unsafe fn from_ptr(ptr: NonNull<c_void>) -> Self;
+
+    // Destination that calls the closure of the hook the call went through
+    fn closure_shim() -> Self;
+}
+
+macro_rules! impl_hook_fn {
+    ($($arg:ident $name:ident),*) => {
+        impl_hook_fn!(@abi "C", $($arg $name),*);
+        impl_hook_fn!(@abi "system", $($arg $name),*);
+    };
+    (@abi $abi:literal, $($arg:ident $name:ident),*) => {
+        impl_hook_fn!(@fn $abi, (extern $abi fn($($arg),*) -> R), $($arg $name),*);
+        impl_hook_fn!(@fn $abi, (unsafe extern $abi fn($($arg),*) -> R), $($arg $name),*);
+    };
+    (@fn $abi:literal, ($($fn_type:tt)*), $($arg:ident $name:ident),*) => {
+        impl<R: 'static, $($arg: 'static),*> HookFn for $($fn_type)* {
+            type Closure = dyn Fn($($arg),*) -> R + Send + Sync;
+
+            fn as_ptr(self) -> *mut c_void {
+                self as *mut c_void
+            }
+
+            unsafe fn from_ptr(ptr: NonNull<c_void>) -> Self {
+                unsafe { core::mem::transmute::<*mut c_void, Self>(ptr.as_ptr()) }
+            }
+
+            fn closure_shim() -> Self {
+                extern $abi fn shim<R: 'static, $($arg: 'static),*>($($name: $arg),*) -> R {
+                    let closure = runtime::entered_hook()
+                        .and_then(|control| control.context())
+                        .and_then(|context| context.get::<Box<dyn Fn($($arg),*) -> R + Send + Sync>>());
+                    closure.expect("closure hook reached without its closure")($($name),*)
+                }
+
+                shim::<R, $($arg),*>
+            }
+        }
+    };
+}
+
+impl_hook_fn!();
+impl_hook_fn!(A1 a1);
+impl_hook_fn!(A1 a1, A2 a2);
+impl_hook_fn!(A1 a1, A2 a2, A3 a3);
+impl_hook_fn!(A1 a1, A2 a2, A3 a3, A4 a4);
+impl_hook_fn!(A1 a1, A2 a2, A3 a3, A4 a4, A5 a5);
+impl_hook_fn!(A1 a1, A2 a2, A3 a3, A4 a4, A5 a5, A6 a6);
+impl_hook_fn!(A1 a1, A2 a2, A3 a3, A4 a4, A5 a5, A6 a6, A7 a7);
+impl_hook_fn!(A1 a1, A2 a2, A3 a3, A4 a4, A5 a5, A6 a6, A7 a7, A8 a8);
+impl_hook_fn!(A1 a1, A2 a2, A3 a3, A4 a4, A5 a5, A6 a6, A7 a7, A8 a8, A9 a9);
+impl_hook_fn!(A1 a1, A2 a2, A3 a3, A4 a4, A5 a5, A6 a6, A7 a7, A8 a8, A9 a9, A10 a10);
+impl_hook_fn!(A1 a1, A2 a2, A3 a3, A4 a4, A5 a5, A6 a6, A7 a7, A8 a8, A9 a9, A10 a10, A11 a11);
+impl_hook_fn!(
+    A1 a1, A2 a2, A3 a3, A4 a4, A5 a5, A6 a6, A7 a7, A8 a8, A9 a9, A10 a10, A11 a11, A12 a12
+);
+
+// The function the running hook destination should continue to, see
+// `original_function_ptr`.
+/// # Safety
+/// `F` has to be the signature of the hooked function.
+pub unsafe fn current_original<F: HookFn>() -> F {
+    unsafe { F::from_ptr(crate::original_function_ptr()) }
+}
+
+// A hook whose target and detour share the signature `F`
+#[derive(Debug)]
+pub struct TypedHook<'a, F: HookFn, M: MemoryController = DefaultMemoryController> {
+    hook: Hook<'a, M>,
+    _fn: PhantomData<F>,
+}
+
+impl<F: HookFn> TypedHook<'static, F, DefaultMemoryController> {
+    /// # Safety
+    /// Same as `HookWriter::create_typed_hook`.
+    pub unsafe fn create(target: F, detour: F) -> Result<Self> {
+        unsafe { HookWriter::from_static().create_typed_hook(target, detour) }
+    }
+
+    /// # Safety
+    /// The symbol has to have the signature `F`.
+    pub unsafe fn by_name(module: Option<&CStr>, symbol: &CStr, detour: F) -> Result<Self> {
+        unsafe { HookWriter::from_static().create_typed_hook_by_name(module, symbol, detour) }
+    }
+
+    /// # Safety
+    /// Same as `HookWriter::create_closure_hook`.
+    pub unsafe fn with_closure(target: F, destination: Box<F::Closure>) -> Result<Self> {
+        unsafe { HookWriter::from_static().create_closure_hook(target, destination) }
+    }
+}
+
+impl<'a, F: HookFn, M: MemoryController> TypedHook<'a, F, M> {
+    /// # Safety
+    /// The target of `hook` has to have the signature `F`.
+    pub unsafe fn from_hook(hook: Hook<'a, M>) -> Self {
+        Self {
+            hook,
+            _fn: PhantomData,
+        }
+    }
+
+    // What the detour should call to continue, the original function or the
+    // next hook chained on the same target.
+    pub fn original(&self) -> F {
+        unsafe { F::from_ptr(self.hook.original_ptr()) }
+    }
+
+    pub fn target(&self) -> F {
+        unsafe { F::from_ptr(self.hook.data.symbol_address) }
+    }
+
+    pub fn into_inner(self) -> Hook<'a, M> {
+        self.hook
+    }
+}
+
+impl<'a, F: HookFn, M: MemoryController> Deref for TypedHook<'a, F, M> {
+    type Target = Hook<'a, M>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.hook
+    }
+}
+
+impl<'a, F: HookFn, M: MemoryController> DerefMut for TypedHook<'a, F, M> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.hook
+    }
+}
+
+// Typed hook that can live in a static, created the first time it is
+// installed
+pub struct StaticHook<F: HookFn> {
+    hook: Mutex<Option<TypedHook<'static, F>>>,
+    // Set once the hook is created, so `original` doesn't need the lock
+    control: AtomicPtr<HookControl>,
+}
+
+impl<F: HookFn> StaticHook<F> {
+    pub const fn new() -> Self {
+        Self {
+            hook: Mutex::new(None),
+            control: AtomicPtr::new(core::ptr::null_mut()),
+        }
+    }
+
+    /// # Safety
+    /// `target` has to point to the start of a function. It is patched right
+    /// away, other threads may be running it.
+    pub unsafe fn install(&self, target: F, detour: F) -> Result<()> {
+        unsafe { self.install_with(|| TypedHook::create(target, detour)) }
+    }
+
+    /// # Safety
+    /// The symbol has to have the signature `F`.
+    pub unsafe fn install_by_name(
+        &self,
+        module: Option<&CStr>,
+        symbol: &CStr,
+        detour: F,
+    ) -> Result<()> {
+        unsafe { self.install_with(|| TypedHook::by_name(module, symbol, detour)) }
+    }
+
+    unsafe fn install_with(
+        &self,
+        create: impl FnOnce() -> Result<TypedHook<'static, F>>,
+    ) -> Result<()> {
+        let mut hook = self.hook.lock().unwrap_or_else(PoisonError::into_inner);
+        let hook = match &mut *hook {
+            Some(hook) => hook,
+            hook => {
+                let created = hook.insert(create()?);
+                let control = created.data.control as *const HookControl;
+                self.control.store(control as *mut _, Ordering::Release);
+                created
+            }
+        };
+        unsafe { hook.apply_hook() }
+    }
+
+    /// # Safety
+    /// The target is patched back, the module it is in can't have been
+    /// unloaded since it was installed.
+    pub unsafe fn uninstall(&self) -> Result<()> {
+        let mut hook = self.hook.lock().unwrap_or_else(PoisonError::into_inner);
+        match &mut *hook {
+            Some(hook) => unsafe { hook.remove_hook() },
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_installed(&self) -> bool {
+        let hook = self.hook.lock().unwrap_or_else(PoisonError::into_inner);
+        hook.as_ref().is_some_and(|hook| hook.is_applied())
+    }
+
+    // Panics if the hook was never installed
+    pub fn original(&self) -> F {
+        let control = self.control.load(Ordering::Acquire);
+        assert!(!control.is_null(), "static hook was never installed");
+        unsafe { F::from_ptr((*control).original()) }
+    }
+}
+
+impl<F: HookFn> Default for StaticHook<F> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+trait Uninstall: Sync {
+    unsafe fn uninstall(&self) -> Result<()>;
+}
+
+impl<F: HookFn> Uninstall for StaticHook<F> {
+    unsafe fn uninstall(&self) -> Result<()> {
+        unsafe { StaticHook::uninstall(self) }
+    }
+}
+
+// Installs static hooks all or nothing. When one fails, the ones installed
+// through the group so far are uninstalled again.
+#[derive(Default)]
+pub struct StaticHookGroup {
+    installed: Vec<&'static dyn Uninstall>,
+}
+
+impl StaticHookGroup {
+    pub const fn new() -> Self {
+        Self {
+            installed: Vec::new(),
+        }
+    }
+
+    /// # Safety
+    /// The symbol has to have the signature `F`.
+    pub unsafe fn install_by_name<F: HookFn>(
+        &mut self,
+        hook: &'static StaticHook<F>,
+        module: Option<&CStr>,
+        symbol: &CStr,
+        detour: F,
+    ) -> Result<()> {
+        let was_installed = hook.is_installed();
+        match unsafe { hook.install_by_name(module, symbol, detour) } {
+            Ok(()) if was_installed => Ok(()),
+            Ok(()) => {
+                self.installed.push(hook);
+                Ok(())
+            }
+            Err(error) => Err(unsafe { self.rollback(error) }),
+        }
+    }
+
+    unsafe fn rollback(&mut self, error: HookingError) -> HookingError {
+        while let Some(hook) = self.installed.pop() {
+            if let Err(rollback) = unsafe { hook.uninstall() } {
+                return HookingError::RollbackFailed {
+                    error: Box::new(error),
+                    rollback: Box::new(rollback),
+                };
+            }
+        }
+        error
+    }
+}
+
+// Looks up a symbol without hooking it
+/// # Safety
+/// The symbol has to have the signature `F`.
+pub unsafe fn resolve_symbol<F: HookFn>(module: Option<&CStr>, symbol: &CStr) -> Result<F> {
+    let mem = DefaultMemoryController::new();
+    unsafe { Ok(F::from_ptr(mem.get_symbol_address(module, symbol)?)) }
+}
+
+impl<'a, M: MemoryController, A: HookAssembler> HookWriter<'a, M, A> {
+    /// # Safety
+    /// `target` has to point to the start of a function, its first
+    /// instructions are read and copied next to the trampoline.
+    pub unsafe fn create_typed_hook<F: HookFn>(
+        &self,
+        target: F,
+        detour: F,
+    ) -> Result<TypedHook<'a, F, M>> {
+        let target_ptr = target.as_ptr();
+        let detour_ptr = detour.as_ptr();
+        unsafe {
+            let hook = self.create_hook(
+                NonNull::new(target_ptr)
+                    .ok_or(HookingError::InvalidTarget(target_ptr as *const _))?,
+                NonNull::new(detour_ptr)
+                    .ok_or(HookingError::InvalidDestination(detour_ptr as *const _))?,
+            )?;
+            Ok(TypedHook::from_hook(hook))
+        }
+    }
+
+    // Calls `destination` in place of `target`. The closure is dropped when
+    // the hook is freed.
+    /// # Safety
+    /// Same as `create_typed_hook`.
+    pub unsafe fn create_closure_hook<F: HookFn>(
+        &self,
+        target: F,
+        destination: Box<F::Closure>,
+    ) -> Result<TypedHook<'a, F, M>> {
+        let target_ptr = target.as_ptr();
+        let target =
+            NonNull::new(target_ptr).ok_or(HookingError::InvalidTarget(target_ptr as *const _))?;
+        let shim = F::closure_shim().as_ptr();
+
+        unsafe {
+            let symbol_info = self.hook_heap.mem.get_symbol_info(target);
+            let hook = self.create_registered_trampoline(
+                target,
+                Trampoline::Closure(NonNull::new_unchecked(shim)),
+                symbol_info,
+                Some(HookContext::new(destination)),
+            )?;
+            Ok(TypedHook::from_hook(hook))
+        }
+    }
+
+    /// # Safety
+    /// The symbol has to have the signature `F`.
+    pub unsafe fn create_typed_hook_by_name<F: HookFn>(
+        &self,
+        module: Option<&CStr>,
+        symbol: &CStr,
+        detour: F,
+    ) -> Result<TypedHook<'a, F, M>> {
+        unsafe {
+            let hook = self.create_hook_by_name(module, symbol, detour.as_ptr() as *mut u8)?;
+            Ok(TypedHook::from_hook(hook))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[inline(never)]
+    extern "C" fn scale(value: i32, factor: i32) -> i32 {
+        std::hint::black_box(value * factor)
+    }
+
+    static SCALE_HOOK: std::sync::OnceLock<TypedHook<'static, extern "C" fn(i32, i32) -> i32>> =
+        std::sync::OnceLock::new();
+
+    extern "C" fn hooked_scale(value: i32, factor: i32) -> i32 {
+        SCALE_HOOK.get().unwrap().original()(value, factor) + 1
+    }
+
+    #[test]
+    fn typed_original_calls_through() {
+        let scale_fn: extern "C" fn(i32, i32) -> i32 = std::hint::black_box(scale);
+        let mut hook = unsafe {
+            TypedHook::create(
+                scale as extern "C" fn(i32, i32) -> i32,
+                hooked_scale as extern "C" fn(i32, i32) -> i32,
+            )
+            .unwrap()
+        };
+
+        unsafe { hook.apply_hook().unwrap() };
+        assert!(SCALE_HOOK.set(hook).is_ok());
+
+        assert_eq!(scale_fn(3, 4), 13);
+        assert_eq!(SCALE_HOOK.get().unwrap().target()(3, 4), 13);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[crate::hook(module = "libc.so.6", symbol = "labs")]
+    unsafe extern "C" fn hooked_labs(value: libc::c_long) -> libc::c_long {
+        unsafe { hooked_labs::original()(value) + 1 }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn hook_attribute_installs_by_name() {
+        let labs: hooked_labs::HookedFn = std::hint::black_box(libc::labs);
+
+        unsafe {
+            hooked_labs::install().unwrap();
+            assert!(hooked_labs::HOOK.is_installed());
+            assert_eq!(labs(-4), 5);
+
+            hooked_labs::uninstall().unwrap();
+            assert_eq!(labs(-4), 4);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    crate::hook_extern! {
+        mod libc_hooks;
+
+        #[hook(module = "libc.so.6")]
+        unsafe extern "C" {
+            pub fn abs(value: i32) -> i32;
+            pub fn ffs(value: i32) -> i32;
+            #[link_name = "hooking_missing_symbol"]
+            pub fn missing();
+        }
+    }
+
+    // A second group next to the first one
+    #[cfg(target_os = "linux")]
+    crate::hook_extern! {
+        mod more_libc_hooks;
+
+        #[hook(module = "libc.so.6")]
+        unsafe extern "C" {
+            fn ffsl(value: i64) -> i32;
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe extern "C" fn hooked_ffsl(value: i64) -> i32 {
+        unsafe { more_libc_hooks::ffsl::original()(value) + 200 }
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe extern "C" fn hooked_abs(value: i32) -> i32 {
+        unsafe { libc_hooks::abs::original()(value) * 2 }
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe extern "C" fn hooked_ffs(value: i32) -> i32 {
+        unsafe { libc_hooks::ffs::original()(value) + 100 }
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe extern "C" fn hooked_missing() {}
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn extern_blocks_install_as_a_group() {
+        let abs = unsafe { libc_hooks::abs::target().unwrap() };
+        let ffs = unsafe { libc_hooks::ffs::target().unwrap() };
+        let mut detours = libc_hooks::Detours {
+            abs: Some(hooked_abs),
+            ffs: Some(hooked_ffs),
+            missing: Some(hooked_missing),
+        };
+
+        unsafe {
+            assert!(libc_hooks::install_all(&detours).is_err());
+            assert!(!libc_hooks::abs::HOOK.is_installed());
+            assert_eq!(abs(-3), 3);
+
+            detours.missing = None;
+            libc_hooks::install_all(&detours).unwrap();
+            assert_eq!(abs(-3), 6);
+            assert_eq!(ffs(8), 104);
+
+            let ffsl = more_libc_hooks::ffsl::target().unwrap();
+            let more_detours = more_libc_hooks::Detours {
+                ffsl: Some(hooked_ffsl),
+            };
+            more_libc_hooks::install_all(&more_detours).unwrap();
+            assert_eq!(ffsl(8), 204);
+
+            libc_hooks::uninstall_all().unwrap();
+            more_libc_hooks::uninstall_all().unwrap();
+            assert_eq!(abs(-3), 3);
+            assert_eq!(ffs(8), 4);
+            assert_eq!(ffsl(8), 4);
+        }
+    }
+
+    #[inline(never)]
+    extern "C" fn offset(value: i64) -> i64 {
+        std::hint::black_box(value - 7)
+    }
+
+    #[test]
+    fn closures_capture_state() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        type OffsetFn = extern "C" fn(i64) -> i64;
+        let offset_fn: OffsetFn = std::hint::black_box(offset);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let factor = 3;
+
+        let destination = Box::new({
+            let calls = calls.clone();
+            move |value| {
+                calls.fetch_add(1, Ordering::Relaxed);
+                let original = unsafe { current_original::<OffsetFn>() };
+                original(value) * factor
+            }
+        });
+        let mut hook = unsafe { TypedHook::with_closure(offset as OffsetFn, destination).unwrap() };
+
+        unsafe { hook.apply_hook().unwrap() };
+        assert_eq!(offset_fn(10), 9);
+        assert_eq!(offset_fn(8), 3);
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+
+        unsafe {
+            hook.remove_hook().unwrap();
+            hook.into_inner().free().unwrap();
+        }
+        assert_eq!(offset_fn(10), 3);
+        assert_eq!(Arc::strong_count(&calls), 1);
+    }
+
+    #[inline(never)]
+    extern "C" fn quarter(value: i64) -> i64 {
+        std::hint::black_box(value / 4)
+    }
+
+    #[inline(never)]
+    extern "C" fn pad(value: i64) -> i64 {
+        std::hint::black_box(value + 5)
+    }
+
+    #[test]
+    fn closures_find_their_original_after_nested_hooks() {
+        type ValueFn = extern "C" fn(i64) -> i64;
+        let pad_fn: ValueFn = std::hint::black_box(pad);
+        let quarter_fn: ValueFn = std::hint::black_box(quarter);
+
+        let quarter_destination =
+            Box::new(|value| unsafe { current_original::<ValueFn>() }(value) + 1);
+        let pad_destination = Box::new(move |value| {
+            let quartered = quarter_fn(value);
+            let original = unsafe { current_original::<ValueFn>() };
+            original(value) * 100 + quartered
+        });
+        let mut quarter_hook =
+            unsafe { TypedHook::with_closure(quarter as ValueFn, quarter_destination).unwrap() };
+        let mut pad_hook =
+            unsafe { TypedHook::with_closure(pad as ValueFn, pad_destination).unwrap() };
+
+        unsafe {
+            quarter_hook.apply_hook().unwrap();
+            pad_hook.apply_hook().unwrap();
+            assert_eq!(pad_fn(8), 1303);
+            assert_eq!(quarter_fn(8), 3);
+
+            pad_hook.remove_hook().unwrap();
+            quarter_hook.remove_hook().unwrap();
+        }
+        assert_eq!(pad_fn(8), 13);
+    }
+
+    #[inline(never)]
+    extern "C" fn magnify(value: i64) -> i64 {
+        std::hint::black_box(value * 11)
+    }
+
+    #[test]
+    fn chained_closures_get_their_own_state() {
+        type MagnifyFn = extern "C" fn(i64) -> i64;
+        let magnify_fn: MagnifyFn = std::hint::black_box(magnify);
+        let closure = |added: i64| -> Box<dyn Fn(i64) -> i64 + Send + Sync> {
+            Box::new(move |value| {
+                let original = unsafe { current_original::<MagnifyFn>() };
+                original(value) + added
+            })
+        };
+
+        let mut first =
+            unsafe { TypedHook::with_closure(magnify as MagnifyFn, closure(1)).unwrap() };
+        let mut second =
+            unsafe { TypedHook::with_closure(magnify as MagnifyFn, closure(100)).unwrap() };
+
+        unsafe {
+            first.apply_hook().unwrap();
+            second.apply_hook().unwrap();
+            assert_eq!(magnify_fn(2), 123);
+
+            second.remove_hook().unwrap();
+            first.remove_hook().unwrap();
+            first.into_inner().free().unwrap();
+            second.into_inner().free().unwrap();
+        }
+        assert_eq!(magnify_fn(2), 22);
+    }
+}
@@ -0,0 +1,65 @@
+use core::ptr::NonNull;
+use std::ffi::c_void;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::error::Result;
+use crate::patch::PatchOptions;
+use crate::registry::{self, HookIntegrity};
+
+// Periodically restores every hooked target that was written over. Stops
+// when dropped.
+pub struct HookWatchdog {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl HookWatchdog {
+    /// # Safety
+    /// See [`HookWatchdog::spawn_with`].
+    pub unsafe fn spawn(
+        interval: Duration,
+        on_check: impl FnMut(Result<(NonNull<c_void>, HookIntegrity)>) + Send + 'static,
+    ) -> Self {
+        unsafe { Self::spawn_with(interval, PatchOptions::new(), on_check) }
+    }
+
+    // `on_check` is called with every target that had to be restored and
+    // with every error that stopped a check.
+    /// # Safety
+    /// The watchdog writes over hooked targets from its own thread whenever
+    /// they changed, so for as long as it runs nothing else may be rewriting
+    /// them on purpose, such as a hot patch or a debugger, and every hook
+    /// that is applied has to stay valid to patch with `options`. Freeing a
+    /// hook goes through the registry and is fine.
+    pub unsafe fn spawn_with(
+        interval: Duration,
+        options: PatchOptions,
+        mut on_check: impl FnMut(Result<(NonNull<c_void>, HookIntegrity)>) + Send + 'static,
+    ) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let thread = std::thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                match unsafe { registry::reapply_all(options) } {
+                    Ok(repaired) => repaired.into_iter().map(Ok).for_each(&mut on_check),
+                    Err(error) => on_check(Err(error)),
+                }
+            }
+        });
+
+        Self {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for HookWatchdog {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
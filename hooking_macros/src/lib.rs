@@ -1,14 +1,353 @@
-pub fn add(left: u64, right: u64) -> u64 {
-    left + right
+use std::ffi::CString;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::quote;
+use syn::ext::IdentExt;
+use syn::parse::{Parse, ParseStream};
+use syn::{
+    Abi, Error, Expr, ExprLit, FnArg, ForeignItem, Ident, ItemFn, ItemForeignMod, Lit, LitCStr,
+    LitStr, Meta, ReturnType, Signature, Token, Visibility, parse_macro_input,
+};
+
+#[derive(Default)]
+struct HookArgs {
+    module: Option<LitStr>,
+    symbol: Option<LitStr>,
+}
+
+// `#[hook(module = "libc.so.6", symbol = "puts")]` on an `extern "C"` or
+// `extern "system"` fn makes it the destination of a hook on the symbol.
+// Next to the fn, a module with the same name holds the static `HOOK`, the
+// typed `original()` and `install()`/`uninstall()`. `module` is optional.
+#[proc_macro_attribute]
+pub fn hook(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut args = HookArgs::default();
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("module") {
+            args.module = Some(meta.value()?.parse()?);
+            Ok(())
+        } else if meta.path.is_ident("symbol") {
+            args.symbol = Some(meta.value()?.parse()?);
+            Ok(())
+        } else {
+            Err(meta.error("expected `module` or `symbol`"))
+        }
+    });
+    parse_macro_input!(attr with parser);
+
+    let function = parse_macro_input!(item as ItemFn);
+    expand_hook(args, function)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn c_str(literal: &LitStr) -> syn::Result<LitCStr> {
+    let value = CString::new(literal.value())
+        .map_err(|_| Error::new(literal.span(), "name can't contain a nul byte"))?;
+    Ok(LitCStr::new(&value, literal.span()))
+}
+
+fn check_abi(abi: &Abi) -> syn::Result<&LitStr> {
+    match &abi.name {
+        Some(name) if name.value() == "C" || name.value() == "system" => Ok(name),
+        _ => Err(Error::new_spanned(
+            abi,
+            "hooks have to be `extern \"C\"` or `extern \"system\"`",
+        )),
+    }
+}
+
+// `unsafe extern "C" fn(A, B) -> R` for `sig` with the given abi
+fn fn_pointer_type(
+    sig: &Signature,
+    abi: &LitStr,
+    unsafety: Option<Token![unsafe]>,
+) -> syn::Result<TokenStream2> {
+    if let Some(variadic) = &sig.variadic {
+        return Err(Error::new_spanned(
+            variadic,
+            "variadic functions can't be hooked",
+        ));
+    }
+    if !sig.generics.params.is_empty() {
+        return Err(Error::new_spanned(&sig.generics, "hooks can't be generic"));
+    }
+    if let Some(asyncness) = &sig.asyncness {
+        return Err(Error::new_spanned(asyncness, "hooks can't be async"));
+    }
+
+    let inputs = sig
+        .inputs
+        .iter()
+        .map(|input| match input {
+            FnArg::Typed(argument) => Ok(&argument.ty),
+            FnArg::Receiver(receiver) => {
+                Err(Error::new_spanned(receiver, "hooks can't take `self`"))
+            }
+        })
+        .collect::<syn::Result<Vec<_>>>()?;
+    let output = match &sig.output {
+        ReturnType::Default => quote!(),
+        ReturnType::Type(arrow, ty) => quote!(#arrow #ty),
+    };
+
+    Ok(quote!(#unsafety extern #abi fn(#(#inputs),*) #output))
+}
+
+fn expand_hook(args: HookArgs, function: ItemFn) -> syn::Result<TokenStream2> {
+    let symbol = args
+        .symbol
+        .as_ref()
+        .ok_or_else(|| Error::new(Span::call_site(), "missing `symbol = \"...\"`"))?;
+    let symbol = c_str(symbol)?;
+    let module = match &args.module {
+        Some(module) => {
+            let module = c_str(module)?;
+            quote!(::core::option::Option::Some(#module))
+        }
+        None => quote!(::core::option::Option::None),
+    };
+
+    let sig = &function.sig;
+    let abi = sig
+        .abi
+        .as_ref()
+        .ok_or_else(|| Error::new_spanned(sig.fn_token, "hooks have to be `extern \"C\"`"))?;
+    let fn_type = fn_pointer_type(sig, check_abi(abi)?, sig.unsafety)?;
+    let name = &function.sig.ident;
+    let vis = &function.vis;
+
+    Ok(quote! {
+        #function
+
+        #vis mod #name {
+            #[allow(unused_imports)]
+            use super::*;
+
+            pub type HookedFn = #fn_type;
+
+            pub static HOOK: ::hooking::StaticHook<HookedFn> = ::hooking::StaticHook::new();
+
+            // The hooked function, or the next hook chained on it
+            pub fn original() -> HookedFn {
+                HOOK.original()
+            }
+
+            pub unsafe fn install() -> ::hooking::error::Result<()> {
+                unsafe { HOOK.install_by_name(#module, #symbol, super::#name as HookedFn) }
+            }
+
+            pub unsafe fn uninstall() -> ::hooking::error::Result<()> {
+                unsafe { HOOK.uninstall() }
+            }
+        }
+    })
+}
+
+// One or more `extern "C" { ... }` blocks
+struct ExternBlocks {
+    // `mod name;` in front of the blocks
+    group: Option<(Visibility, Ident)>,
+    blocks: Vec<ItemForeignMod>,
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl Parse for ExternBlocks {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let fork = input.fork();
+        let group = if fork.parse::<Visibility>().is_ok() && fork.peek(Token![mod]) {
+            let vis = input.parse()?;
+            input.parse::<Token![mod]>()?;
+            let name = input.parse()?;
+            input.parse::<Token![;]>()?;
+            Some((vis, name))
+        } else {
+            None
+        };
 
-    #[test]
-    fn it_works() {
-        let result = add(2, 2);
-        assert_eq!(result, 4);
+        let mut blocks = Vec::new();
+        while !input.is_empty() {
+            blocks.push(input.parse()?);
+        }
+        Ok(Self { group, blocks })
     }
 }
+
+// `hook_extern! { #[hook(module = "libc.so.6")] unsafe extern "C" { fn read(...) -> isize; } }`
+// declares a hook for every fn in the blocks, resolved by name when installed.
+// Each fn gets a module like the one of `#[hook]`, with `install(detour)`
+// instead. `Detours` with `install_all`/`uninstall_all` install them as a
+// group. `#[link_name]` overrides the symbol, `module` is optional. A leading
+// `mod name;` puts everything in that module, so several groups can share a
+// module.
+#[proc_macro]
+pub fn hook_extern(item: TokenStream) -> TokenStream {
+    let blocks = parse_macro_input!(item as ExternBlocks);
+    expand_hook_extern(blocks)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn block_module(block: &ItemForeignMod) -> syn::Result<TokenStream2> {
+    let mut args = HookArgs::default();
+    for attr in &block.attrs {
+        if !attr.path().is_ident("hook") {
+            return Err(Error::new_spanned(
+                attr,
+                "expected `#[hook(module = \"...\")]`",
+            ));
+        }
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("module") {
+                args.module = Some(meta.value()?.parse()?);
+                Ok(())
+            } else {
+                Err(meta.error("expected `module`"))
+            }
+        })?;
+    }
+
+    Ok(match &args.module {
+        Some(module) => {
+            let module = c_str(module)?;
+            quote!(::core::option::Option::Some(#module))
+        }
+        None => quote!(::core::option::Option::None),
+    })
+}
+
+fn expand_hook_extern(blocks: ExternBlocks) -> syn::Result<TokenStream2> {
+    let mut modules = Vec::new();
+    let mut names = Vec::new();
+    let mut all_public = true;
+
+    let grouped = blocks.group.is_some();
+    for block in &blocks.blocks {
+        let module = block_module(block)?;
+        let abi = check_abi(&block.abi)?;
+
+        for item in &block.items {
+            let ForeignItem::Fn(function) = item else {
+                return Err(Error::new_spanned(item, "only functions can be hooked"));
+            };
+
+            let ident = &function.sig.ident;
+            let mut symbol = LitStr::new(&ident.unraw().to_string(), ident.span());
+            let mut attrs = Vec::new();
+            for attr in &function.attrs {
+                match &attr.meta {
+                    Meta::NameValue(link_name) if link_name.path.is_ident("link_name") => {
+                        let Expr::Lit(ExprLit {
+                            lit: Lit::Str(name),
+                            ..
+                        }) = &link_name.value
+                        else {
+                            return Err(Error::new_spanned(&link_name.value, "expected a string"));
+                        };
+                        symbol = name.clone();
+                    }
+                    _ => attrs.push(attr),
+                }
+            }
+            let symbol = c_str(&symbol)?;
+
+            let fn_type = fn_pointer_type(&function.sig, abi, Some(Default::default()))?;
+            let name = ident;
+            let vis = match &function.vis {
+                Visibility::Inherited if grouped => quote!(pub(super)),
+                vis => quote!(#vis),
+            };
+            all_public &= matches!(function.vis, Visibility::Public(_));
+
+            modules.push(quote! {
+                #(#attrs)*
+                #vis mod #name {
+                    #[allow(unused_imports)]
+                    use super::*;
+
+                    pub type HookedFn = #fn_type;
+
+                    pub const MODULE: ::core::option::Option<&::core::ffi::CStr> = #module;
+                    pub const SYMBOL: &::core::ffi::CStr = #symbol;
+
+                    pub static HOOK: ::hooking::StaticHook<HookedFn> = ::hooking::StaticHook::new();
+
+                    // The hooked function, or the next hook chained on it
+                    pub fn original() -> HookedFn {
+                        HOOK.original()
+                    }
+
+                    // The function without going through any hook
+                    pub unsafe fn target() -> ::hooking::error::Result<HookedFn> {
+                        unsafe { ::hooking::typed::resolve_symbol(MODULE, SYMBOL) }
+                    }
+
+                    pub unsafe fn install(detour: HookedFn) -> ::hooking::error::Result<()> {
+                        unsafe { HOOK.install_by_name(MODULE, SYMBOL, detour) }
+                    }
+
+                    pub unsafe fn uninstall() -> ::hooking::error::Result<()> {
+                        unsafe { HOOK.uninstall() }
+                    }
+                }
+            });
+            names.push(name);
+        }
+    }
+
+    let group_vis = match (all_public, grouped) {
+        (true, _) => quote!(pub),
+        (false, true) => quote!(pub(super)),
+        (false, false) => quote!(),
+    };
+
+    let group = quote! {
+        #(#modules)*
+
+        // Destinations for the declared functions, `None` leaves one unhooked
+        #[derive(Debug, Default, Clone, Copy)]
+        #group_vis struct Detours {
+            #(pub #names: ::core::option::Option<#names::HookedFn>,)*
+        }
+
+        // Installs every hook with a destination, none of them if one fails
+        #group_vis unsafe fn install_all(detours: &Detours) -> ::hooking::error::Result<()> {
+            let mut group = ::hooking::StaticHookGroup::new();
+            #(
+                if let ::core::option::Option::Some(detour) = detours.#names {
+                    unsafe {
+                        group.install_by_name(&#names::HOOK, #names::MODULE, #names::SYMBOL, detour)?
+                    };
+                }
+            )*
+            ::core::result::Result::Ok(())
+        }
+
+        // Tries every hook, even after one failed
+        #group_vis unsafe fn uninstall_all() -> ::hooking::error::Result<()> {
+            let mut errors = ::std::vec::Vec::new();
+            #(
+                if let ::core::result::Result::Err(error) = unsafe { #names::uninstall() } {
+                    errors.push(error);
+                }
+            )*
+            match errors.len() {
+                0 => ::core::result::Result::Ok(()),
+                1 => ::core::result::Result::Err(errors.remove(0)),
+                _ => ::core::result::Result::Err(::hooking::error::HookingError::Multiple(errors)),
+            }
+        }
+    };
+
+    Ok(match blocks.group {
+        Some((vis, name)) => quote! {
+            #vis mod #name {
+                #[allow(unused_imports)]
+                use super::*;
+
+                #group
+            }
+        },
+        None => group,
+    })
+}